@@ -6,11 +6,13 @@ rm -rf configure
 mkdir -p configure
 
 BL_LIST=("limine")
-ARCH_LIST=("riscv64")
+ARCH_LIST=("riscv64" "x86_64" "aarch64" "loongarch64")
 PROFILE_LIST=("dev" "release")
 
-PLATFORMS_RO=(["riscv64"]="RISCV64_VIRT_CODE")
-PLATFORMS_RW=(["riscv64"]="RISCV64_VIRT_VARS")
+declare -A PLATFORMS_RO=(["riscv64"]="RISCV64_VIRT_CODE" ["x86_64"]="X64_OVMF_CODE" ["aarch64"]="AARCH64_QEMU_EFI" ["loongarch64"]="LOONGARCH64_QEMU_EFI")
+declare -A PLATFORMS_RW=(["riscv64"]="RISCV64_VIRT_VARS" ["x86_64"]="X64_OVMF_VARS" ["aarch64"]="AARCH64_QEMU_VARS" ["loongarch64"]="LOONGARCH64_QEMU_VARS")
+# Flash size the machine expects, if the images must be padded to it.
+declare -A PLATFORMS_SIZE=(["riscv64"]="33554432" ["x86_64"]="" ["aarch64"]="67108864" ["loongarch64"]="16777216")
 
 gum log "Welcome to the CHOS kernel configuration script!"
 
@@ -51,14 +53,17 @@ echo "EXPENS_TEST := ${EXPENS_TEST}" >>configure/configure.mk
 
 ln -sf ../src/bootloader/${BL}/linker.ld configure/linker.ld
 echo "include src/bootloader/${BL}/bl.mk" >>configure/configure.mk
+echo "include src/arch/${ARCH}/arch.mk" >>configure/configure.mk
 
 ln -sf ../src/arch/${ARCH}/target.json configure/target.json
 
-gum spin --title "Downloading https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RO["riscv64"]}.fd" -- \
-    curl -o configure/OVMF_RO.fd https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RO["riscv64"]}.fd
-gum spin --title "Downloading https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RW["riscv64"]}.fd" -- \
-    curl -o configure/OVMF_RW.fd https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RW["riscv64"]}.fd
-truncate -s 33554432 configure/OVMF_RO.fd
-truncate -s 33554432 configure/OVMF_RW.fd
+gum spin --title "Downloading https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RO[${ARCH}]}.fd" -- \
+    curl -o configure/OVMF_RO.fd https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RO[${ARCH}]}.fd
+gum spin --title "Downloading https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RW[${ARCH}]}.fd" -- \
+    curl -o configure/OVMF_RW.fd https://raw.githubusercontent.com/retrage/edk2-nightly/refs/heads/master/bin/RELEASE${PLATFORMS_RW[${ARCH}]}.fd
+if [[ -n "${PLATFORMS_SIZE[${ARCH}]}" ]]; then
+    truncate -s ${PLATFORMS_SIZE[${ARCH}]} configure/OVMF_RO.fd
+    truncate -s ${PLATFORMS_SIZE[${ARCH}]} configure/OVMF_RW.fd
+fi
 
 touch configure/CONFIGURED
\ No newline at end of file
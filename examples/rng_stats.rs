@@ -0,0 +1,28 @@
+//! Checks the kernel's random number generators on the host: `make rng-stats`.
+use std::process::ExitCode;
+
+use kernel::rng::{Rng, chacha::ChaCha20Rng, stats};
+
+/// Blocks of [`stats::BLOCK_WORDS`] words tested per generator.
+const BLOCKS: usize = 16;
+
+fn main() -> ExitCode {
+    let checks = [
+        ("Rng", stats::check(Rng::from_seed(b"rng_stats"), BLOCKS)),
+        (
+            "ChaCha20Rng",
+            stats::check(ChaCha20Rng::new([0x5a; 32]), BLOCKS),
+        ),
+    ];
+    let mut status = ExitCode::SUCCESS;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => println!("{name}: ok"),
+            Err(failure) => {
+                eprintln!("{name}: {failure}");
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
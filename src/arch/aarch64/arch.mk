@@ -0,0 +1,3 @@
+EFI_ARCH := AA64
+QEMU := qemu-system-aarch64
+QEMU_MACHINE := -M virt -cpu max
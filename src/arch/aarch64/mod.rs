@@ -0,0 +1,414 @@
+use crate::arch::page::{
+    InvalidPageTableEntry, LeafPageTableEntry, PageTableEntry, PointerPageTableEntry,
+};
+
+use super::page::{PageCache, PagePrivilege, PagingMode, PhyPageNumber};
+use crate::Page;
+use crate::fdt::DeviceTree;
+use core::{arch::asm, ptr};
+
+/// Bits of entropy credited per RNDRRS word; the CPU is not trusted to be the only source.
+const RNDRRS_WORD_BITS: usize = 16;
+/// Attempts at RNDR or RNDRRS before giving up, as both may fail for a while.
+const RNDR_RETRIES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Arch;
+impl super::ArchImpl for Arch {
+    fn halt() -> ! {
+        log::error!("Halting the CPU indefinitely.");
+        unsafe {
+            asm!("msr daifset, #0xf", options(nomem, nostack));
+        }
+        loop {
+            unsafe {
+                asm!("wfi", options(nomem, nostack));
+            }
+        }
+    }
+    fn wait_for_interrupt() {
+        unsafe {
+            asm!("wfi", options(nomem, nostack));
+        }
+    }
+    unsafe fn run_on_stack(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+        unsafe {
+            asm!(
+                "mov sp, x2",
+                "mov x29, xzr",
+                "mov x30, xzr",
+                "br x1",
+                in("x0") arg,
+                in("x1") entry,
+                in("x2") stack_top,
+                options(noreturn)
+            );
+        }
+    }
+    fn probe(_device_tree: Option<&DeviceTree<'_>>) {
+        Self::init_hart();
+        log::info!(
+            "RNDR: {}, ASIDs: {} bits",
+            if has_rndr() { "present" } else { "absent" },
+            if read_tcr() & TCR_AS == 0 { 8 } else { 16 }
+        );
+    }
+    fn init_hart() {
+        // Limine only promises the write-back entry at index 0, which the kernel keeps.
+        let mut tcr = read_tcr();
+        // TTBR0 shares the root with TTBR1, so it must only reach the lower half of the root's entries.
+        // One bit less of address than TTBR1 keeps the lower half from aliasing the kernel's upper half.
+        let t1sz = (tcr >> TCR_T1SZ_OFFSET) & len_to_mask(TCR_TSZ_LEN);
+        tcr = (tcr & !(len_to_mask(TCR_TSZ_LEN) << TCR_T0SZ_OFFSET) & !TCR_EPD0)
+            | ((t1sz + 1) << TCR_T0SZ_OFFSET);
+        if (read_mmfr0() >> MMFR0_ASID_BITS_OFFSET) & len_to_mask(MMFR0_ASID_BITS_LEN)
+            == MMFR0_ASID_BITS_16
+        {
+            tcr |= TCR_AS;
+        }
+        unsafe {
+            asm!(
+                "msr mair_el1, {}",
+                "msr tcr_el1, {}",
+                "isb",
+                in(reg) MAIR,
+                in(reg) tcr,
+                options(nostack)
+            );
+        }
+        Self::flush_mmu(None, None);
+    }
+    fn hart_id() -> usize {
+        let id: usize;
+        unsafe {
+            asm!("mrs {}, tpidr_el1", out(reg) id, options(nomem, nostack));
+        }
+        id
+    }
+    unsafe fn set_hart_id(id: usize) {
+        unsafe {
+            asm!("msr tpidr_el1, {}", in(reg) id, options(nomem, nostack));
+        }
+    }
+    #[inline(never)]
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let mut fp: usize;
+        unsafe {
+            asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+        }
+        let mut count = 0;
+        // Each frame record keeps the caller's frame pointer at the frame pointer and the return address right above it.
+        while count < frames.len() && fp != 0 && fp.is_multiple_of(size_of::<usize>()) {
+            let slot = ptr::with_exposed_provenance::<usize>(fp);
+            let (caller, ra) = unsafe { (slot.read(), slot.add(1).read()) };
+            if ra == 0 {
+                break;
+            }
+            frames[count] = ra;
+            count += 1;
+            if caller <= fp {
+                break;
+            }
+            fp = caller;
+        }
+        count
+    }
+    fn max_leaf_level() -> usize {
+        // Blocks and pages are encoded differently, so the level-less encoding only makes pages.
+        0
+    }
+    fn flush_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
+        log::trace!("Flushing MMU for address space: {addr_space:?}, address: {addr:?}");
+        // Page table writes must be visible to the walkers of every core before the invalidation.
+        unsafe {
+            asm!("dsb ishst", options(nostack));
+        }
+        let asid = |space: usize| (space & len_to_mask(ASID_LEN)) << ASID_OFFSET;
+        let page = |address: *const ()| (address.addr() >> Page::BITS) & len_to_mask(TLBI_VA_LEN);
+        match (addr_space, addr) {
+            (Some(space), Some(address)) => unsafe {
+                asm!("tlbi vae1is, {}", in(reg) asid(space) | page(address), options(nostack));
+            },
+            (Some(space), None) => unsafe {
+                asm!("tlbi aside1is, {}", in(reg) asid(space), options(nostack));
+            },
+            (None, Some(address)) => unsafe {
+                asm!("tlbi vaae1is, {}", in(reg) page(address), options(nostack));
+            },
+            (None, None) => unsafe {
+                asm!("tlbi vmalle1is", options(nostack));
+            },
+        }
+        unsafe {
+            asm!("dsb ish", "isb", options(nostack));
+        }
+    }
+    unsafe fn set_mmu(addr_space: u16, mode: PagingMode, root_table: PhyPageNumber) -> bool {
+        log::trace!(
+            "Setting MMU with address space: {addr_space}, mode: {mode:?}, root paging table: {root_table:?}"
+        );
+        // TCR.T1SZ stays as the bootloader left it, so only the current mode can be used.
+        if mode != Self::get_default_paging_mode() || addr_space > Self::get_max_address_space() {
+            return false;
+        }
+        // One root table holds both halves: TTBR0 walks its lower entries and TTBR1 its upper ones,
+        // as `init_hart` narrows TTBR0 to half of TTBR1's range.
+        // TCR.A1 is clear, so the address space is taken from TTBR0.
+        let root = (usize::from(root_table) & len_to_mask(PPN_LEN)) << Page::BITS;
+        unsafe {
+            asm!(
+                "msr ttbr0_el1, {}",
+                "msr ttbr1_el1, {}",
+                "isb",
+                in(reg) root | (usize::from(addr_space) << ASID_OFFSET),
+                in(reg) root,
+                options(nostack)
+            );
+        }
+        true
+    }
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)> {
+        let sctlr: usize;
+        let ttbr1: usize;
+        unsafe {
+            asm!("mrs {}, sctlr_el1", out(reg) sctlr, options(nomem, nostack));
+            asm!("mrs {}, ttbr1_el1", out(reg) ttbr1, options(nomem, nostack));
+        }
+        if sctlr & SCTLR_M == 0 {
+            return None;
+        }
+        Some((
+            Self::get_default_paging_mode(),
+            PhyPageNumber::from((ttbr1 >> Page::BITS) & len_to_mask(PPN_LEN)),
+        ))
+    }
+    fn get_max_address_space() -> u16 {
+        if read_tcr() & TCR_AS == 0 {
+            u16::from(u8::MAX)
+        } else {
+            u16::MAX
+        }
+    }
+    fn get_default_paging_mode() -> PagingMode {
+        match (read_tcr() >> TCR_T1SZ_OFFSET) & len_to_mask(TCR_TSZ_LEN) {
+            25 => PagingMode::Layer3,
+            _ => PagingMode::Layer4,
+        }
+    }
+    fn arch_rand() -> usize {
+        let mut rand = Self::cycle_counter();
+        if has_rndr() {
+            for _ in 0..RNDR_RETRIES {
+                let value: usize;
+                let ok: usize;
+                unsafe {
+                    asm!("mrs {}, s3_3_c2_c4_0", "cset {}, ne", out(reg) value, out(reg) ok, options(nomem, nostack));
+                }
+                if ok != 0 {
+                    rand ^= value;
+                    break;
+                }
+            }
+        }
+        rand
+    }
+    fn cycle_counter() -> usize {
+        let count: usize;
+        unsafe {
+            asm!("mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack));
+        }
+        count
+    }
+    fn hardware_entropy(dest: &mut [u8]) -> usize {
+        if !has_rndr() {
+            return 0;
+        }
+        let mut bits = 0;
+        for word in dest.as_chunks_mut::<{ size_of::<usize>() }>().0 {
+            let Some(seed) = (0..RNDR_RETRIES).find_map(|_| {
+                let value: usize;
+                let ok: usize;
+                unsafe {
+                    asm!("mrs {}, s3_3_c2_c4_1", "cset {}, ne", out(reg) value, out(reg) ok, options(nomem, nostack));
+                }
+                (ok != 0).then_some(value)
+            }) else {
+                break;
+            };
+            *word = seed.to_ne_bytes();
+            bits += RNDRRS_WORD_BITS;
+        }
+        bits
+    }
+
+    fn pte_to_num(pte: PageTableEntry) -> usize {
+        match pte {
+            PageTableEntry::Pointer(pointer) => {
+                BASE_VALID
+                    | TABLE_OR_PAGE
+                    | ((usize::from(pointer.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (usize::from(pointer.reserved) << RESERVED_OFFSET)
+                    | (usize::from(pointer.global) << TABLE_GLOBAL_OFFSET)
+            }
+            PageTableEntry::Leaf(entry) => {
+                let (writable, executable) = privilege_to_access(entry.privilege);
+                // Pages never run at the other privilege level, so it is always denied execution.
+                let (kernel_executable, user_executable) = if entry.user {
+                    (false, executable)
+                } else {
+                    (executable, false)
+                };
+                BASE_VALID
+                    | TABLE_OR_PAGE
+                    | (SHAREABILITY_INNER << SHAREABILITY_OFFSET)
+                    | ((usize::from(entry.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (cache_to_number(entry.cache) << ATTR_INDEX_OFFSET)
+                    | (usize::from(entry.user) << AP_USER_OFFSET)
+                    | (usize::from(!writable) << AP_READ_ONLY_OFFSET)
+                    | (usize::from(!kernel_executable) << PXN_OFFSET)
+                    | (usize::from(!user_executable) << UXN_OFFSET)
+                    | (usize::from(entry.reserved) << RESERVED_OFFSET)
+                    | (usize::from(!entry.global) << NOT_GLOBAL_OFFSET)
+                    | (usize::from(entry.accessed) << ACCESS_OFFSET)
+                    | (usize::from(entry.dirty) << DIRTY_OFFSET)
+            }
+            PageTableEntry::Invalid(ptr) => {
+                assert_eq!(usize::from(ptr) & BASE_VALID, 0);
+                ptr.into()
+            }
+        }
+    }
+
+    fn num_to_pte(num: usize, level: usize) -> PageTableEntry {
+        // Blocks clear the table bit, while pages set it like tables do.
+        let leaf = level == 0 || num & TABLE_OR_PAGE == 0;
+        match (num & BASE_VALID != 0, leaf) {
+            (false, _) => PageTableEntry::Invalid(InvalidPageTableEntry::from(num)),
+            (true, false) => PageTableEntry::Pointer(PointerPageTableEntry {
+                to: PhyPageNumber::from((num >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                global: (num >> TABLE_GLOBAL_OFFSET) & 1 != 0,
+                reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+            }),
+            (true, true) => {
+                let user = (num >> AP_USER_OFFSET) & 1 != 0;
+                let execute_never = if user { UXN_OFFSET } else { PXN_OFFSET };
+                PageTableEntry::Leaf(LeafPageTableEntry {
+                    to: PhyPageNumber::from((num >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                    privilege: access_to_privilege(
+                        (num >> AP_READ_ONLY_OFFSET) & 1 == 0,
+                        (num >> execute_never) & 1 == 0,
+                    ),
+                    cache: number_to_cache(
+                        (num >> ATTR_INDEX_OFFSET) & len_to_mask(ATTR_INDEX_LEN),
+                    ),
+                    global: (num >> NOT_GLOBAL_OFFSET) & 1 == 0,
+                    user,
+                    accessed: (num >> ACCESS_OFFSET) & 1 != 0,
+                    dirty: (num >> DIRTY_OFFSET) & 1 != 0,
+                    reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+                })
+            }
+        }
+    }
+}
+
+const BASE_VALID: usize = 1;
+/// Set in tables and level 3 pages, clear in blocks.
+const TABLE_OR_PAGE: usize = 1 << 1;
+
+/// Index into MAIR.
+const ATTR_INDEX_OFFSET: usize = 2;
+const ATTR_INDEX_LEN: usize = 3;
+const AP_USER_OFFSET: usize = 6;
+const AP_READ_ONLY_OFFSET: usize = 7;
+const SHAREABILITY_OFFSET: usize = 8;
+const SHAREABILITY_INNER: usize = 0b11;
+const ACCESS_OFFSET: usize = 10;
+const NOT_GLOBAL_OFFSET: usize = 11;
+const PPN_OFFSET: usize = 12;
+const PPN_LEN: usize = 36;
+const PXN_OFFSET: usize = 53;
+const UXN_OFFSET: usize = 54;
+/// Available to software at every level.
+const RESERVED_OFFSET: usize = 56;
+/// Kept by software, as hardware dirty tracking is optional.
+const DIRTY_OFFSET: usize = 57;
+/// Kept by software in table entries, which have no global bit.
+const TABLE_GLOBAL_OFFSET: usize = 58;
+
+/// Normal write-back, normal non-cacheable and device-nGnRE memory, at indices 0, 1 and 2.
+const MAIR: usize = 0x04_44_ff;
+const ASID_OFFSET: usize = 48;
+const ASID_LEN: usize = 16;
+const TLBI_VA_LEN: usize = 44;
+const SCTLR_M: usize = 1;
+const TCR_T0SZ_OFFSET: usize = 0;
+const TCR_EPD0: usize = 1 << 7;
+const TCR_T1SZ_OFFSET: usize = 16;
+const TCR_TSZ_LEN: usize = 6;
+const TCR_AS: usize = 1 << 36;
+const MMFR0_ASID_BITS_OFFSET: usize = 4;
+const MMFR0_ASID_BITS_LEN: usize = 4;
+const MMFR0_ASID_BITS_16: usize = 0b0010;
+const ISAR0_RNDR_OFFSET: usize = 60;
+
+/// Returns whether pages are writable and executable; execute-only pages stay readable.
+const fn privilege_to_access(p: PagePrivilege) -> (bool, bool) {
+    match p {
+        PagePrivilege::ReadOnly => (false, false),
+        PagePrivilege::ExecuteOnly | PagePrivilege::ReadExecute => (false, true),
+        PagePrivilege::ReadWrite => (true, false),
+        PagePrivilege::ReadWriteExecute => (true, true),
+    }
+}
+const fn access_to_privilege(writable: bool, executable: bool) -> PagePrivilege {
+    match (writable, executable) {
+        (false, false) => PagePrivilege::ReadOnly,
+        (false, true) => PagePrivilege::ReadExecute,
+        (true, false) => PagePrivilege::ReadWrite,
+        (true, true) => PagePrivilege::ReadWriteExecute,
+    }
+}
+/// Decodes the MAIR index; indices the kernel never sets count as non-cacheable.
+const fn number_to_cache(num: usize) -> PageCache {
+    match num {
+        0 => PageCache::Cacheable,
+        2 => PageCache::IO,
+        _ => PageCache::NonCacheable,
+    }
+}
+/// Encodes the cache type as a MAIR index.
+const fn cache_to_number(c: PageCache) -> usize {
+    match c {
+        PageCache::Cacheable => 0,
+        PageCache::NonCacheable => 1,
+        PageCache::IO => 2,
+    }
+}
+const fn len_to_mask(len: usize) -> usize {
+    (1usize << len).overflowing_sub(1).0
+}
+
+fn read_tcr() -> usize {
+    let tcr: usize;
+    unsafe {
+        asm!("mrs {}, tcr_el1", out(reg) tcr, options(nomem, nostack));
+    }
+    tcr
+}
+
+fn read_mmfr0() -> usize {
+    let mmfr0: usize;
+    unsafe {
+        asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0, options(nomem, nostack));
+    }
+    mmfr0
+}
+
+fn has_rndr() -> bool {
+    let isar0: usize;
+    unsafe {
+        asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0, options(nomem, nostack));
+    }
+    isar0 >> ISAR0_RNDR_OFFSET != 0
+}
@@ -0,0 +1,3 @@
+EFI_ARCH := LOONGARCH64
+QEMU := qemu-system-loongarch64
+QEMU_MACHINE := -M virt -cpu la464
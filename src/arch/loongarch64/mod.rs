@@ -0,0 +1,322 @@
+use crate::arch::page::{
+    InvalidPageTableEntry, LeafPageTableEntry, PageTableEntry, PointerPageTableEntry,
+};
+
+use super::page::{PageCache, PagePrivilege, PagingMode, PhyPageNumber};
+use crate::Page;
+use core::{arch::asm, ptr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Arch;
+impl super::ArchImpl for Arch {
+    fn halt() -> ! {
+        log::error!("Halting the CPU indefinitely.");
+        unsafe {
+            asm!("csrxchg {}, {}, 0x0", inout(reg) 0usize => _, in(reg) CRMD_IE, options(nomem, nostack));
+        }
+        loop {
+            unsafe {
+                asm!("idle 0", options(nomem, nostack));
+            }
+        }
+    }
+    fn wait_for_interrupt() {
+        unsafe {
+            asm!("idle 0", options(nomem, nostack));
+        }
+    }
+    unsafe fn run_on_stack(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+        unsafe {
+            asm!(
+                "move $sp, $a2",
+                "move $fp, $zero",
+                "move $ra, $zero",
+                "jirl $zero, $a1, 0",
+                in("$a0") arg,
+                in("$a1") entry,
+                in("$a2") stack_top,
+                options(noreturn)
+            );
+        }
+    }
+    fn hart_id() -> usize {
+        let id: usize;
+        unsafe {
+            asm!("move {}, $tp", out(reg) id, options(nomem, nostack));
+        }
+        id
+    }
+    unsafe fn set_hart_id(id: usize) {
+        // The kernel uses no thread-local storage, so the thread pointer is free to hold the hart index.
+        unsafe {
+            asm!("move $tp, {}", in(reg) id, options(nomem, nostack));
+        }
+    }
+    #[inline(never)]
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let mut fp: usize;
+        unsafe {
+            asm!("move {}, $fp", out(reg) fp, options(nomem, nostack));
+        }
+        let mut count = 0;
+        // Each frame keeps the return address right below the frame pointer and the caller's frame pointer below it.
+        while count < frames.len() && fp != 0 && fp.is_multiple_of(size_of::<usize>()) {
+            let slot = ptr::with_exposed_provenance::<usize>(fp);
+            let (ra, caller) = unsafe { (slot.sub(1).read(), slot.sub(2).read()) };
+            if ra == 0 {
+                break;
+            }
+            frames[count] = ra;
+            count += 1;
+            if caller <= fp {
+                break;
+            }
+            fp = caller;
+        }
+        count
+    }
+    fn max_leaf_level() -> usize {
+        // Bit 6 is the global bit of pages but the huge bit of directories, so the level-less encoding only makes pages.
+        0
+    }
+    fn flush_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
+        log::trace!("Flushing MMU for address space: {addr_space:?}, address: {addr:?}");
+        // Page table writes must complete before the refill handler can walk them again.
+        unsafe {
+            asm!("dbar 0", options(nostack));
+        }
+        match (addr_space, addr) {
+            // Global pages match every address space, so they are flushed too.
+            (Some(space), Some(address)) => unsafe {
+                asm!("invtlb 0x6, {}, {}", in(reg) space & len_to_mask(ASID_LEN), in(reg) address, options(nostack));
+            },
+            (Some(space), None) => unsafe {
+                asm!("invtlb 0x4, {}, $zero", in(reg) space & len_to_mask(ASID_LEN), options(nostack));
+            },
+            // No INVTLB operation flushes one address from every address space.
+            (None, _) => unsafe {
+                asm!("invtlb 0x0, $zero, $zero", options(nostack));
+            },
+        }
+    }
+    unsafe fn set_mmu(addr_space: u16, mode: PagingMode, root_table: PhyPageNumber) -> bool {
+        log::trace!(
+            "Setting MMU with address space: {addr_space}, mode: {mode:?}, root paging table: {root_table:?}"
+        );
+        // The bootloader's TLB refill handler walks the layout in PWCL and PWCH, so only the current mode can be used.
+        if mode != Self::get_default_paging_mode() || addr_space > Self::get_max_address_space() {
+            return false;
+        }
+        // One root table holds both halves: PGDL is walked for its lower entries and PGDH for its upper ones.
+        let root = (usize::from(root_table) & len_to_mask(PPN_LEN)) << Page::BITS;
+        unsafe {
+            asm!(
+                "csrxchg {}, {}, 0x18",
+                "csrwr {}, 0x19",
+                "csrwr {}, 0x1a",
+                inout(reg) usize::from(addr_space) => _,
+                in(reg) len_to_mask(ASID_LEN),
+                inout(reg) root => _,
+                inout(reg) root => _,
+                options(nostack)
+            );
+        }
+        true
+    }
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)> {
+        let crmd: usize;
+        let pgdh: usize;
+        unsafe {
+            asm!("csrrd {}, 0x0", out(reg) crmd, options(nomem, nostack));
+            asm!("csrrd {}, 0x1a", out(reg) pgdh, options(nomem, nostack));
+        }
+        if crmd & CRMD_PG == 0 {
+            return None;
+        }
+        Some((
+            Self::get_default_paging_mode(),
+            PhyPageNumber::from((pgdh >> Page::BITS) & len_to_mask(PPN_LEN)),
+        ))
+    }
+    fn get_max_address_space() -> u16 {
+        let asid: usize;
+        unsafe {
+            asm!("csrrd {}, 0x18", out(reg) asid, options(nomem, nostack));
+        }
+        let bits = (asid >> ASID_BITS_OFFSET) & len_to_mask(ASID_BITS_LEN);
+        u16::try_from(len_to_mask(bits.min(ASID_LEN))).unwrap()
+    }
+    fn get_default_paging_mode() -> PagingMode {
+        let (low, high): (usize, usize);
+        unsafe {
+            asm!("csrrd {}, 0x1c", out(reg) low, options(nomem, nostack));
+            asm!("csrrd {}, 0x1d", out(reg) high, options(nomem, nostack));
+        }
+        // Every directory level the refill handler walks has a nonzero width.
+        let directories = [
+            (low >> PWCL_DIR1_WIDTH_OFFSET) & len_to_mask(PWCL_WIDTH_LEN),
+            (low >> PWCL_DIR2_WIDTH_OFFSET) & len_to_mask(PWCL_WIDTH_LEN),
+            (high >> PWCH_DIR3_WIDTH_OFFSET) & len_to_mask(PWCH_WIDTH_LEN),
+            (high >> PWCH_DIR4_WIDTH_OFFSET) & len_to_mask(PWCH_WIDTH_LEN),
+        ]
+        .into_iter()
+        .filter(|&width| width != 0)
+        .count();
+        match directories {
+            ..=2 => PagingMode::Layer3,
+            3 => PagingMode::Layer4,
+            _ => PagingMode::Layer5,
+        }
+    }
+    fn arch_rand() -> usize {
+        Self::cycle_counter()
+    }
+    fn cycle_counter() -> usize {
+        let count: usize;
+        unsafe {
+            asm!("rdtime.d {}, $zero", out(reg) count, options(nomem, nostack));
+        }
+        count
+    }
+
+    fn pte_to_num(pte: PageTableEntry) -> usize {
+        match pte {
+            PageTableEntry::Pointer(pointer) => {
+                // LDDIR ignores the low bits of directory entries, so they are free to mark the entry valid.
+                BASE_VALID
+                    | ((usize::from(pointer.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (usize::from(pointer.reserved) << RESERVED_OFFSET)
+                    | (usize::from(pointer.global) << TABLE_GLOBAL_OFFSET)
+            }
+            PageTableEntry::Leaf(entry) => {
+                let (readable, writable, executable) = privilege_to_access(entry.privilege);
+                BASE_VALID
+                    | ((usize::from(entry.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (usize::from(writable) << WRITABLE_OFFSET)
+                    | (usize::from(!readable) << NO_READ_OFFSET)
+                    | (usize::from(!executable) << NO_EXECUTE_OFFSET)
+                    | (cache_to_number(entry.cache) << CACHE_OFFSET)
+                    | ((if entry.user { PLV_USER } else { 0 }) << PLV_OFFSET)
+                    | (usize::from(entry.reserved) << RESERVED_OFFSET)
+                    | (usize::from(entry.global) << GLOBAL_OFFSET)
+                    | (usize::from(entry.accessed) << ACCESS_OFFSET)
+                    | (usize::from(entry.dirty) << DIRTY_OFFSET)
+            }
+            PageTableEntry::Invalid(ptr) => {
+                assert_eq!(usize::from(ptr) & BASE_VALID, 0);
+                ptr.into()
+            }
+        }
+    }
+
+    fn num_to_pte(num: usize, level: usize) -> PageTableEntry {
+        // Every entry of the last level is a page, while directories hold huge pages only with bit 6 set.
+        let leaf = level == 0 || num & (1 << GLOBAL_OFFSET) != 0;
+        match (num & BASE_VALID != 0, leaf) {
+            (false, _) => PageTableEntry::Invalid(InvalidPageTableEntry::from(num)),
+            (true, false) => PageTableEntry::Pointer(PointerPageTableEntry {
+                to: PhyPageNumber::from((num >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                global: (num >> TABLE_GLOBAL_OFFSET) & 1 != 0,
+                reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+            }),
+            (true, true) => {
+                // Huge pages keep their global bit in the lowest frame number bit, as bit 6 gives their size.
+                let (global, frame) = if level == 0 {
+                    (GLOBAL_OFFSET, num)
+                } else {
+                    (HUGE_GLOBAL_OFFSET, num & !(1 << HUGE_GLOBAL_OFFSET))
+                };
+                PageTableEntry::Leaf(LeafPageTableEntry {
+                    to: PhyPageNumber::from((frame >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                    privilege: access_to_privilege(
+                        (num >> NO_READ_OFFSET) & 1 == 0,
+                        (num >> WRITABLE_OFFSET) & 1 != 0,
+                        (num >> NO_EXECUTE_OFFSET) & 1 == 0,
+                    ),
+                    cache: number_to_cache((num >> CACHE_OFFSET) & len_to_mask(CACHE_LEN)),
+                    global: (num >> global) & 1 != 0,
+                    user: (num >> PLV_OFFSET) & len_to_mask(PLV_LEN) != 0,
+                    accessed: (num >> ACCESS_OFFSET) & 1 != 0,
+                    dirty: (num >> DIRTY_OFFSET) & 1 != 0,
+                    reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+                })
+            }
+        }
+    }
+}
+
+const BASE_VALID: usize = 1;
+
+/// The hardware dirty bit, which allows stores; dirtiness itself is kept by software.
+const WRITABLE_OFFSET: usize = 1;
+const PLV_OFFSET: usize = 2;
+const PLV_LEN: usize = 2;
+const PLV_USER: usize = 3;
+/// Memory access type: strongly-ordered uncached, coherent cached or weakly-ordered uncached.
+const CACHE_OFFSET: usize = 4;
+const CACHE_LEN: usize = 2;
+/// The global bit of pages, which is the huge bit in directories.
+const GLOBAL_OFFSET: usize = 6;
+/// Kept by software, as the hardware has no accessed bit.
+const ACCESS_OFFSET: usize = 8;
+/// Kept by software, next to the hardware dirty bit used for write permission.
+const DIRTY_OFFSET: usize = 9;
+/// Available to software at every level.
+const RESERVED_OFFSET: usize = 10;
+/// Kept by software in directory entries, whose bit 6 would make them huge pages.
+const TABLE_GLOBAL_OFFSET: usize = 11;
+const HUGE_GLOBAL_OFFSET: usize = 12;
+const PPN_OFFSET: usize = 12;
+const PPN_LEN: usize = 36;
+const NO_READ_OFFSET: usize = 61;
+const NO_EXECUTE_OFFSET: usize = 62;
+
+const CRMD_IE: usize = 1 << 2;
+const CRMD_PG: usize = 1 << 4;
+const ASID_LEN: usize = 10;
+const ASID_BITS_OFFSET: usize = 16;
+const ASID_BITS_LEN: usize = 8;
+const PWCL_DIR1_WIDTH_OFFSET: usize = 15;
+const PWCL_DIR2_WIDTH_OFFSET: usize = 25;
+const PWCL_WIDTH_LEN: usize = 5;
+const PWCH_DIR3_WIDTH_OFFSET: usize = 6;
+const PWCH_DIR4_WIDTH_OFFSET: usize = 18;
+const PWCH_WIDTH_LEN: usize = 6;
+
+/// Returns whether pages are readable, writable and executable.
+const fn privilege_to_access(p: PagePrivilege) -> (bool, bool, bool) {
+    match p {
+        PagePrivilege::ReadOnly => (true, false, false),
+        PagePrivilege::ExecuteOnly => (false, false, true),
+        PagePrivilege::ReadExecute => (true, false, true),
+        PagePrivilege::ReadWrite => (true, true, false),
+        PagePrivilege::ReadWriteExecute => (true, true, true),
+    }
+}
+/// Decodes the access bits; pages that are neither readable nor executable count as read-only.
+const fn access_to_privilege(readable: bool, writable: bool, executable: bool) -> PagePrivilege {
+    match (readable, writable, executable) {
+        (false, _, true) => PagePrivilege::ExecuteOnly,
+        (true, false, true) => PagePrivilege::ReadExecute,
+        (_, true, false) => PagePrivilege::ReadWrite,
+        (true, true, true) => PagePrivilege::ReadWriteExecute,
+        (_, false, false) => PagePrivilege::ReadOnly,
+    }
+}
+const fn number_to_cache(num: usize) -> PageCache {
+    match num {
+        0 => PageCache::IO,
+        1 => PageCache::Cacheable,
+        _ => PageCache::NonCacheable,
+    }
+}
+const fn cache_to_number(c: PageCache) -> usize {
+    match c {
+        PageCache::IO => 0,
+        PageCache::Cacheable => 1,
+        PageCache::NonCacheable => 2,
+    }
+}
+const fn len_to_mask(len: usize) -> usize {
+    (1usize << len).overflowing_sub(1).0
+}
@@ -1,22 +1,87 @@
 pub mod page;
 mod test;
+pub mod trap;
 
 use alloc::fmt::Debug;
 
 use page::PhyPageNumber;
 
 use crate::arch::page::{PageTableEntry, PagingMode};
+use crate::fdt::DeviceTree;
 
 pub trait ArchImpl: Debug + Clone + Copy + Default + Send + Sync {
+    /// Registers saved on trap entry, handed to [`trap::dispatch`].
+    type TrapFrame: Debug + Default + trap::SyscallRegisters = ();
+
     /// Halts the CPU indefinitely.
     fn halt() -> ! {
         #[allow(clippy::empty_loop)]
         loop {}
     }
 
+    /// Waits for an interrupt, or returns at once where the CPU cannot wait.
+    /// Returning early is allowed, so callers loop until what they wait for has happened.
+    fn wait_for_interrupt() {
+        core::hint::spin_loop();
+    }
+
+    /// Switches to the stack ending at `stack_top` and calls `entry` with `arg` on it.
+    /// The frame chain starts over, so backtraces end at `entry`.
+    /// # Safety
+    /// `stack_top` must be the aligned end of a mapped stack nothing else uses,
+    /// and nothing may refer to the stack being left behind.
+    unsafe fn run_on_stack(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> !;
+
+    /// Detects optional CPU features described by the device tree.
+    /// Called once by the boot hart before anything relies on those features.
+    fn probe(device_tree: Option<&DeviceTree<'_>>) {
+        let _ = device_tree;
+    }
+
+    /// Installs the trap entry on the current hart, after which traps are dispatched through [`trap::dispatch`].
+    /// Must run after [`crate::hardening::init`], which handlers are mangled with.
+    fn install_traps() {}
+
+    /// Applies the per-hart settings chosen by [`ArchImpl::probe`] to the current hart.
+    /// Called by [`ArchImpl::probe`] for the boot hart and by every secondary hart as it starts.
+    fn init_hart() {}
+
+    /// Returns the index of the current hart, counted from 0 as set by [`ArchImpl::set_hart_id`].
+    /// Architectures that cannot record it only ever run the boot hart, which is 0.
+    #[must_use]
+    fn hart_id() -> usize {
+        0
+    }
+
+    /// Records `id` as the index of the current hart, as returned by [`ArchImpl::hart_id`] from then on.
+    /// # Safety
+    /// `id` must not be in use by any other hart.
+    unsafe fn set_hart_id(id: usize) {
+        let _ = id;
+    }
+
+    /// Fills `frames` with the return addresses of the current call stack, innermost first.
+    /// Returns how many were found; architectures that cannot walk the stack find none.
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let _ = frames;
+        0
+    }
+
+    /// Returns the highest page table level leaves may be placed at, level 0 mapping single pages.
+    #[must_use]
+    fn max_leaf_level() -> usize {
+        2
+    }
+
     /// Flushes the MMU for the given address space and address.
     fn flush_mmu(addr_space: Option<usize>, addr: Option<*const ()>);
 
+    /// Flushes the MMU of every other online hart, as [`ArchImpl::flush_mmu`] does for the current one.
+    /// Nothing needs doing where local flushes already reach every hart, or where only one hart runs.
+    fn flush_remote_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
+        let _ = (addr_space, addr);
+    }
+
     /// Sets the MMU with the given address space, paging mode, and root paging table.
     /// Returns true if successful, false otherwise.
     /// # Safety
@@ -24,6 +89,9 @@ pub trait ArchImpl: Debug + Clone + Copy + Default + Send + Sync {
     /// Caller must ensure proper fence is used after this call.
     unsafe fn set_mmu(addr_space: u16, mode: PagingMode, root_paging: PhyPageNumber) -> bool;
 
+    /// Returns the paging mode and root paging table currently in use, or `None` if paging is off.
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)>;
+
     /// Returns the maximum address space supported by the architecture.
     fn get_max_address_space() -> u16;
 
@@ -33,20 +101,72 @@ pub trait ArchImpl: Debug + Clone + Copy + Default + Send + Sync {
     /// Returns the architecture-specific random number. Maybe low-quality.
     fn arch_rand() -> usize;
 
+    /// Returns a free-running cycle counter, used to measure timing jitter.
+    fn cycle_counter() -> usize;
+
+    /// Returns the frequency of [`ArchImpl::timer_ticks`] in hertz, or 0 if it is unknown.
+    #[must_use]
+    fn timer_frequency() -> u64 {
+        0
+    }
+
+    /// Returns the time in timer ticks since an arbitrary point, never going backwards.
+    #[must_use]
+    fn timer_ticks() -> u64 {
+        0
+    }
+
+    /// Arms the current hart's timer to raise [`trap::Trap::TimerInterrupt`] once [`ArchImpl::timer_ticks`]
+    /// reaches `deadline`, replacing any earlier deadline, or disarms it with `None`.
+    /// The interrupt is only taken while interrupts are enabled.
+    /// Returns false if the architecture has no timer.
+    #[must_use]
+    fn set_deadline(deadline: Option<u64>) -> bool {
+        let _ = deadline;
+        false
+    }
+
+    /// Enables or disables interrupts on the current hart, returning whether they were enabled.
+    /// Architectures without a trap entry keep them disabled.
+    #[must_use]
+    fn set_interrupts(enabled: bool) -> bool {
+        let _ = enabled;
+        false
+    }
+
+    /// Fills `dest` from the hardware entropy source, if the CPU has one.
+    /// Returns the bits of entropy the filled bytes are credited with, 0 without a source.
+    fn hardware_entropy(dest: &mut [u8]) -> usize {
+        let _ = dest;
+        0
+    }
+
     /// Converts a page table entry to a number.
     fn pte_to_num(pte: PageTableEntry) -> usize;
 
-    /// Converts a number to a page table entry.
-    fn num_to_pte(num: usize) -> PageTableEntry;
+    /// Converts a number to a page table entry, found in a table at `level`, 0 being the last.
+    fn num_to_pte(num: usize, level: usize) -> PageTableEntry;
 }
 
+#[cfg(all(target_arch = "aarch64", not(test)))]
+mod aarch64;
+#[cfg(all(target_arch = "loongarch64", not(test)))]
+mod loongarch64;
+#[cfg(all(target_arch = "riscv64", not(test)))]
 mod riscv64;
+#[cfg(all(target_arch = "x86_64", not(test)))]
+mod x86_64;
 cfg_if::cfg_if! {
     if #[cfg(test)] {
         pub use test::Arch;
     } else if #[cfg(target_arch = "riscv64")] {
-        // mod riscv64;
-        pub use riscv64::Arch;
+        pub use riscv64::{Arch, sbi};
+    } else if #[cfg(target_arch = "x86_64")] {
+        pub use x86_64::Arch;
+    } else if #[cfg(target_arch = "aarch64")] {
+        pub use aarch64::Arch;
+    } else if #[cfg(target_arch = "loongarch64")] {
+        pub use loongarch64::Arch;
     } else {
         pub use test::Arch;
     }
@@ -22,14 +22,22 @@ impl Default for PageTable {
 impl PageTable {
     pub const COUNT: usize = Page::SIZE.exact_div(size_of::<AtomicUsize>());
 
-    pub fn iter(&self) -> impl Iterator<Item = PageTableEntry> + '_ {
+    /// Returns the entries of the table, which sits at `level` of its tree.
+    pub fn iter(&self, level: usize) -> impl Iterator<Item = PageTableEntry> + '_ {
         self.0
             .iter()
-            .map(|entry| Arch::num_to_pte(entry.load(Ordering::Relaxed)))
+            .map(move |entry| Arch::num_to_pte(entry.load(Ordering::Relaxed), level))
+    }
+
+    /// Overwrites the page table entry at the given index.
+    /// # Safety
+    /// The caller must ensure change this page table does not violate the architecture's requirements.
+    pub unsafe fn set(&self, index: usize, entry: PageTableEntry) {
+        self.0[index].store(Arch::pte_to_num(entry), Ordering::Relaxed);
     }
 
     #[must_use = "Always check the result to see if update fails"]
-    /// Update the page table entry at the given index atomicity.
+    /// Update the page table entry at the given index atomicity, in a table at `level` of its tree.
     /// # Safety
     /// The caller must ensure change this page table does not violate the architecture's requirements.
     /// # Errors
@@ -37,14 +45,15 @@ impl PageTable {
     pub unsafe fn update_at(
         &self,
         index: usize,
+        level: usize,
         mut f: impl FnMut(PageTableEntry) -> Option<PageTableEntry>,
     ) -> Result<PageTableEntry, PageTableEntry> {
         self.0[index]
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |num| {
-                f(Arch::num_to_pte(num)).map(Arch::pte_to_num)
+                f(Arch::num_to_pte(num, level)).map(Arch::pte_to_num)
             })
-            .map(Arch::num_to_pte)
-            .map_err(Arch::num_to_pte)
+            .map(|num| Arch::num_to_pte(num, level))
+            .map_err(|num| Arch::num_to_pte(num, level))
     }
 }
 
@@ -114,24 +123,14 @@ impl Step for VirtPageNumber {
 impl VirtPageNumber {
     pub const MIN: Self = VirtPageNumber(0);
     pub const MAX: Self = VirtPageNumber(usize::MAX >> Page::BITS);
+    /// Returns true if the page is canonical in `paging_mode` and is not the null page.
     #[must_use]
-    #[allow(clippy::missing_panics_doc)] // Actually would not panic
     pub fn is_valid(&self, paging_mode: PagingMode) -> bool {
         if self.0 == 0 {
             return false;
         }
-        if self.0 >= (1 << (usize::try_from(usize::BITS).unwrap() - paging_mode.virt_size())) - 1 {
-            return false;
-        }
-        if [
-            0,
-            ((1 << (usize::try_from(usize::BITS).unwrap() - paging_mode.virt_size())) - 1),
-        ]
-        .contains(&(self.0 >> (paging_mode.virt_size() - 1)))
-        {
-            return false;
-        }
-        true
+        let high = self.0 >> (paging_mode.virt_size() - Page::BITS - 1);
+        high == 0 || high == Self::MAX.0 >> (paging_mode.virt_size() - Page::BITS - 1)
     }
 }
 impl From<usize> for VirtPageNumber {
@@ -236,6 +235,15 @@ pub enum PagingMode {
 }
 impl PagingMode {
     pub const MAX_LAYERS: usize = 6;
+    /// Returns the number of page table levels.
+    #[must_use]
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Layer3 => 3,
+            PagingMode::Layer4 => 4,
+            PagingMode::Layer5 => 5,
+        }
+    }
     #[must_use]
     pub const fn virt_size(self) -> usize {
         match self {
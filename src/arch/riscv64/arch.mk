@@ -0,0 +1,3 @@
+EFI_ARCH := RISCV64
+QEMU := qemu-system-riscv64
+QEMU_MACHINE := -M virt -cpu rv64
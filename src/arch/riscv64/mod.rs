@@ -3,11 +3,33 @@ use crate::arch::page::{
 };
 
 use super::page::{PageCache, PagePrivilege, PagingMode, PhyPageNumber};
-use core::arch::asm;
+use crate::Page;
+use crate::fdt::{DeviceTree, Node, read_cells, read_strings};
+use core::{
+    arch::asm,
+    hint, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+pub mod sbi;
+mod trap;
+
+/// Whether every hart implements the Zkr entropy source.
+static ZKR: AtomicBool = AtomicBool::new(false);
+/// Whether every hart implements Sstc, so deadlines are written to `stimecmp` instead of going through the SBI.
+static SSTC: AtomicBool = AtomicBool::new(false);
+/// Frequency of the `time` counter in hertz, 0 until the device tree gives it.
+static TIMEBASE: AtomicU64 = AtomicU64::new(0);
+/// Bits of entropy credited per 16-bit `seed` sample, which is raw noise still needing conditioning.
+const SEED_SAMPLE_BITS: usize = 2;
+/// Polls of the `seed` CSR before giving up on a sample.
+const SEED_POLLS: usize = 1 << 16;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct Arch;
 impl super::ArchImpl for Arch {
+    type TrapFrame = trap::TrapFrame;
+
     fn halt() -> ! {
         log::error!("Halting the CPU indefinitely.");
         loop {
@@ -16,6 +38,101 @@ impl super::ArchImpl for Arch {
             }
         }
     }
+    fn wait_for_interrupt() {
+        unsafe {
+            asm!("wfi", options(nomem, nostack));
+        }
+    }
+    unsafe fn run_on_stack(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+        unsafe {
+            asm!(
+                "mv sp, a2",
+                "mv s0, zero",
+                "mv ra, zero",
+                "jr a1",
+                in("a0") arg,
+                in("a1") entry,
+                in("a2") stack_top,
+                options(noreturn)
+            );
+        }
+    }
+    fn probe(device_tree: Option<&DeviceTree<'_>>) {
+        sbi::probe();
+        let cpus = device_tree.and_then(|tree| tree.find("/cpus"));
+        let zkr = cpus.is_some_and(|cpus| all_harts_have(cpus, "zkr"));
+        ZKR.store(zkr, Ordering::Relaxed);
+        let sstc = cpus.is_some_and(|cpus| all_harts_have(cpus, "sstc"));
+        SSTC.store(sstc, Ordering::Relaxed);
+        // The frequency is usually given for all harts on /cpus, but may be on each hart instead.
+        let timebase = cpus
+            .and_then(|cpus| {
+                cpus.property("timebase-frequency").or_else(|| {
+                    cpus.children()
+                        .filter(|node| node.base_name() == "cpu")
+                        .find_map(|hart| hart.property("timebase-frequency"))
+                })
+            })
+            .and_then(|mut value| {
+                let cells = value.len() / size_of::<u32>();
+                read_cells(&mut value, cells)
+            })
+            .unwrap_or(0);
+        TIMEBASE.store(timebase, Ordering::Relaxed);
+        log::info!(
+            "Zkr entropy source: {}, timer: {} at {timebase} Hz",
+            if zkr { "present" } else { "absent" },
+            if sstc { "Sstc" } else { "SBI" }
+        );
+    }
+    fn install_traps() {
+        trap::init_hart_area(Self::hart_id());
+        unsafe {
+            asm!(
+                "lla {}, __trap_entry",
+                "csrw stvec, {0}",
+                "csrw sscratch, zero",
+                out(reg) _,
+                options(nomem, nostack)
+            );
+        }
+    }
+    fn hart_id() -> usize {
+        let id: usize;
+        unsafe {
+            asm!("mv {}, tp", out(reg) id, options(nomem, nostack));
+        }
+        id
+    }
+    unsafe fn set_hart_id(id: usize) {
+        // The kernel uses no thread-local storage, so the thread pointer is free to hold the hart index.
+        unsafe {
+            asm!("mv tp, {}", in(reg) id, options(nomem, nostack));
+        }
+    }
+    #[inline(never)]
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let mut fp: usize;
+        unsafe {
+            asm!("mv {}, s0", out(reg) fp, options(nomem, nostack));
+        }
+        let mut count = 0;
+        // Each frame keeps the return address right below the frame pointer and the caller's frame pointer below it.
+        while count < frames.len() && fp != 0 && fp.is_multiple_of(size_of::<usize>()) {
+            let slot = ptr::with_exposed_provenance::<usize>(fp);
+            let (ra, caller) = unsafe { (slot.sub(1).read(), slot.sub(2).read()) };
+            if ra == 0 {
+                break;
+            }
+            frames[count] = ra;
+            count += 1;
+            if caller <= fp {
+                break;
+            }
+            fp = caller;
+        }
+        count
+    }
     fn flush_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
         log::trace!("Flushing MMU for address space: {addr_space:?}, address: {addr:?}");
         match (addr_space, addr) {
@@ -43,6 +160,17 @@ impl super::ArchImpl for Arch {
             },
         }
     }
+    fn flush_remote_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
+        // Harts not running the kernel are flushed too, which does them no harm.
+        let (start, size) = addr.map_or((0, usize::MAX), |address| (address.addr(), Page::SIZE));
+        let result = match addr_space {
+            Some(space) => sbi::remote_sfence_vma_asid(sbi::HartMask::ALL, start, size, space),
+            None => sbi::remote_sfence_vma(sbi::HartMask::ALL, start, size),
+        };
+        if let Err(error) = result {
+            panic!("Cannot flush the MMU of other harts: {error}");
+        }
+    }
     unsafe fn set_mmu(addr_space: u16, mode: PagingMode, root_table: PhyPageNumber) -> bool {
         log::trace!(
             "Setting MMU with address space: {addr_space}, mode: {mode:?}, root paging table: {root_table:?}"
@@ -80,6 +208,19 @@ impl super::ArchImpl for Arch {
         };
         result == 0
     }
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)> {
+        let satp: usize;
+        unsafe {
+            asm!("csrr {}, satp", out(reg) satp, options(nomem, nostack));
+        }
+        let mode = match satp >> 60 {
+            8 => PagingMode::Layer3,
+            9 => PagingMode::Layer4,
+            10 => PagingMode::Layer5,
+            _ => return None,
+        };
+        Some((mode, PhyPageNumber::from(satp & len_to_mask(44))))
+    }
     fn get_max_address_space() -> u16 {
         let max_space: usize;
         unsafe {
@@ -119,6 +260,67 @@ impl super::ArchImpl for Arch {
         }
         rand
     }
+    fn cycle_counter() -> usize {
+        let cycles: usize;
+        unsafe {
+            asm!("rdcycle {}", out(reg) cycles, options(nomem, nostack));
+        }
+        cycles
+    }
+    fn timer_frequency() -> u64 {
+        TIMEBASE.load(Ordering::Relaxed)
+    }
+    fn timer_ticks() -> u64 {
+        let time: u64;
+        unsafe {
+            asm!("rdtime {}", out(reg) time, options(nomem, nostack));
+        }
+        time
+    }
+    fn set_deadline(deadline: Option<u64>) -> bool {
+        // A deadline that never comes also clears a pending timer interrupt.
+        let value = deadline.unwrap_or(u64::MAX);
+        if SSTC.load(Ordering::Relaxed) {
+            unsafe {
+                asm!("csrw stimecmp, {}", in(reg) value, options(nomem, nostack));
+            }
+        } else if sbi::set_timer(value).is_err() {
+            return false;
+        }
+        unsafe {
+            if deadline.is_some() {
+                asm!("csrs sie, {}", in(reg) SIE_STIE, options(nomem, nostack));
+            } else {
+                asm!("csrc sie, {}", in(reg) SIE_STIE, options(nomem, nostack));
+            }
+        }
+        true
+    }
+    fn set_interrupts(enabled: bool) -> bool {
+        let previous: usize;
+        unsafe {
+            if enabled {
+                asm!("csrrs {}, sstatus, {}", out(reg) previous, in(reg) SSTATUS_SIE, options(nomem, nostack));
+            } else {
+                asm!("csrrc {}, sstatus, {}", out(reg) previous, in(reg) SSTATUS_SIE, options(nomem, nostack));
+            }
+        }
+        previous & SSTATUS_SIE != 0
+    }
+    fn hardware_entropy(dest: &mut [u8]) -> usize {
+        if !ZKR.load(Ordering::Relaxed) {
+            return 0;
+        }
+        let mut bits = 0;
+        for sample in dest.as_chunks_mut::<2>().0 {
+            let Some(entropy) = read_seed() else {
+                break;
+            };
+            *sample = entropy.to_le_bytes();
+            bits += SEED_SAMPLE_BITS;
+        }
+        bits
+    }
 
     fn pte_to_num(pte: PageTableEntry) -> usize {
         match pte {
@@ -147,9 +349,9 @@ impl super::ArchImpl for Arch {
         }
     }
 
-    fn num_to_pte(num: usize) -> PageTableEntry {
+    fn num_to_pte(num: usize, _level: usize) -> PageTableEntry {
         match (
-            num & 1 == 0,
+            num & BASE_VALID != 0,
             (num >> PRIVILEGE_OFFSET) & len_to_mask(PRIVILEGE_LEN),
         ) {
             (false, _) => PageTableEntry::Invalid(InvalidPageTableEntry::from(num)),
@@ -222,6 +424,56 @@ const fn cache_to_number(c: PageCache) -> usize {
         PageCache::IO => 2,
     }
 }
+const SIE_STIE: usize = 1 << 5;
+const SSTATUS_SIE: usize = 1 << 1;
 const fn len_to_mask(len: usize) -> usize {
-    (1usize << (len + 1)).overflowing_sub(1).0
+    (1usize << len).overflowing_sub(1).0
+}
+
+/// Returns true if every hart below `cpus` lists the multi-letter ISA extension `name`.
+fn all_harts_have(cpus: Node<'_>, name: &str) -> bool {
+    let mut harts = cpus
+        .children()
+        .filter(|node| node.base_name() == "cpu")
+        .peekable();
+    harts.peek().is_some() && harts.all(|hart| has_extension(hart, name))
+}
+
+/// Returns true if `hart` lists the multi-letter ISA extension `name`.
+fn has_extension(hart: Node<'_>, name: &str) -> bool {
+    if let Some(extensions) = hart.property("riscv,isa-extensions") {
+        return read_strings(extensions).any(|extension| extension == name);
+    }
+    hart.property("riscv,isa")
+        .and_then(|isa| read_strings(isa).next())
+        .is_some_and(|isa| {
+            isa.split('_')
+                .skip(1)
+                .any(|extension| extension.eq_ignore_ascii_case(name))
+        })
+}
+
+/// Polls the Zkr `seed` CSR until it yields a 16-bit sample.
+/// Requires the firmware to grant supervisor access through `mseccfg.SSEED`.
+fn read_seed() -> Option<u16> {
+    for _ in 0..SEED_POLLS {
+        let seed: usize;
+        // The CSR must be accessed with a write, which acknowledges the sample.
+        unsafe {
+            asm!("csrrw {}, 0x015, x0", out(reg) seed, options(nomem, nostack));
+        }
+        match (seed >> 30) & 0b11 {
+            // ES16: a valid sample.
+            0b10 => return u16::try_from(seed & 0xFFFF).ok(),
+            // DEAD: the source has failed for good.
+            0b11 => {
+                log::error!("Zkr entropy source reported an unrecoverable failure");
+                ZKR.store(false, Ordering::Relaxed);
+                return None;
+            }
+            // BIST or WAIT: no sample yet.
+            _ => hint::spin_loop(),
+        }
+    }
+    None
 }
@@ -0,0 +1,531 @@
+use core::{
+    arch::asm,
+    error::Error,
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// An error returned by the SBI implementation.
+pub enum SbiError {
+    Failed,
+    /// The extension or function is not implemented, or was not found when probing.
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoSharedMemory,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    DeniedLocked,
+    /// An error code the specification does not define.
+    Unknown(isize),
+}
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoSharedMemory,
+            -10 => SbiError::InvalidState,
+            -11 => SbiError::BadRange,
+            -12 => SbiError::Timeout,
+            -13 => SbiError::Io,
+            -14 => SbiError::DeniedLocked,
+            _ => SbiError::Unknown(code),
+        }
+    }
+}
+impl Display for SbiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SbiError::Failed => write!(f, "SBI call failed"),
+            SbiError::NotSupported => write!(f, "SBI call is not supported"),
+            SbiError::InvalidParam => write!(f, "Invalid SBI call parameter"),
+            SbiError::Denied => write!(f, "SBI call was denied"),
+            SbiError::InvalidAddress => write!(f, "Invalid SBI call address"),
+            SbiError::AlreadyAvailable => write!(f, "SBI resource is already available"),
+            SbiError::AlreadyStarted => write!(f, "SBI resource is already started"),
+            SbiError::AlreadyStopped => write!(f, "SBI resource is already stopped"),
+            SbiError::NoSharedMemory => write!(f, "SBI shared memory is not available"),
+            SbiError::InvalidState => write!(f, "Invalid SBI state"),
+            SbiError::BadRange => write!(f, "Bad SBI range"),
+            SbiError::Timeout => write!(f, "SBI call timed out"),
+            SbiError::Io => write!(f, "SBI input or output error"),
+            SbiError::DeniedLocked => write!(f, "SBI call was denied as the resource is locked"),
+            SbiError::Unknown(code) => write!(f, "Unknown SBI error {code}"),
+        }
+    }
+}
+impl Error for SbiError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+/// The SBI extensions this client knows.
+pub enum Extension {
+    Base,
+    Time,
+    Ipi,
+    RemoteFence,
+    HartStateManagement,
+    SystemReset,
+    DebugConsole,
+    PerformanceMonitoring,
+    SystemSuspend,
+}
+impl Extension {
+    pub const COUNT: usize = 9;
+    pub const ALL: [Extension; Self::COUNT] = [
+        Extension::Base,
+        Extension::Time,
+        Extension::Ipi,
+        Extension::RemoteFence,
+        Extension::HartStateManagement,
+        Extension::SystemReset,
+        Extension::DebugConsole,
+        Extension::PerformanceMonitoring,
+        Extension::SystemSuspend,
+    ];
+
+    /// Returns the extension ID, which spells the extension's name in ASCII.
+    #[must_use]
+    pub const fn id(self) -> usize {
+        match self {
+            Extension::Base => 0x10,
+            Extension::Time => 0x5449_4D45,
+            Extension::Ipi => 0x0073_5049,
+            Extension::RemoteFence => 0x5246_4E43,
+            Extension::HartStateManagement => 0x0048_534D,
+            Extension::SystemReset => 0x5352_5354,
+            Extension::DebugConsole => 0x4442_434E,
+            Extension::PerformanceMonitoring => 0x0050_4D55,
+            Extension::SystemSuspend => 0x5355_5350,
+        }
+    }
+}
+
+/// Set in [`AVAILABLE`] once every extension has been probed.
+const PROBED: usize = 1 << Extension::COUNT;
+/// Extensions the firmware implements, one bit per [`Extension`].
+static AVAILABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if the firmware implements `extension`, probing every extension on first use.
+#[must_use]
+pub fn is_available(extension: Extension) -> bool {
+    let mut available = AVAILABLE.load(Ordering::Relaxed);
+    if available & PROBED == 0 {
+        available = Extension::ALL
+            .into_iter()
+            .filter(|&extension| {
+                extension == Extension::Base
+                    || probe_extension(extension.id()).is_ok_and(|id| id != 0)
+            })
+            .fold(PROBED, |available, extension| {
+                available | 1 << extension as usize
+            });
+        AVAILABLE.store(available, Ordering::Relaxed);
+    }
+    available & (1 << extension as usize) != 0
+}
+
+/// Probes every extension and logs what the firmware provides.
+pub fn probe() {
+    let version = spec_version();
+    let available = Extension::ALL
+        .into_iter()
+        .filter(|&extension| is_available(extension));
+    log::info!(
+        "SBI {}.{} from implementation {:#x}, extensions: {:?}",
+        version.major,
+        version.minor,
+        impl_id().unwrap_or(0),
+        available.collect::<arrayvec::ArrayVec<_, { Extension::COUNT }>>()
+    );
+}
+
+/// Calls function `function` of extension `extension` with `args`, returning its value.
+fn call(extension: usize, function: usize, args: [usize; 6]) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a6") function,
+            in("a7") extension,
+            options(nostack)
+        );
+    }
+    match error {
+        0 => Ok(value),
+        _ => Err(SbiError::from_code(error)),
+    }
+}
+
+/// Calls `function` of `extension` once it is known to be available.
+fn checked_call(
+    extension: Extension,
+    function: usize,
+    args: [usize; 6],
+) -> Result<usize, SbiError> {
+    if !is_available(extension) {
+        return Err(SbiError::NotSupported);
+    }
+    call(extension.id(), function, args)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpecVersion {
+    pub major: usize,
+    pub minor: usize,
+}
+
+/// Returns the version of the SBI specification the firmware implements.
+#[must_use]
+pub fn spec_version() -> SpecVersion {
+    // Every SBI since 0.2 implements the base extension, and its version call cannot fail.
+    let version = call(Extension::Base.id(), 0, [0; 6]).unwrap_or(0);
+    SpecVersion {
+        major: (version >> 24) & 0x7F,
+        minor: version & 0xFF_FFFF,
+    }
+}
+
+/// Returns the ID of the SBI implementation, such as 1 for `OpenSBI`.
+/// # Errors
+/// Returns the error reported by the firmware.
+pub fn impl_id() -> Result<usize, SbiError> {
+    call(Extension::Base.id(), 1, [0; 6])
+}
+
+/// Returns the version of the SBI implementation, in its own encoding.
+/// # Errors
+/// Returns the error reported by the firmware.
+pub fn impl_version() -> Result<usize, SbiError> {
+    call(Extension::Base.id(), 2, [0; 6])
+}
+
+/// Returns a nonzero value if the firmware implements the extension `id`, 0 if it does not.
+/// # Errors
+/// Returns the error reported by the firmware.
+pub fn probe_extension(id: usize) -> Result<usize, SbiError> {
+    call(Extension::Base.id(), 3, [id, 0, 0, 0, 0, 0])
+}
+
+/// Returns the `mvendorid`, `marchid` and `mimpid` of the machine.
+/// # Errors
+/// Returns the error reported by the firmware.
+pub fn machine_ids() -> Result<(usize, usize, usize), SbiError> {
+    let id = |function| call(Extension::Base.id(), function, [0; 6]);
+    Ok((id(4)?, id(5)?, id(6)?))
+}
+
+/// Programs the next timer event of the current hart for when `time` reaches `value`, clearing the pending one.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the TIME extension.
+pub fn set_timer(value: u64) -> Result<(), SbiError> {
+    checked_call(
+        Extension::Time,
+        0,
+        [usize::try_from(value).unwrap(), 0, 0, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A set of harts: bit `n` of `mask` selects hart `base + n`.
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+impl HartMask {
+    /// Selects every hart in the system.
+    pub const ALL: Self = HartMask {
+        mask: 0,
+        base: usize::MAX,
+    };
+
+    #[must_use]
+    pub const fn single(hart: usize) -> Self {
+        HartMask {
+            mask: 1,
+            base: hart,
+        }
+    }
+}
+
+/// Raises a supervisor software interrupt on every hart in `harts`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the IPI extension.
+pub fn send_ipi(harts: HartMask) -> Result<(), SbiError> {
+    checked_call(Extension::Ipi, 0, [harts.mask, harts.base, 0, 0, 0, 0]).map(|_| ())
+}
+
+/// Makes every hart in `harts` execute `fence.i`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the RFENCE extension.
+pub fn remote_fence_i(harts: HartMask) -> Result<(), SbiError> {
+    checked_call(
+        Extension::RemoteFence,
+        0,
+        [harts.mask, harts.base, 0, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Makes every hart in `harts` execute `sfence.vma` for `size` bytes from `start`, in every address space.
+/// A `start` and `size` of 0 and `usize::MAX` flush everything.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the RFENCE extension.
+pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> Result<(), SbiError> {
+    checked_call(
+        Extension::RemoteFence,
+        1,
+        [harts.mask, harts.base, start, size, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Makes every hart in `harts` execute `sfence.vma` for `size` bytes from `start`, in address space `asid` only.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the RFENCE extension.
+pub fn remote_sfence_vma_asid(
+    harts: HartMask,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), SbiError> {
+    checked_call(
+        Extension::RemoteFence,
+        2,
+        [harts.mask, harts.base, start, size, asid, 0],
+    )
+    .map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The state of a hart as tracked by the HSM extension.
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Starts the stopped hart `hart` in supervisor mode at the physical address `start`,
+/// with its hart ID in `a0`, `opaque` in `a1` and paging off.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the HSM extension.
+pub fn hart_start(hart: usize, start: usize, opaque: usize) -> Result<(), SbiError> {
+    checked_call(
+        Extension::HartStateManagement,
+        0,
+        [hart, start, opaque, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Stops the current hart, returning only if that fails.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the HSM extension.
+pub fn hart_stop() -> Result<(), SbiError> {
+    checked_call(Extension::HartStateManagement, 1, [0; 6]).map(|_| ())
+}
+
+/// Returns the state of hart `hart`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the HSM extension.
+pub fn hart_status(hart: usize) -> Result<HartState, SbiError> {
+    match checked_call(Extension::HartStateManagement, 2, [hart, 0, 0, 0, 0, 0])? {
+        0 => Ok(HartState::Started),
+        1 => Ok(HartState::Stopped),
+        2 => Ok(HartState::StartPending),
+        3 => Ok(HartState::StopPending),
+        4 => Ok(HartState::Suspended),
+        5 => Ok(HartState::SuspendPending),
+        6 => Ok(HartState::ResumePending),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+/// Suspends the current hart with the platform's `suspend_type`.
+/// Retentive suspends return once the hart resumes; non-retentive ones resume at the physical address `resume`
+/// with its hart ID in `a0` and `opaque` in `a1`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the HSM extension.
+pub fn hart_suspend(suspend_type: u32, resume: usize, opaque: usize) -> Result<(), SbiError> {
+    checked_call(
+        Extension::HartStateManagement,
+        3,
+        [suspend_type as usize, resume, opaque, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// Resets or shuts the system down, returning only if that fails.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the SRST extension.
+pub fn system_reset(kind: ResetType, reason: ResetReason) -> Result<(), SbiError> {
+    checked_call(
+        Extension::SystemReset,
+        0,
+        [kind as usize, reason as usize, 0, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Writes `len` bytes from the physical address `phys` to the debug console, returning how many were written.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the DBCN extension.
+pub fn console_write(phys: usize, len: usize) -> Result<usize, SbiError> {
+    checked_call(Extension::DebugConsole, 0, [len, phys, 0, 0, 0, 0])
+}
+
+/// Reads up to `len` bytes from the debug console to the physical address `phys`, returning how many were read.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the DBCN extension.
+pub fn console_read(phys: usize, len: usize) -> Result<usize, SbiError> {
+    checked_call(Extension::DebugConsole, 1, [len, phys, 0, 0, 0, 0])
+}
+
+/// Writes one byte to the debug console, which needs no physical address.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the DBCN extension.
+pub fn console_write_byte(byte: u8) -> Result<(), SbiError> {
+    checked_call(
+        Extension::DebugConsole,
+        2,
+        [usize::from(byte), 0, 0, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Returns the number of hardware and firmware performance counters.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_num_counters() -> Result<usize, SbiError> {
+    checked_call(Extension::PerformanceMonitoring, 0, [0; 6])
+}
+
+/// Returns the raw description of counter `counter`: its CSR, width and whether it is a firmware counter.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_counter_info(counter: usize) -> Result<usize, SbiError> {
+    checked_call(
+        Extension::PerformanceMonitoring,
+        1,
+        [counter, 0, 0, 0, 0, 0],
+    )
+}
+
+/// Finds a counter among those selected by bit `n` of `mask` for counter `base + n`, able to count `event` with `event_data`, configures it with `flags`
+/// and returns its index.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_counter_config_matching(
+    base: usize,
+    mask: usize,
+    flags: usize,
+    event: usize,
+    event_data: u64,
+) -> Result<usize, SbiError> {
+    checked_call(
+        Extension::PerformanceMonitoring,
+        2,
+        [
+            base,
+            mask,
+            flags,
+            event,
+            usize::try_from(event_data).unwrap(),
+            0,
+        ],
+    )
+}
+
+/// Starts the counters selected by `base` and `mask`, setting them to `initial` if `flags` asks for it.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_counter_start(
+    base: usize,
+    mask: usize,
+    flags: usize,
+    initial: u64,
+) -> Result<(), SbiError> {
+    checked_call(
+        Extension::PerformanceMonitoring,
+        3,
+        [base, mask, flags, usize::try_from(initial).unwrap(), 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Stops the counters selected by `base` and `mask`, also releasing them if `flags` asks for it.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_counter_stop(base: usize, mask: usize, flags: usize) -> Result<(), SbiError> {
+    checked_call(
+        Extension::PerformanceMonitoring,
+        4,
+        [base, mask, flags, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+/// Reads the firmware counter `counter`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the PMU extension.
+pub fn pmu_counter_fw_read(counter: usize) -> Result<usize, SbiError> {
+    checked_call(
+        Extension::PerformanceMonitoring,
+        5,
+        [counter, 0, 0, 0, 0, 0],
+    )
+}
+
+/// Suspends the whole system with `sleep_type`, 0 being suspend to RAM.
+/// On wake-up the boot hart resumes at the physical address `resume` with its hart ID in `a0` and `opaque` in `a1`.
+/// # Errors
+/// Returns the error reported by the firmware, or [`SbiError::NotSupported`] without the SUSP extension.
+pub fn system_suspend(sleep_type: u32, resume: usize, opaque: usize) -> Result<(), SbiError> {
+    checked_call(
+        Extension::SystemSuspend,
+        0,
+        [sleep_type as usize, resume, opaque, 0, 0, 0],
+    )
+    .map(|_| ())
+}
@@ -0,0 +1,205 @@
+use core::{
+    arch::global_asm,
+    mem::offset_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::trap::{self, SyscallRegisters, Trap},
+    smp::MAX_HARTS,
+    syscall::SyscallError,
+};
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+/// Registers saved on trap entry.
+/// Floating-point registers are not saved, as the kernel does not use them.
+pub struct TrapFrame {
+    /// General purpose registers by number; the slot of `x0` is unused.
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub stval: usize,
+    pub scause: usize,
+}
+impl TrapFrame {
+    /// Returns true if the trap came from user mode.
+    #[must_use]
+    pub fn is_from_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+}
+
+/// System calls take their number in `a7`, their arguments in `a0` to `a5`,
+/// and return a value in `a0` and an error number in `a1`, 0 on success.
+impl SyscallRegisters for TrapFrame {
+    fn syscall(&self) -> (usize, [usize; 6]) {
+        let mut args = [0; 6];
+        args.copy_from_slice(&self.regs[A0..A0 + 6]);
+        (self.regs[A7], args)
+    }
+
+    fn set_syscall_result(&mut self, result: Result<usize, SyscallError>) {
+        let (value, error) = match result {
+            Ok(value) => (value, 0),
+            Err(error) => (0, error as usize),
+        };
+        self.regs[A0] = value;
+        self.regs[A0 + 1] = error;
+        // `ecall` has no compressed form.
+        self.sepc += 4;
+    }
+}
+
+#[derive(Debug)]
+#[repr(C, align(32))]
+/// What the trap entry needs to find the kernel again on a trap from user mode, one per hart.
+struct HartArea {
+    /// Top of the kernel stack traps from user mode start on, set on every return to user mode.
+    kernel_stack: AtomicUsize,
+    /// The kernel's `tp`, which holds the hart index user code may have overwritten.
+    hart: AtomicUsize,
+    /// Holds `t0` while the entry switches stacks.
+    scratch: AtomicUsize,
+}
+impl HartArea {
+    const fn new() -> Self {
+        HartArea {
+            kernel_stack: AtomicUsize::new(0),
+            hart: AtomicUsize::new(0),
+            scratch: AtomicUsize::new(0),
+        }
+    }
+}
+
+static HART_AREAS: [HartArea; MAX_HARTS] = [const { HartArea::new() }; _];
+const _: () = assert!(size_of::<HartArea>().is_power_of_two());
+
+const SSTATUS_SPP: usize = 1 << 8;
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+const A0: usize = 10;
+const A7: usize = 17;
+
+/// Records the current hart's index in its area, for the trap entry to restore `tp` from.
+pub(super) fn init_hart_area(hart: usize) {
+    HART_AREAS[hart].hart.store(hart, Ordering::Relaxed);
+}
+
+// `sscratch` points at the hart's `HartArea` while user code runs and holds zero while the kernel does,
+// so kernel traps stay on the current stack and user traps switch to the kernel stack and `tp`.
+global_asm!(
+    r#"
+    .section .text.trap, "ax"
+    .balign 4
+    .global __trap_entry
+__trap_entry:
+    csrrw sp, sscratch, sp
+    beqz sp, 1f
+    sd t0, {scratch}(sp)
+    mv t0, sp
+    ld sp, {stack}(t0)
+    j 2f
+1:
+    csrrw sp, sscratch, sp
+2:
+    addi sp, sp, -{frame}
+    .irp n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    sd x\n, \n * 8(sp)
+    .endr
+    csrr t1, sstatus
+    andi t1, t1, {spp}
+    beqz t1, 3f
+    addi t1, sp, {frame}
+    sd t1, 2 * 8(sp)
+    j 4f
+3:
+    csrrw t1, sscratch, zero
+    sd t1, 2 * 8(sp)
+    ld t1, {scratch}(t0)
+    sd t1, 5 * 8(sp)
+    ld tp, {hart}(t0)
+4:
+    csrr t0, sepc
+    sd t0, {sepc}(sp)
+    csrr t0, sstatus
+    sd t0, {sstatus}(sp)
+    csrr t0, stval
+    sd t0, {stval}(sp)
+    csrr t0, scause
+    sd t0, {scause}(sp)
+    mv a0, sp
+    call {handle}
+    ld t0, {sepc}(sp)
+    csrw sepc, t0
+    ld t0, {sstatus}(sp)
+    csrw sstatus, t0
+    andi t0, t0, {spp}
+    bnez t0, 5f
+    la t0, {areas}
+    slli t1, tp, {area_shift}
+    add t0, t0, t1
+    addi t1, sp, {frame}
+    sd t1, {stack}(t0)
+    csrw sscratch, t0
+5:
+    .irp n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    ld x\n, \n * 8(sp)
+    .endr
+    ld sp, 2 * 8(sp)
+    sret
+    "#,
+    frame = const size_of::<TrapFrame>(),
+    sepc = const offset_of!(TrapFrame, sepc),
+    sstatus = const offset_of!(TrapFrame, sstatus),
+    stval = const offset_of!(TrapFrame, stval),
+    scause = const offset_of!(TrapFrame, scause),
+    spp = const SSTATUS_SPP,
+    stack = const offset_of!(HartArea, kernel_stack),
+    hart = const offset_of!(HartArea, hart),
+    scratch = const offset_of!(HartArea, scratch),
+    areas = sym HART_AREAS,
+    area_shift = const size_of::<HartArea>().trailing_zeros(),
+    handle = sym handle_trap,
+);
+
+extern "C" fn handle_trap(frame: &mut TrapFrame) {
+    let trap = decode(frame.scause, frame.stval);
+    trap::dispatch(&trap, frame);
+}
+
+/// Decodes `scause` and `stval` as defined by the privileged specification.
+fn decode(scause: usize, stval: usize) -> Trap {
+    let code = scause & !SCAUSE_INTERRUPT;
+    if scause & SCAUSE_INTERRUPT != 0 {
+        return match code {
+            1 => Trap::SoftwareInterrupt,
+            5 => Trap::TimerInterrupt,
+            9 => Trap::ExternalInterrupt,
+            _ => Trap::Unknown {
+                interrupt: true,
+                code,
+                value: stval,
+            },
+        };
+    }
+    match code {
+        0 => Trap::InstructionMisaligned(stval),
+        1 => Trap::InstructionAccessFault(stval),
+        2 => Trap::IllegalInstruction(stval),
+        3 => Trap::Breakpoint(stval),
+        4 => Trap::LoadMisaligned(stval),
+        5 => Trap::LoadAccessFault(stval),
+        6 => Trap::StoreMisaligned(stval),
+        7 => Trap::StoreAccessFault(stval),
+        8 => Trap::UserEnvCall,
+        9 => Trap::SupervisorEnvCall,
+        12 => Trap::InstructionPageFault(stval),
+        13 => Trap::LoadPageFault(stval),
+        15 => Trap::StorePageFault(stval),
+        _ => Trap::Unknown {
+            interrupt: false,
+            code,
+            value: stval,
+        },
+    }
+}
@@ -1,20 +1,27 @@
 use crate::{
     PhyPageNumber,
-    arch::page::{PageTableEntry, PagingMode},
+    arch::page::{LeafPageTableEntry, PageTableEntry, PagingMode, PointerPageTableEntry},
 };
 
 use super::ArchImpl;
+use layout::{
+    ACCESSED, CACHE, CACHES, DIRTY, GLOBAL, LEAF, PPN, PRIVILEGE, PRIVILEGES, RESERVED, USER, VALID,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[allow(unused)]
 pub struct Arch;
 impl ArchImpl for Arch {
-    fn flush_mmu(_addr_space: Option<usize>, _addr: Option<*const ()>) {
+    unsafe fn run_on_stack(_stack_top: usize, _entry: extern "C" fn(usize) -> !, _arg: usize) -> ! {
         unimplemented!()
     }
+    fn flush_mmu(_addr_space: Option<usize>, _addr: Option<*const ()>) {}
     unsafe fn set_mmu(_addr_space: u16, _mode: PagingMode, _root_paging: PhyPageNumber) -> bool {
         unimplemented!()
     }
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)> {
+        None
+    }
     fn get_max_address_space() -> u16 {
         u16::MAX
     }
@@ -24,10 +31,87 @@ impl ArchImpl for Arch {
     fn arch_rand() -> usize {
         0
     }
-    fn pte_to_num(_pte: PageTableEntry) -> usize {
-        unimplemented!()
+    fn cycle_counter() -> usize {
+        0
     }
-    fn num_to_pte(_num: usize) -> PageTableEntry {
-        unimplemented!()
+    fn pte_to_num(pte: PageTableEntry) -> usize {
+        match pte {
+            PageTableEntry::Invalid(invalid) => usize::from(invalid),
+            PageTableEntry::Pointer(pointer) => {
+                VALID
+                    | usize::from(pointer.global) << GLOBAL
+                    | usize::from(pointer.reserved) << RESERVED
+                    | usize::from(pointer.to) << PPN
+            }
+            PageTableEntry::Leaf(leaf) => {
+                let privilege = PRIVILEGES
+                    .iter()
+                    .position(|&privilege| privilege == leaf.privilege)
+                    .unwrap();
+                let cache = CACHES
+                    .iter()
+                    .position(|&cache| cache == leaf.cache)
+                    .unwrap();
+                VALID
+                    | LEAF
+                    | privilege << PRIVILEGE
+                    | cache << CACHE
+                    | usize::from(leaf.global) << GLOBAL
+                    | usize::from(leaf.user) << USER
+                    | usize::from(leaf.accessed) << ACCESSED
+                    | usize::from(leaf.dirty) << DIRTY
+                    | usize::from(leaf.reserved) << RESERVED
+                    | usize::from(leaf.to) << PPN
+            }
+        }
     }
+    fn num_to_pte(num: usize, _level: usize) -> PageTableEntry {
+        let bit = |shift: usize| num >> shift & 1 == 1;
+        if num & VALID == 0 {
+            PageTableEntry::Invalid(num.into())
+        } else if num & LEAF == 0 {
+            PageTableEntry::Pointer(PointerPageTableEntry {
+                to: (num >> PPN).into(),
+                global: bit(GLOBAL),
+                reserved: bit(RESERVED),
+            })
+        } else {
+            PageTableEntry::Leaf(LeafPageTableEntry {
+                to: (num >> PPN).into(),
+                privilege: PRIVILEGES[num >> PRIVILEGE & 0b111],
+                cache: CACHES[num >> CACHE & 0b11],
+                global: bit(GLOBAL),
+                user: bit(USER),
+                accessed: bit(ACCESSED),
+                dirty: bit(DIRTY),
+                reserved: bit(RESERVED),
+            })
+        }
+    }
+}
+
+#[allow(unused)]
+/// A made-up entry layout for host tests; invalid entries keep their payload with the valid bit clear.
+mod layout {
+    use crate::arch::page::{PageCache, PagePrivilege};
+
+    pub const VALID: usize = 1 << 0;
+    pub const LEAF: usize = 1 << 1;
+    pub const PRIVILEGE: usize = 2;
+    pub const CACHE: usize = 5;
+    pub const GLOBAL: usize = 7;
+    pub const USER: usize = 8;
+    pub const ACCESSED: usize = 9;
+    pub const DIRTY: usize = 10;
+    pub const RESERVED: usize = 11;
+    pub const PPN: usize = 12;
+    pub const PRIVILEGES: [PagePrivilege; 5] = [
+        PagePrivilege::ReadOnly,
+        PagePrivilege::ExecuteOnly,
+        PagePrivilege::ReadExecute,
+        PagePrivilege::ReadWrite,
+        PagePrivilege::ReadWriteExecute,
+    ];
+    pub const CACHES: [PageCache; 3] =
+        [PageCache::Cacheable, PageCache::NonCacheable, PageCache::IO];
 }
@@ -0,0 +1,137 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Arch, ArchImpl, hardening, syscall::SyscallError};
+
+/// The registers saved on trap entry by the current architecture.
+pub type TrapFrame = <Arch as ArchImpl>::TrapFrame;
+/// Handles a trap; returning resumes the interrupted code from the saved frame.
+pub type TrapHandler = fn(&Trap, &mut TrapFrame);
+
+/// The registers of a [`TrapFrame`] a system call passes its number, arguments and result in.
+pub trait SyscallRegisters {
+    /// Returns the system call number and its arguments.
+    fn syscall(&self) -> (usize, [usize; 6]);
+
+    /// Hands `result` back to the caller and moves it past the instruction that made the call.
+    fn set_syscall_result(&mut self, result: Result<usize, SyscallError>);
+}
+/// Architectures without a trap entry never take a system call.
+impl SyscallRegisters for () {
+    fn syscall(&self) -> (usize, [usize; 6]) {
+        (usize::MAX, [0; 6])
+    }
+
+    fn set_syscall_result(&mut self, _result: Result<usize, SyscallError>) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A decoded exception or interrupt, with the faulting address or instruction where the hardware reports one.
+pub enum Trap {
+    InstructionMisaligned(usize),
+    InstructionAccessFault(usize),
+    /// Carries the faulting instruction bits, or 0 if the hardware does not report them.
+    IllegalInstruction(usize),
+    Breakpoint(usize),
+    LoadMisaligned(usize),
+    LoadAccessFault(usize),
+    StoreMisaligned(usize),
+    StoreAccessFault(usize),
+    /// A system call from user mode.
+    UserEnvCall,
+    /// A call from the kernel to the firmware below it.
+    SupervisorEnvCall,
+    InstructionPageFault(usize),
+    LoadPageFault(usize),
+    StorePageFault(usize),
+    SoftwareInterrupt,
+    TimerInterrupt,
+    ExternalInterrupt,
+    /// A cause this kernel does not know, with its raw code and value.
+    Unknown {
+        interrupt: bool,
+        code: usize,
+        value: usize,
+    },
+}
+impl Trap {
+    #[must_use]
+    pub fn kind(&self) -> TrapKind {
+        match self {
+            Trap::InstructionMisaligned(_) => TrapKind::InstructionMisaligned,
+            Trap::InstructionAccessFault(_) => TrapKind::InstructionAccessFault,
+            Trap::IllegalInstruction(_) => TrapKind::IllegalInstruction,
+            Trap::Breakpoint(_) => TrapKind::Breakpoint,
+            Trap::LoadMisaligned(_) => TrapKind::LoadMisaligned,
+            Trap::LoadAccessFault(_) => TrapKind::LoadAccessFault,
+            Trap::StoreMisaligned(_) => TrapKind::StoreMisaligned,
+            Trap::StoreAccessFault(_) => TrapKind::StoreAccessFault,
+            Trap::UserEnvCall => TrapKind::UserEnvCall,
+            Trap::SupervisorEnvCall => TrapKind::SupervisorEnvCall,
+            Trap::InstructionPageFault(_) => TrapKind::InstructionPageFault,
+            Trap::LoadPageFault(_) => TrapKind::LoadPageFault,
+            Trap::StorePageFault(_) => TrapKind::StorePageFault,
+            Trap::SoftwareInterrupt => TrapKind::SoftwareInterrupt,
+            Trap::TimerInterrupt => TrapKind::TimerInterrupt,
+            Trap::ExternalInterrupt => TrapKind::ExternalInterrupt,
+            Trap::Unknown { .. } => TrapKind::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+/// The kinds of [`Trap`] handlers are registered for.
+pub enum TrapKind {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    SoftwareInterrupt,
+    TimerInterrupt,
+    ExternalInterrupt,
+    Unknown,
+}
+impl TrapKind {
+    pub const COUNT: usize = 17;
+}
+
+/// Registered handlers by [`TrapKind`], mangled so a stray write cannot redirect them; 0 means none.
+static HANDLERS: [AtomicUsize; TrapKind::COUNT] = [const { AtomicUsize::new(0) }; _];
+
+/// Registers `handler` for traps of `kind`, returning the handler it replaces.
+/// Handlers must only be registered once [`hardening::init`] has run.
+pub fn set_handler(kind: TrapKind, handler: TrapHandler) -> Option<TrapHandler> {
+    let previous =
+        HANDLERS[kind as usize].swap(hardening::mangle(handler as usize), Ordering::AcqRel);
+    (previous != 0).then(|| demangle_handler(previous))
+}
+
+/// Removes the handler for traps of `kind`, returning it.
+pub fn clear_handler(kind: TrapKind) -> Option<TrapHandler> {
+    let previous = HANDLERS[kind as usize].swap(0, Ordering::AcqRel);
+    (previous != 0).then(|| demangle_handler(previous))
+}
+
+/// Runs the handler registered for `trap`; called by the architecture's trap entry.
+/// # Panics
+/// Panics if no handler is registered for the trap.
+pub fn dispatch(trap: &Trap, frame: &mut TrapFrame) {
+    let handler = HANDLERS[trap.kind() as usize].load(Ordering::Acquire);
+    assert!(handler != 0, "Unhandled trap {trap:x?} with {frame:#x?}");
+    demangle_handler(handler)(trap, frame);
+}
+
+fn demangle_handler(mangled: usize) -> TrapHandler {
+    let addr = hardening::demangle(mangled);
+    // Only handlers stored by `set_handler` are ever mangled into the table.
+    unsafe { core::mem::transmute::<usize, TrapHandler>(addr) }
+}
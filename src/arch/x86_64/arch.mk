@@ -0,0 +1,3 @@
+EFI_ARCH := X64
+QEMU := qemu-system-x86_64
+QEMU_MACHINE := -M q35 -cpu max
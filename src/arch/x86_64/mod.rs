@@ -0,0 +1,427 @@
+use crate::arch::page::{
+    InvalidPageTableEntry, LeafPageTableEntry, PageTableEntry, PointerPageTableEntry,
+};
+
+use super::page::{PageCache, PagePrivilege, PagingMode, PhyPageNumber};
+use crate::Page;
+use crate::fdt::DeviceTree;
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether INVPCID can be used, which also means PCIDs are enabled.
+static INVPCID: AtomicBool = AtomicBool::new(false);
+/// Whether 1 GiB pages can be mapped, which not every x86-64 CPU supports.
+static GIGANTIC_PAGES: AtomicBool = AtomicBool::new(false);
+/// Bits of entropy credited per RDSEED word; the CPU is not trusted to be the only source.
+const RDSEED_WORD_BITS: usize = 16;
+/// Attempts at RDRAND or RDSEED before giving up, as both may run dry for a while.
+const RDRAND_RETRIES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Arch;
+impl super::ArchImpl for Arch {
+    fn halt() -> ! {
+        log::error!("Halting the CPU indefinitely.");
+        loop {
+            unsafe {
+                asm!("cli", "hlt", options(nomem, nostack));
+            }
+        }
+    }
+    fn wait_for_interrupt() {
+        unsafe {
+            asm!("hlt", options(nomem, nostack));
+        }
+    }
+    unsafe fn run_on_stack(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+        // The pushed zero stands in for a return address, leaving the stack aligned as on any call.
+        unsafe {
+            asm!(
+                "mov rsp, rdx",
+                "xor ebp, ebp",
+                "push 0",
+                "jmp rsi",
+                in("rdi") arg,
+                in("rsi") entry,
+                in("rdx") stack_top,
+                options(noreturn)
+            );
+        }
+    }
+    fn probe(_device_tree: Option<&DeviceTree<'_>>) {
+        let pcid = __cpuid_count(1, 0).ecx & CPUID_1_ECX_PCID != 0;
+        let invpcid = __cpuid_count(7, 0).ebx & CPUID_7_EBX_INVPCID != 0;
+        // CR4.PCIDE may only be set while the current PCID is 0, as the bootloader leaves it on every hart.
+        if pcid && invpcid && read_cr3() & len_to_mask(PCID_LEN) == 0 {
+            INVPCID.store(true, Ordering::Relaxed);
+        }
+        let gigantic = __cpuid_count(CPUID_EXTENDED, 0).eax >= CPUID_EXTENDED_FEATURES
+            && __cpuid_count(CPUID_EXTENDED_FEATURES, 0).edx & CPUID_80000001_EDX_PDPE1GB != 0;
+        GIGANTIC_PAGES.store(gigantic, Ordering::Relaxed);
+        Self::init_hart();
+        log::info!(
+            "PCID: {}",
+            if INVPCID.load(Ordering::Relaxed) {
+                "enabled"
+            } else {
+                "unavailable"
+            }
+        );
+        log::info!(
+            "1 GiB pages: {}",
+            if gigantic { "supported" } else { "unsupported" }
+        );
+    }
+    fn init_hart() {
+        // Leaves set bit 7 at every level, which selects the upper half of the PAT at level 0.
+        // Mirroring the lower half there gives those pages the same cache types as large pages.
+        unsafe {
+            write_msr(IA32_PAT, PAT_MIRRORED);
+            asm!("wbinvd", options(nostack));
+        }
+        if INVPCID.load(Ordering::Relaxed) {
+            unsafe { write_cr4(read_cr4() | CR4_PCIDE) };
+        }
+        Self::flush_mmu(None, None);
+    }
+    fn hart_id() -> usize {
+        usize::try_from(unsafe { read_msr(IA32_GS_BASE) }).unwrap()
+    }
+    unsafe fn set_hart_id(id: usize) {
+        // Nothing addresses through GS yet, so its base is free to hold the hart index.
+        unsafe { write_msr(IA32_GS_BASE, u64::try_from(id).unwrap()) };
+    }
+    #[inline(never)]
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let mut fp: usize;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack));
+        }
+        let mut count = 0;
+        // Each frame keeps the caller's frame pointer at the frame pointer and the return address right above it.
+        while count < frames.len() && fp != 0 && fp.is_multiple_of(size_of::<usize>()) {
+            let slot = ptr::with_exposed_provenance::<usize>(fp);
+            let (caller, ra) = unsafe { (slot.read(), slot.add(1).read()) };
+            if ra == 0 {
+                break;
+            }
+            frames[count] = ra;
+            count += 1;
+            if caller <= fp {
+                break;
+            }
+            fp = caller;
+        }
+        count
+    }
+    fn max_leaf_level() -> usize {
+        // 2 MiB pages are always there, 1 GiB pages only with PDPE1GB.
+        if GIGANTIC_PAGES.load(Ordering::Relaxed) {
+            2
+        } else {
+            1
+        }
+    }
+    fn flush_mmu(addr_space: Option<usize>, addr: Option<*const ()>) {
+        log::trace!("Flushing MMU for address space: {addr_space:?}, address: {addr:?}");
+        if !INVPCID.load(Ordering::Relaxed) {
+            // Without PCIDs the TLB only holds the current address space.
+            if let Some(address) = addr {
+                unsafe {
+                    asm!("invlpg [{}]", in(reg) address, options(nostack));
+                }
+            } else {
+                // Toggling CR4.PGE drops every entry, global ones included.
+                unsafe {
+                    let cr4 = read_cr4();
+                    write_cr4(cr4 & !CR4_PGE);
+                    write_cr4(cr4);
+                }
+            }
+            return;
+        }
+        match (addr_space, addr) {
+            (Some(space), Some(address)) => unsafe {
+                invpcid(INVPCID_ADDRESS, space, address.addr());
+            },
+            (Some(space), None) => unsafe { invpcid(INVPCID_SINGLE, space, 0) },
+            // No INVPCID type flushes one address from every address space.
+            (None, _) => unsafe { invpcid(INVPCID_ALL_GLOBAL, 0, 0) },
+        }
+    }
+    unsafe fn set_mmu(addr_space: u16, mode: PagingMode, root_table: PhyPageNumber) -> bool {
+        log::trace!(
+            "Setting MMU with address space: {addr_space}, mode: {mode:?}, root paging table: {root_table:?}"
+        );
+        // CR4.LA57 cannot change while paging is on, so only the current mode can be used.
+        if mode != Self::get_default_paging_mode() || addr_space > Self::get_max_address_space() {
+            return false;
+        }
+        let cr3 = ((usize::from(root_table) & len_to_mask(PPN_LEN)) << Page::BITS)
+            | usize::from(addr_space);
+        unsafe {
+            asm!("mov cr3, {}", in(reg) cr3, options(nostack));
+        }
+        true
+    }
+    fn get_mmu() -> Option<(PagingMode, PhyPageNumber)> {
+        let cr0: usize;
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+        }
+        if cr0 & CR0_PG == 0 {
+            return None;
+        }
+        Some((
+            Self::get_default_paging_mode(),
+            PhyPageNumber::from((read_cr3() >> Page::BITS) & len_to_mask(PPN_LEN)),
+        ))
+    }
+    fn get_max_address_space() -> u16 {
+        if read_cr4() & CR4_PCIDE == 0 {
+            0
+        } else {
+            u16::try_from(len_to_mask(PCID_LEN)).unwrap()
+        }
+    }
+    fn get_default_paging_mode() -> PagingMode {
+        if read_cr4() & CR4_LA57 == 0 {
+            PagingMode::Layer4
+        } else {
+            PagingMode::Layer5
+        }
+    }
+    fn arch_rand() -> usize {
+        let mut rand = Self::cycle_counter();
+        if __cpuid_count(1, 0).ecx & CPUID_1_ECX_RDRAND != 0 {
+            for _ in 0..RDRAND_RETRIES {
+                let value: usize;
+                let ok: u8;
+                unsafe {
+                    asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+                }
+                if ok != 0 {
+                    rand ^= value;
+                    break;
+                }
+            }
+        }
+        rand
+    }
+    fn cycle_counter() -> usize {
+        let (low, high): (u32, u32);
+        unsafe {
+            asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+        }
+        (usize::try_from(high).unwrap() << 32) | usize::try_from(low).unwrap()
+    }
+    fn hardware_entropy(dest: &mut [u8]) -> usize {
+        if __cpuid_count(7, 0).ebx & CPUID_7_EBX_RDSEED == 0 {
+            return 0;
+        }
+        let mut bits = 0;
+        for word in dest.as_chunks_mut::<{ size_of::<usize>() }>().0 {
+            let Some(seed) = (0..RDRAND_RETRIES).find_map(|_| {
+                let value: usize;
+                let ok: u8;
+                unsafe {
+                    asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+                }
+                (ok != 0).then_some(value)
+            }) else {
+                break;
+            };
+            *word = seed.to_ne_bytes();
+            bits += RDSEED_WORD_BITS;
+        }
+        bits
+    }
+
+    fn pte_to_num(pte: PageTableEntry) -> usize {
+        match pte {
+            PageTableEntry::Pointer(pointer) => {
+                // Access is decided by the leaf, so tables allow everything.
+                BASE_PRESENT
+                    | (1 << WRITABLE_OFFSET)
+                    | (1 << USER_OFFSET)
+                    | ((usize::from(pointer.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (usize::from(pointer.reserved) << RESERVED_OFFSET)
+                    | (usize::from(pointer.global) << GLOBAL_OFFSET)
+            }
+            PageTableEntry::Leaf(entry) => {
+                let (writable, executable) = privilege_to_access(entry.privilege);
+                BASE_PRESENT
+                    | (1 << LEAF_OFFSET)
+                    | ((usize::from(entry.to) & len_to_mask(PPN_LEN)) << PPN_OFFSET)
+                    | (usize::from(writable) << WRITABLE_OFFSET)
+                    | (usize::from(!executable) << EXECUTE_DISABLE_OFFSET)
+                    | ((cache_to_number(entry.cache) & len_to_mask(CACHE_LEN)) << CACHE_OFFSET)
+                    | (usize::from(entry.reserved) << RESERVED_OFFSET)
+                    | (usize::from(entry.global) << GLOBAL_OFFSET)
+                    | (usize::from(entry.user) << USER_OFFSET)
+                    | (usize::from(entry.accessed) << ACCESS_OFFSET)
+                    | (usize::from(entry.dirty) << DIRTY_OFFSET)
+            }
+            PageTableEntry::Invalid(ptr) => {
+                assert_eq!(usize::from(ptr) & 1, 0);
+                ptr.into()
+            }
+        }
+    }
+
+    fn num_to_pte(num: usize, level: usize) -> PageTableEntry {
+        // Every entry of the last level is a page, whether or not it selects the upper half of the PAT.
+        let leaf = level == 0 || (num >> LEAF_OFFSET) & 1 != 0;
+        match (num & BASE_PRESENT != 0, leaf) {
+            (false, _) => PageTableEntry::Invalid(InvalidPageTableEntry::from(num)),
+            (true, false) => PageTableEntry::Pointer(PointerPageTableEntry {
+                to: PhyPageNumber::from((num >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                global: (num >> GLOBAL_OFFSET) & 1 != 0,
+                reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+            }),
+            (true, true) => PageTableEntry::Leaf(LeafPageTableEntry {
+                to: PhyPageNumber::from((num >> PPN_OFFSET) & len_to_mask(PPN_LEN)),
+                privilege: access_to_privilege(
+                    (num >> WRITABLE_OFFSET) & 1 != 0,
+                    (num >> EXECUTE_DISABLE_OFFSET) & 1 == 0,
+                ),
+                cache: number_to_cache((num >> CACHE_OFFSET) & len_to_mask(CACHE_LEN)),
+                global: (num >> GLOBAL_OFFSET) & 1 != 0,
+                user: (num >> USER_OFFSET) & 1 != 0,
+                accessed: (num >> ACCESS_OFFSET) & 1 != 0,
+                dirty: (num >> DIRTY_OFFSET) & 1 != 0,
+                reserved: (num >> RESERVED_OFFSET) & 1 != 0,
+            }),
+        }
+    }
+}
+
+const BASE_PRESENT: usize = 1;
+
+const WRITABLE_OFFSET: usize = 1;
+const USER_OFFSET: usize = 2;
+/// Write-through and cache-disable, indexing the PAT together with the leaf bit.
+const CACHE_OFFSET: usize = 3;
+const CACHE_LEN: usize = 2;
+const ACCESS_OFFSET: usize = 5;
+const DIRTY_OFFSET: usize = 6;
+/// Page size above level 0 and PAT index at level 0, set on every leaf.
+const LEAF_OFFSET: usize = 7;
+/// Ignored by the hardware in pointer entries, where it is kept for software.
+const GLOBAL_OFFSET: usize = 8;
+/// Available to software at every level.
+const RESERVED_OFFSET: usize = 9;
+const PPN_OFFSET: usize = 12;
+const PPN_LEN: usize = 40;
+const EXECUTE_DISABLE_OFFSET: usize = 63;
+
+const CR0_PG: usize = 1 << 31;
+const CR4_PGE: usize = 1 << 7;
+const CR4_LA57: usize = 1 << 12;
+const CR4_PCIDE: usize = 1 << 17;
+const PCID_LEN: usize = 12;
+const CPUID_1_ECX_PCID: u32 = 1 << 17;
+const CPUID_1_ECX_RDRAND: u32 = 1 << 30;
+const CPUID_7_EBX_INVPCID: u32 = 1 << 10;
+const CPUID_7_EBX_RDSEED: u32 = 1 << 18;
+/// The leaf returning the highest extended leaf.
+const CPUID_EXTENDED: u32 = 0x8000_0000;
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const CPUID_80000001_EDX_PDPE1GB: u32 = 1 << 26;
+const INVPCID_ADDRESS: usize = 0;
+const INVPCID_SINGLE: usize = 1;
+const INVPCID_ALL_GLOBAL: usize = 2;
+const IA32_PAT: u32 = 0x277;
+const IA32_GS_BASE: u32 = 0xC000_0101;
+/// Write-back, write-through, uncached-minus and uncached, in both halves.
+const PAT_MIRRORED: u64 = 0x0007_0406_0007_0406;
+
+/// Returns whether pages are writable and executable; execute-only pages stay readable.
+const fn privilege_to_access(p: PagePrivilege) -> (bool, bool) {
+    match p {
+        PagePrivilege::ReadOnly => (false, false),
+        PagePrivilege::ExecuteOnly | PagePrivilege::ReadExecute => (false, true),
+        PagePrivilege::ReadWrite => (true, false),
+        PagePrivilege::ReadWriteExecute => (true, true),
+    }
+}
+const fn access_to_privilege(writable: bool, executable: bool) -> PagePrivilege {
+    match (writable, executable) {
+        (false, false) => PagePrivilege::ReadOnly,
+        (false, true) => PagePrivilege::ReadExecute,
+        (true, false) => PagePrivilege::ReadWrite,
+        (true, true) => PagePrivilege::ReadWriteExecute,
+    }
+}
+/// Decodes the PAT index bits; write-through pages, which the kernel never makes, count as non-cacheable.
+const fn number_to_cache(num: usize) -> PageCache {
+    match num {
+        0 => PageCache::Cacheable,
+        3 => PageCache::IO,
+        _ => PageCache::NonCacheable,
+    }
+}
+/// Encodes the cache type as PAT index bits: write-back, uncached-minus or uncached.
+const fn cache_to_number(c: PageCache) -> usize {
+    match c {
+        PageCache::Cacheable => 0,
+        PageCache::NonCacheable => 2,
+        PageCache::IO => 3,
+    }
+}
+const fn len_to_mask(len: usize) -> usize {
+    (1usize << len).overflowing_sub(1).0
+}
+
+fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+    }
+    cr3
+}
+
+fn read_cr4() -> usize {
+    let cr4: usize;
+    unsafe {
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+    }
+    cr4
+}
+
+unsafe fn write_cr4(cr4: usize) {
+    unsafe {
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack));
+    }
+}
+
+unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+unsafe fn write_msr(msr: u32, value: u64) {
+    let (low, high) = (value & u64::from(u32::MAX), value >> 32);
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") u32::try_from(low).unwrap(),
+            in("edx") u32::try_from(high).unwrap(),
+            options(nostack)
+        );
+    }
+}
+
+unsafe fn invpcid(kind: usize, pcid: usize, addr: usize) {
+    let descriptor: [usize; 2] = [pcid, addr];
+    unsafe {
+        asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &raw const descriptor, options(nostack));
+    }
+}
@@ -1,6 +1,6 @@
 target/esp: target/kernel
 	mkdir -p target/esp
 	mkdir -p target/esp/EFI/BOOT
-	dd if=${LIMINE_PATH}/BOOT${ARCH_UPPER}.EFI of=target/esp/EFI/BOOT/BOOT${ARCH_UPPER}.EFI >/dev/null
+	dd if=${LIMINE_PATH}/BOOT${EFI_ARCH}.EFI of=target/esp/EFI/BOOT/BOOT${EFI_ARCH}.EFI >/dev/null
 	dd if=target/kernel of=target/esp/kernel >/dev/null
 	printf "timeout: 0\nserial: yes\nrandomise_memory: ${EXPENS_TEST}\nverbose: yes\n/CHOS:\nprotocol: limine\npath: boot():/kernel\nkaslr: yes" >target/esp/limine.conf
\ No newline at end of file
@@ -3,15 +3,22 @@
 #![feature(ptr_metadata, exact_div, pointer_is_aligned_to, ptr_as_uninit)]
 extern crate kernel;
 
+#[cfg(target_arch = "aarch64")]
+use arrayvec::ArrayVec;
+#[cfg(any(target_arch = "x86_64", target_arch = "loongarch64"))]
+use core::arch::asm;
+#[cfg(target_arch = "aarch64")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{
-    arch::asm,
     ffi::c_void,
     fmt,
-    ptr::{self, addr_of},
+    ptr::{self, NonNull, addr_of},
 };
+#[cfg(target_arch = "aarch64")]
+use kernel::sync::SpinLock;
 use kernel::{
     Page,
-    page::{PhysicalPageAccessGuard, PhysicalPageAccessor},
+    page::{PhysicalPageAccessGuard, PhysicalPageAccessor, PhysicalPageDirectMap},
 };
 
 #[allow(clippy::wildcard_imports)]
@@ -45,6 +52,16 @@ static HHDM: HhdmRequest = HhdmRequest::new();
 #[used]
 static MEMORY_MAP: MemoryMapRequest = MemoryMapRequest::new();
 
+#[unsafe(link_section = ".limine_reqs")]
+#[used]
+static DEVICE_TREE: DeviceTreeBlobRequest = DeviceTreeBlobRequest::new();
+
+/// Limine has no multiprocessor request on LoongArch, which only ever runs the boot hart.
+#[cfg(not(target_arch = "loongarch64"))]
+#[unsafe(link_section = ".limine_reqs")]
+#[used]
+static MP: MpRequest = MpRequest::new();
+
 #[unsafe(link_section = ".limine_reqs")]
 #[used]
 static STACK_SIZE: StackSizeRequest = StackSizeRequest::new().with_size((Page::SIZE * 512) as u64);
@@ -62,23 +79,34 @@ unsafe extern "C" {
     static KERNEL_BL_END: c_void;
 }
 
-fn to_kernel_memory_type(ty: limine::memory_map::EntryType) -> Option<kernel::MemoryMapType> {
+fn to_kernel_memory_type(ty: limine::memory_map::EntryType) -> kernel::MemoryMapType {
     match ty {
-        limine::memory_map::EntryType::USABLE => Some(kernel::MemoryMapType::Unused),
+        limine::memory_map::EntryType::USABLE => kernel::MemoryMapType::Unused,
         limine::memory_map::EntryType::BOOTLOADER_RECLAIMABLE => {
-            Some(kernel::MemoryMapType::BootloaderReserved)
+            kernel::MemoryMapType::BootloaderReserved
+        }
+        limine::memory_map::EntryType::ACPI_RECLAIMABLE => kernel::MemoryMapType::AcpiReclaimable,
+        limine::memory_map::EntryType::ACPI_NVS => kernel::MemoryMapType::AcpiNvs,
+        limine::memory_map::EntryType::BAD_MEMORY => kernel::MemoryMapType::BadMemory,
+        limine::memory_map::EntryType::FRAMEBUFFER => kernel::MemoryMapType::Framebuffer,
+        limine::memory_map::EntryType::EXECUTABLE_AND_MODULES => {
+            kernel::MemoryMapType::ExecutableAndModules
         }
-        limine::memory_map::EntryType::RESERVED => Some(kernel::MemoryMapType::Reserved),
-        _ => None,
+        _ => kernel::MemoryMapType::Reserved,
     }
 }
 
 struct BootParms {
     rng: Option<kernel::rng::Rng>,
-    memory_map: &'static [&'static limine::memory_map::Entry],
+    memory_map: kernel::page::memmap::MemoryMap,
+    limine_memory_map: &'static [&'static limine::memory_map::Entry],
     hhdm_offset: usize,
     kernel_vbase: usize,
     kernel_pbase: usize,
+    device_tree: Option<kernel::fdt::DeviceTree<'static>>,
+    boot_hart: usize,
+    #[cfg(not(target_arch = "loongarch64"))]
+    cpus: &'static [&'static limine::mp::Cpu],
 }
 
 impl kernel::BootParms for BootParms {
@@ -86,24 +114,21 @@ impl kernel::BootParms for BootParms {
         self.rng.take().expect("try to take RNG more than once")
     }
 
+    fn entropy_seed(&self) -> Option<&[u8]> {
+        // Limine hands over no random seed; firmware seeds arrive through the device tree.
+        None
+    }
+
     fn make_memory_map_accessor(
         &self,
     ) -> impl Iterator<Item = (kernel::PhyPageNumber, usize, kernel::MemoryMapType)> + '_ {
-        self.memory_map.iter().filter_map(|entry| {
-            to_kernel_memory_type(entry.entry_type).map(|ty| {
-                (
-                    usize::try_from(entry.base).unwrap().exact_div(4096).into(),
-                    usize::try_from(entry.length).unwrap().exact_div(4096),
-                    ty,
-                )
-            })
-        })
+        self.memory_map.iter()
     }
 
     fn extra_map_iter(
         &self,
     ) -> impl Iterator<Item = (kernel::PhyPageNumber, kernel::VirtPageNumber, usize)> + '_ {
-        self.memory_map.iter().filter_map(|entry| {
+        self.limine_memory_map.iter().filter_map(|entry| {
             if entry.entry_type != limine::memory_map::EntryType::BOOTLOADER_RECLAIMABLE {
                 return None;
             }
@@ -117,7 +142,7 @@ impl kernel::BootParms for BootParms {
         })
     }
 
-    fn take_phy_page_accessor(&mut self) -> impl kernel::page::PhysicalPageAccessor {
+    fn take_phy_page_accessor(&mut self) -> impl kernel::page::PhysicalPageDirectMap {
         struct Accessor<'a> {
             rf: &'a BootParms,
         }
@@ -143,9 +168,94 @@ impl kernel::BootParms for BootParms {
                 }
             }
         }
+        impl PhysicalPageDirectMap for Accessor<'_> {
+            fn phy_to_virt(&self, phy_page_number: kernel::PhyPageNumber) -> NonNull<Page> {
+                NonNull::new(ptr::with_exposed_provenance_mut::<Page>(
+                    self.rf.hhdm_offset + usize::from(phy_page_number) * Page::SIZE,
+                ))
+                .unwrap()
+            }
+
+            fn virt_to_phy(&self, ptr: *const u8) -> Option<kernel::PhyPageNumber> {
+                let end = self
+                    .rf
+                    .memory_map
+                    .iter()
+                    .last()
+                    .map_or(0, |(start, len, _)| usize::from(start) + len);
+                ptr.addr()
+                    .checked_sub(self.rf.hhdm_offset)
+                    .map(|offset| offset / Page::SIZE)
+                    .filter(|page| *page < end)
+                    .map(kernel::PhyPageNumber::from)
+            }
+        }
         Accessor { rf: self }
     }
 
+    fn device_tree(&self) -> Option<kernel::fdt::DeviceTree<'_>> {
+        self.device_tree
+    }
+
+    fn boot_hart(&self) -> usize {
+        self.boot_hart
+    }
+
+    #[cfg(not(target_arch = "loongarch64"))]
+    fn secondary_harts(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cpus
+            .iter()
+            .map(|cpu| hardware_id(cpu))
+            .filter(|&id| id != self.boot_hart)
+    }
+
+    #[cfg(target_arch = "loongarch64")]
+    fn secondary_harts(&self) -> impl Iterator<Item = usize> + '_ {
+        core::iter::empty()
+    }
+
+    #[cfg(not(target_arch = "loongarch64"))]
+    fn start_hart(hardware_id: usize) -> bool {
+        let Some(cpu) = MP.get_response().and_then(|mp| {
+            mp.cpus()
+                .iter()
+                .find(|cpu| self::hardware_id(cpu) == hardware_id)
+        }) else {
+            return false;
+        };
+        cpu.goto_address.write(start_secondary);
+        true
+    }
+
+    #[cfg(target_arch = "loongarch64")]
+    fn start_hart(_hardware_id: usize) -> bool {
+        false
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn map_devices(
+        mut map: impl FnMut(kernel::PhyPageNumber, kernel::VirtPageNumber, usize) -> bool,
+    ) {
+        let base = UART_BASE.load(Ordering::Relaxed);
+        let Some(hhdm) = HHDM.get_response().filter(|_| base != 0) else {
+            return;
+        };
+        // Placed in the direct map, which Limine leaves without device memory.
+        let page = base & !(Page::SIZE - 1);
+        let virt = usize::try_from(hhdm.offset()).unwrap() + page;
+        if map((page / Page::SIZE).into(), (virt / Page::SIZE).into(), 1) {
+            let uart = virt + (base - page);
+            let mut early = EARLY_OUTPUT.lock();
+            UART.store(uart, Ordering::Release);
+            early.iter().for_each(|&byte| pl011_write(uart, byte));
+            early.clear();
+        }
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fn map_devices(_map: impl FnMut(kernel::PhyPageNumber, kernel::VirtPageNumber, usize) -> bool) {
+    }
+
     fn kernel_address(&self) -> kernel::KernelAddress {
         assert_eq!(self.kernel_vbase, addr_of!(KERNEL_TEXT_START).addr());
         let pbase = self.kernel_pbase.exact_div(Page::SIZE);
@@ -191,9 +301,27 @@ impl kernel::BootParms for BootParms {
     }
 }
 
-struct SBILog;
-static SBILOG: SBILog = SBILog;
-impl log::Log for SBILog {
+/// Returns the ID the kernel knows a hart by: its local APIC ID, hart ID or MPIDR.
+#[cfg(not(target_arch = "loongarch64"))]
+fn hardware_id(cpu: &limine::mp::Cpu) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    let id = u64::from(cpu.lapic_id);
+    #[cfg(target_arch = "riscv64")]
+    let id = cpu.hartid;
+    #[cfg(target_arch = "aarch64")]
+    let id = cpu.mpidr;
+    usize::try_from(id).unwrap()
+}
+
+/// Entry point of a secondary hart once Limine lets it go.
+#[cfg(not(target_arch = "loongarch64"))]
+extern "C" fn start_secondary(cpu: &limine::mp::Cpu) -> ! {
+    unsafe { kernel::smp::secondary_entry(hardware_id(cpu)) }
+}
+
+struct BootLog;
+static BOOT_LOG: BootLog = BootLog;
+impl log::Log for BootLog {
     fn enabled(&self, _metadata: &log::Metadata) -> bool {
         true
     }
@@ -203,12 +331,7 @@ impl log::Log for SBILog {
         struct Writer;
         impl Write for Writer {
             fn write_str(&mut self, s: &str) -> fmt::Result {
-                s.bytes().for_each(|b| unsafe {
-                    asm!("ecall",
-                    in("a0") usize::from(b),
-                    in("a6") 2usize,
-                    in("a7") 0x4442434Eusize);
-                });
+                s.bytes().for_each(console_write);
                 Ok(())
             }
         }
@@ -224,6 +347,111 @@ impl log::Log for SBILog {
     fn flush(&self) {}
 }
 
+/// Writes a byte to the SBI debug console.
+#[cfg(target_arch = "riscv64")]
+fn console_write(byte: u8) {
+    let _ = kernel::arch::sbi::console_write_byte(byte);
+}
+
+/// Writes a byte to the first legacy serial port.
+#[cfg(target_arch = "x86_64")]
+fn console_write(byte: u8) {
+    const COM1: u16 = 0x3F8;
+    const LINE_STATUS: u16 = COM1 + 5;
+    const TRANSMIT_EMPTY: u8 = 1 << 5;
+    loop {
+        let status: u8;
+        unsafe {
+            asm!("in al, dx", out("al") status, in("dx") LINE_STATUS, options(nomem, nostack));
+        }
+        if status & TRANSMIT_EMPTY != 0 {
+            break;
+        }
+    }
+    unsafe {
+        asm!("out dx, al", in("dx") COM1, in("al") byte, options(nomem, nostack));
+    }
+}
+
+/// Physical address of the PL011 the device tree names in `/chosen/stdout-path`, 0 if there is none.
+#[cfg(target_arch = "aarch64")]
+static UART_BASE: AtomicUsize = AtomicUsize::new(0);
+/// Virtual address of the PL011, 0 until the kernel page tree maps it.
+#[cfg(target_arch = "aarch64")]
+static UART: AtomicUsize = AtomicUsize::new(0);
+/// Output written before the PL011 is mapped, replayed once it is; the rest is dropped.
+#[cfg(target_arch = "aarch64")]
+static EARLY_OUTPUT: SpinLock<ArrayVec<u8, EARLY_OUTPUT_BYTES>> =
+    SpinLock::new(ArrayVec::new_const());
+#[cfg(target_arch = "aarch64")]
+const EARLY_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// Writes a byte to the PL011 console, or holds it back until the UART is mapped.
+#[cfg(target_arch = "aarch64")]
+fn console_write(byte: u8) {
+    let mut uart = UART.load(Ordering::Acquire);
+    if uart == 0 {
+        let mut early = EARLY_OUTPUT.lock();
+        uart = UART.load(Ordering::Acquire);
+        if uart == 0 {
+            let _ = early.try_push(byte);
+            return;
+        }
+    }
+    pl011_write(uart, byte);
+}
+
+#[cfg(target_arch = "aarch64")]
+fn pl011_write(uart: usize, byte: u8) {
+    const FLAGS: usize = 0x18;
+    const TRANSMIT_FULL: u32 = 1 << 5;
+    unsafe {
+        while ptr::with_exposed_provenance::<u32>(uart + FLAGS).read_volatile() & TRANSMIT_FULL != 0
+        {
+        }
+        ptr::with_exposed_provenance_mut::<u32>(uart).write_volatile(u32::from(byte));
+    }
+}
+
+/// Returns the physical address of the PL011 `/chosen/stdout-path` names, directly or through `/aliases`.
+/// The address is taken as is, so the UART must not sit behind a bus translating addresses.
+#[cfg(target_arch = "aarch64")]
+fn find_uart(tree: &kernel::fdt::DeviceTree<'_>) -> Option<usize> {
+    use kernel::fdt::{read_cells, read_strings};
+    let stdout = tree.find("/chosen")?.property("stdout-path")?;
+    // Options such as the baud rate follow a colon.
+    let path = read_strings(stdout).next()?.split(':').next()?;
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        read_strings(tree.find("/aliases")?.property(path)?).next()?
+    };
+    let node = tree.find(path)?;
+    if !read_strings(node.property("compatible")?).any(|compatible| compatible == "arm,pl011") {
+        return None;
+    }
+    let parent = tree.find(path.rsplit_once('/')?.0)?;
+    let mut reg = node.property("reg")?;
+    usize::try_from(read_cells(&mut reg, parent.address_cells())?).ok()
+}
+
+/// Writes a byte to the serial port of the virt machine through an uncached direct mapping window.
+#[cfg(target_arch = "loongarch64")]
+fn console_write(byte: u8) {
+    /// Maps 0x8000_0000_0000_0000 onwards to physical memory, uncached, for the kernel only.
+    const WINDOW: usize = 0x8000_0000_0000_0001;
+    const UART: usize = 0x8000_0000_1fe0_01e0;
+    const LINE_STATUS: usize = UART + 5;
+    const TRANSMIT_EMPTY: u8 = 1 << 5;
+    unsafe {
+        asm!("csrwr {}, 0x183", inout(reg) WINDOW => _, options(nomem, nostack));
+        while ptr::with_exposed_provenance::<u8>(LINE_STATUS).read_volatile() & TRANSMIT_EMPTY == 0
+        {
+        }
+        ptr::with_exposed_provenance_mut::<u8>(UART).write_volatile(byte);
+    }
+}
+
 #[unsafe(export_name = "_start")]
 /// Entry point for the bootloader.
 /// # Panics
@@ -231,7 +459,7 @@ impl log::Log for SBILog {
 pub extern "system" fn entry() -> ! {
     unsafe {
         log::set_max_level_racy(log::LevelFilter::Trace);
-        log::set_logger_racy(&SBILOG).expect("failed to set logger");
+        log::set_logger_racy(&BOOT_LOG).expect("failed to set logger");
     };
     let hhdm = HHDM
         .get_response()
@@ -246,18 +474,51 @@ pub extern "system" fn entry() -> ! {
         .get_response()
         .expect("not receiving kernel address from bootloader(limine)");
 
+    #[cfg(not(target_arch = "loongarch64"))]
+    let mp = MP.get_response();
+
     log::info!("Successfully collected bootloader information");
 
     let mut rng = kernel::rng::Rng::default();
     rng.feed(&hhdm.offset().to_ne_bytes());
     rng.feed(&boot_date.timestamp().as_micros().to_ne_bytes());
 
+    let device_tree = DEVICE_TREE
+        .get_response()
+        .and_then(|dtb| unsafe { kernel::fdt::DeviceTree::from_ptr(dtb.dtb_ptr().cast()) }.ok());
+    // Without one the console stays silent, as nothing else on every Arm machine can print.
+    #[cfg(target_arch = "aarch64")]
+    if let Some(base) = device_tree.as_ref().and_then(find_uart) {
+        UART_BASE.store(base, Ordering::Relaxed);
+    }
+
     let mut parms = BootParms {
         rng: Some(rng),
-        memory_map: memory_map.entries(),
+        memory_map: kernel::page::memmap::MemoryMap::from_entries(memory_map.entries().iter().map(
+            |entry| {
+                (
+                    usize::try_from(entry.base).unwrap(),
+                    usize::try_from(entry.length).unwrap(),
+                    to_kernel_memory_type(entry.entry_type),
+                )
+            },
+        )),
+        limine_memory_map: memory_map.entries(),
         hhdm_offset: hhdm.offset().try_into().unwrap(),
         kernel_vbase: kernel_address.virtual_base().try_into().unwrap(),
         kernel_pbase: kernel_address.physical_base().try_into().unwrap(),
+        device_tree,
+        // Without an answer to the multiprocessor request only the boot hart runs, known by ID 0.
+        #[cfg(target_arch = "loongarch64")]
+        boot_hart: 0,
+        #[cfg(target_arch = "x86_64")]
+        boot_hart: mp.map_or(0, |mp| usize::try_from(mp.bsp_lapic_id()).unwrap()),
+        #[cfg(target_arch = "riscv64")]
+        boot_hart: mp.map_or(0, |mp| usize::try_from(mp.bsp_hartid()).unwrap()),
+        #[cfg(target_arch = "aarch64")]
+        boot_hart: mp.map_or(0, |mp| usize::try_from(mp.bsp_mpidr()).unwrap()),
+        #[cfg(not(target_arch = "loongarch64"))]
+        cpus: mp.map_or(&[], |mp| mp.cpus()),
     };
 
     kernel::start_kernel(&mut parms)
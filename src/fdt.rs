@@ -0,0 +1,372 @@
+use core::{error::Error, ffi::CStr, fmt::Display, iter, slice};
+
+#[cfg(test)]
+pub(crate) mod testing;
+
+const MAGIC: u32 = 0xd00d_feed;
+const TOKEN_BEGIN_NODE: u32 = 1;
+const TOKEN_END_NODE: u32 = 2;
+const TOKEN_PROP: u32 = 3;
+const TOKEN_NOP: u32 = 4;
+const TOKEN_END: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FdtError;
+impl Display for FdtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Invalid flattened device tree")
+    }
+}
+impl Error for FdtError {}
+
+#[derive(Debug, Clone, Copy)]
+/// A read-only view of a flattened device tree blob.
+pub struct DeviceTree<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+    End,
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Parses the header of a device tree blob.
+    /// # Errors
+    /// Returns an error if the header is invalid or does not fit in `blob`.
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let header = |index: usize| {
+            read_u32(blob, index * size_of::<u32>())
+                .map(|value| value as usize)
+                .ok_or(FdtError)
+        };
+        if header(0)? != MAGIC as usize || header(1)? > blob.len() {
+            return Err(FdtError);
+        }
+        let structs = blob
+            .get(header(2)?..header(2)? + header(9)?)
+            .ok_or(FdtError)?;
+        let strings = blob
+            .get(header(3)?..header(3)? + header(8)?)
+            .ok_or(FdtError)?;
+        Ok(DeviceTree { structs, strings })
+    }
+
+    /// Parses a device tree blob whose size is taken from its header.
+    /// # Safety
+    /// `ptr` must point to a device tree blob that stays valid and unmodified for `'a`.
+    /// # Errors
+    /// Returns an error if the header is invalid.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = unsafe { slice::from_raw_parts(ptr, 2 * size_of::<u32>()) };
+        if read_u32(header, 0) != Some(MAGIC) {
+            return Err(FdtError);
+        }
+        let total = read_u32(header, size_of::<u32>()).ok_or(FdtError)?;
+        Self::new(unsafe { slice::from_raw_parts(ptr, total as usize) })
+    }
+
+    /// Returns the root node.
+    #[must_use]
+    pub fn root(&self) -> Option<Node<'a>> {
+        match self.token(0)? {
+            (Token::BeginNode(name), body) => Some(Node {
+                tree: *self,
+                name,
+                body,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Finds a node by its absolute path, such as `/cpus` or `/soc/serial@10000000`.
+    /// A path component without a unit address matches any unit address.
+    #[must_use]
+    pub fn find(&self, path: &str) -> Option<Node<'a>> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(self.root()?, |node, component| node.child(component))
+    }
+
+    /// Returns every node in depth-first order.
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let tree = *self;
+        let mut offset = 0;
+        iter::from_fn(move || {
+            loop {
+                let (token, next) = tree.token(offset)?;
+                offset = next;
+                match token {
+                    Token::BeginNode(name) => {
+                        return Some(Node {
+                            tree,
+                            name,
+                            body: next,
+                        });
+                    }
+                    Token::End => return None,
+                    Token::EndNode | Token::Prop(..) => {}
+                }
+            }
+        })
+    }
+
+    /// Reads the token at `offset`, skipping NOPs.
+    /// Returns the token and the offset of the next one.
+    fn token(&self, mut offset: usize) -> Option<(Token<'a>, usize)> {
+        loop {
+            let kind = read_u32(self.structs, offset)?;
+            offset += size_of::<u32>();
+            match kind {
+                TOKEN_BEGIN_NODE => {
+                    let name = read_str(self.structs.get(offset..)?)?;
+                    let next = (offset + name.len() + 1).next_multiple_of(size_of::<u32>());
+                    return Some((Token::BeginNode(name), next));
+                }
+                TOKEN_END_NODE => return Some((Token::EndNode, offset)),
+                TOKEN_PROP => {
+                    let len = read_u32(self.structs, offset)? as usize;
+                    let name_offset = read_u32(self.structs, offset + size_of::<u32>())? as usize;
+                    let start = offset + 2 * size_of::<u32>();
+                    let value = self.structs.get(start..start + len)?;
+                    let name = read_str(self.strings.get(name_offset..)?)?;
+                    let next = (start + len).next_multiple_of(size_of::<u32>());
+                    return Some((Token::Prop(name, value), next));
+                }
+                TOKEN_NOP => {}
+                TOKEN_END => return Some((Token::End, offset)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A node of a [`DeviceTree`].
+pub struct Node<'a> {
+    tree: DeviceTree<'a>,
+    name: &'a str,
+    body: usize,
+}
+impl<'a> Node<'a> {
+    /// Returns the node name including its unit address.
+    #[must_use]
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Returns the node name without its unit address.
+    #[must_use]
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or_default()
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + 'a {
+        let tree = self.tree;
+        let mut offset = self.body;
+        iter::from_fn(move || match tree.token(offset)? {
+            (Token::Prop(name, value), next) => {
+                offset = next;
+                Some((name, value))
+            }
+            _ => None,
+        })
+    }
+
+    #[must_use]
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|(prop, _)| *prop == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the `#address-cells` of this node's children.
+    #[must_use]
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(|value| read_u32(value, 0))
+            .map_or(2, |cells| cells as usize)
+    }
+
+    /// Returns the `#size-cells` of this node's children.
+    #[must_use]
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(|value| read_u32(value, 0))
+            .map_or(1, |cells| cells as usize)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let tree = self.tree;
+        let mut offset = Some(self.body);
+        iter::from_fn(move || {
+            loop {
+                let (token, next) = tree.token(offset?)?;
+                match token {
+                    Token::Prop(..) => offset = Some(next),
+                    Token::BeginNode(name) => {
+                        let child = Node {
+                            tree,
+                            name,
+                            body: next,
+                        };
+                        offset = child.end();
+                        return Some(child);
+                    }
+                    Token::EndNode | Token::End => return None,
+                }
+            }
+        })
+    }
+
+    /// Finds a direct child by name, with or without its unit address.
+    #[must_use]
+    pub fn child(&self, name: &str) -> Option<Node<'a>> {
+        self.children()
+            .find(|child| child.name == name || (!name.contains('@') && child.base_name() == name))
+    }
+
+    /// Returns the offset right after this node's end token.
+    fn end(&self) -> Option<usize> {
+        let mut depth = 0usize;
+        let mut offset = self.body;
+        loop {
+            let (token, next) = self.tree.token(offset)?;
+            offset = next;
+            match token {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode if depth == 0 => return Some(offset),
+                Token::EndNode => depth -= 1,
+                Token::Prop(..) => {}
+                Token::End => return None,
+            }
+        }
+    }
+}
+
+/// Reads a number made of `cells` big-endian 32-bit cells from the front of `value`, advancing it.
+/// Returns `None` if `value` is too short or the number takes more than 2 cells.
+#[must_use]
+pub fn read_cells(value: &mut &[u8], cells: usize) -> Option<u64> {
+    if cells > 2 {
+        return None;
+    }
+    let (number, rest) = value.split_at_checked(cells * size_of::<u32>())?;
+    *value = rest;
+    number
+        .chunks_exact(size_of::<u32>())
+        .try_fold(0u64, |acc, cell| {
+            Some((acc << 32) | u64::from(read_u32(cell, 0)?))
+        })
+}
+
+/// Splits a string list property into its strings, stopping at the first invalid one.
+pub fn read_strings(value: &[u8]) -> impl Iterator<Item = &str> {
+    value
+        .split(|&byte| byte == 0)
+        .map_while(|string| str::from_utf8(string).ok())
+        .filter(|string| !string.is_empty())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + size_of::<u32>())
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_str(data: &[u8]) -> Option<&str> {
+    CStr::from_bytes_until_nul(data).ok()?.to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{DeviceTree, TOKEN_NOP, read_cells, read_strings, testing::Builder};
+
+    fn sample() -> Vec<u8> {
+        Builder::default()
+            .begin("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .prop("compatible", b"riscv-virtio\0simple-bus\0")
+            .begin("cpus")
+            .prop_u32("#address-cells", 1)
+            .prop_u32("#size-cells", 0)
+            .begin("cpu@0")
+            .prop_u32("reg", 0)
+            .end()
+            .token(TOKEN_NOP)
+            .begin("cpu@1")
+            .prop_u32("reg", 1)
+            .end()
+            .end()
+            .begin("memory@80000000")
+            .prop("reg", &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0])
+            .end()
+            .begin("chosen")
+            .end()
+            .end()
+            .finish()
+    }
+
+    #[test]
+    fn finds_nodes_by_path() {
+        let blob = sample();
+        let tree = DeviceTree::new(&blob).unwrap();
+        assert_eq!(tree.nodes().count(), 6);
+        assert_eq!(tree.root().unwrap().children().count(), 3);
+        assert_eq!(tree.find("/cpus").unwrap().children().count(), 2);
+        assert_eq!(
+            tree.find("/cpus/cpu@1").unwrap().property("reg"),
+            Some(&[0, 0, 0, 1][..])
+        );
+        assert_eq!(tree.find("/memory").unwrap().name(), "memory@80000000");
+        assert_eq!(tree.find("/memory@80000000").unwrap().base_name(), "memory");
+        assert!(tree.find("/memory@90000000").is_none());
+        assert!(tree.find("/cpus/cpu@0/missing").is_none());
+        assert!(tree.find("/chosen").unwrap().properties().next().is_none());
+    }
+
+    #[test]
+    fn reads_cells() {
+        let blob = sample();
+        let tree = DeviceTree::new(&blob).unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!((root.address_cells(), root.size_cells()), (2, 2));
+        let cpus = tree.find("/cpus").unwrap();
+        assert_eq!((cpus.address_cells(), cpus.size_cells()), (1, 0));
+        let chosen = tree.find("/chosen").unwrap();
+        assert_eq!((chosen.address_cells(), chosen.size_cells()), (2, 1));
+
+        let mut reg = tree.find("/memory").unwrap().property("reg").unwrap();
+        assert_eq!(read_cells(&mut reg, 2), Some(0x8000_0000));
+        assert_eq!(read_cells(&mut reg, 2), Some(0x1_0000_0000));
+        assert!(reg.is_empty());
+        assert_eq!(read_cells(&mut reg, 1), None);
+        assert_eq!(read_cells(&mut reg, 0), Some(0));
+        let mut wide: &[u8] = &[0; 12];
+        assert_eq!(read_cells(&mut wide, 3), None);
+        assert_eq!(wide.len(), 12);
+    }
+
+    #[test]
+    fn reads_strings() {
+        let blob = sample();
+        let tree = DeviceTree::new(&blob).unwrap();
+        let compatible = tree.root().unwrap().property("compatible").unwrap();
+        assert!(read_strings(compatible).eq(["riscv-virtio", "simple-bus"]));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut blob = sample();
+        assert!(DeviceTree::new(&blob[..blob.len() - 1]).is_err());
+        blob[0] = 0;
+        assert!(DeviceTree::new(&blob).is_err());
+        assert!(DeviceTree::new(&[]).is_err());
+    }
+}
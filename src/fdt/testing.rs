@@ -0,0 +1,80 @@
+use alloc::vec::Vec;
+
+use super::{MAGIC, TOKEN_BEGIN_NODE, TOKEN_END, TOKEN_END_NODE, TOKEN_PROP};
+
+#[derive(Default)]
+/// Assembles a device tree blob token by token.
+pub struct Builder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+impl Builder {
+    pub fn token(&mut self, token: u32) -> &mut Self {
+        self.structs.extend(token.to_be_bytes());
+        self
+    }
+
+    pub fn begin(&mut self, name: &str) -> &mut Self {
+        self.token(TOKEN_BEGIN_NODE);
+        self.structs.extend(name.as_bytes());
+        self.structs.push(0);
+        self.pad()
+    }
+
+    pub fn end(&mut self) -> &mut Self {
+        self.token(TOKEN_END_NODE)
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        let name_offset = u32::try_from(self.strings.len()).unwrap();
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.token(TOKEN_PROP);
+        self.structs
+            .extend(u32::try_from(value.len()).unwrap().to_be_bytes());
+        self.structs.extend(name_offset.to_be_bytes());
+        self.structs.extend(value);
+        self.pad()
+    }
+
+    pub fn prop_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.prop(name, &value.to_be_bytes())
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &value)
+    }
+
+    fn pad(&mut self) -> &mut Self {
+        self.structs
+            .resize(self.structs.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.token(TOKEN_END);
+        let header_len = 40;
+        let strings_offset = header_len + self.structs.len();
+        let total = strings_offset + self.strings.len();
+        let header = [
+            MAGIC as usize,
+            total,
+            header_len,
+            strings_offset,
+            0,
+            17,
+            16,
+            0,
+            self.strings.len(),
+            self.structs.len(),
+        ];
+        let mut blob: Vec<u8> = header
+            .iter()
+            .flat_map(|&field| u32::try_from(field).unwrap().to_be_bytes())
+            .collect();
+        blob.extend(&self.structs);
+        blob.extend(&self.strings);
+        blob
+    }
+}
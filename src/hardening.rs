@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Rng;
+
+/// Guard value in place until the boot path seeds one.
+/// The NUL, newline, carriage return and 0xFF bytes stop most string copies from forging it.
+const INITIAL_STACK_GUARD: usize = 0x5a3c_ff0a_0d00_a5c3;
+/// Rotation applied after mixing the pointer guard in, so low bits alone reveal nothing.
+const MANGLE_ROTATION: u32 = 17;
+
+/// The canary compiled stack protection places in every protected frame.
+#[cfg_attr(target_os = "none", unsafe(export_name = "__stack_chk_guard"))]
+static STACK_GUARD: AtomicUsize = AtomicUsize::new(INITIAL_STACK_GUARD);
+/// The per-boot secret mixed into mangled pointers.
+static POINTER_GUARD: AtomicUsize = AtomicUsize::new(0);
+
+/// Seeds the stack protector guard and the pointer mangling secret from the boot generator.
+/// Always inlined, so the guard changes in the caller's own frame.
+/// # Safety
+/// Must run once, before anything is mangled, from a function that never returns:
+/// every protected frame live across the call would fail its canary check on return.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub unsafe fn init(boot: &mut Rng) {
+    let key = boot.extract_key();
+    let (words, _) = key.as_chunks::<{ size_of::<usize>() }>();
+    // A zero low byte stops string overflows from reproducing the canary.
+    STACK_GUARD.store(usize::from_ne_bytes(words[0]) & !0xFF, Ordering::Relaxed);
+    POINTER_GUARD.store(usize::from_ne_bytes(words[1]), Ordering::Relaxed);
+}
+
+/// Hides a saved code or data address behind the per-boot secret.
+#[must_use]
+pub fn mangle(addr: usize) -> usize {
+    (addr ^ POINTER_GUARD.load(Ordering::Relaxed)).rotate_left(MANGLE_ROTATION)
+}
+
+/// Recovers an address hidden by [`mangle`].
+#[must_use]
+pub fn demangle(mangled: usize) -> usize {
+    mangled.rotate_right(MANGLE_ROTATION) ^ POINTER_GUARD.load(Ordering::Relaxed)
+}
@@ -0,0 +1,465 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    Page, VirtPageNumber,
+    arch::page::{PageCache, PagePrivilege, PagingMode},
+    heap::debug::DebugHeap,
+    page::{
+        PageTree, PhysicalPageAccessor, PhysicalPageAllocator,
+        descriptor::PageKind,
+        stats::{MEMORY_STATS, MemoryCounter},
+    },
+    sync::SpinLock,
+};
+
+pub mod debug;
+
+/// The smallest number of pages the heap grows by at once.
+const GROW_PAGES: usize = 16;
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+/// Source of fresh memory behind the kernel heap.
+pub trait HeapBacking {
+    /// Maps up to `count` fresh writable pages starting at `at`.
+    /// Returns the number of pages mapped, fewer than `count` if memory ran out.
+    fn grow(&self, at: VirtPageNumber, count: usize) -> usize;
+}
+impl<C: PhysicalPageAccessor, A: PhysicalPageAllocator> HeapBacking for PageTree<C, A> {
+    fn grow(&self, at: VirtPageNumber, count: usize) -> usize {
+        for i in 0..count {
+            let Ok(frame) = self.allocator().allocate() else {
+                return i;
+            };
+            self.allocator().retype(frame, 1, PageKind::KernelHeap);
+            if self
+                .map(
+                    frame,
+                    at + i,
+                    1,
+                    PagePrivilege::ReadWrite,
+                    PageCache::Cacheable,
+                    false,
+                )
+                .is_err()
+            {
+                unsafe { self.allocator().deallocate(frame) };
+                return i;
+            }
+        }
+        count
+    }
+}
+
+/// Returns the virtual pages reserved for the kernel heap in `mode`.
+/// The window is the second to last eighth of the address space,
+/// clear of the direct map at the start of the upper half and of the kernel image at its very end.
+#[must_use]
+pub fn heap_window(mode: PagingMode) -> (VirtPageNumber, usize) {
+    let eighth = 1 << (mode.virt_size() - Page::BITS - 3);
+    (VirtPageNumber::MAX - (2 * eighth - 1), eighth)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Counters of the kernel heap, in bytes.
+pub struct HeapUsage {
+    pub mapped: usize,
+    pub free: usize,
+    pub free_blocks: usize,
+}
+
+#[derive(Debug)]
+/// Header of a free block, kept at its start.
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+#[derive(Debug)]
+struct HeapState {
+    base: usize,
+    mapped: usize,
+    limit: usize,
+    backing: Option<NonNull<dyn HeapBacking>>,
+    /// Free blocks in address order.
+    free: Option<NonNull<FreeBlock>>,
+}
+unsafe impl Send for HeapState {}
+
+#[derive(Debug)]
+/// The kernel heap: a first-fit free list over a virtually contiguous window,
+/// which grows by mapping fresh frames at its end.
+pub struct KernelHeap {
+    state: SpinLock<HeapState>,
+}
+impl KernelHeap {
+    #[must_use]
+    pub const fn new() -> Self {
+        KernelHeap {
+            state: SpinLock::new(HeapState {
+                base: 0,
+                mapped: 0,
+                limit: 0,
+                backing: None,
+                free: None,
+            }),
+        }
+    }
+
+    /// Places the heap at `len` virtual pages from `base`, mapped on demand by `backing`.
+    /// # Safety
+    /// The pages must be unused. The heap keeps `backing` past the borrow, so `backing` must not be moved,
+    /// dropped or used mutably elsewhere for as long as anything allocates from or frees to the heap.
+    /// The kernel meets this by handing in a page tree that lives in a frame of [`crate::start_kernel`],
+    /// which never returns.
+    /// # Panics
+    /// Panics if the heap is already initialized.
+    pub unsafe fn init(&self, base: VirtPageNumber, len: usize, backing: &dyn HeapBacking) {
+        let mut state = self.state.lock();
+        assert!(state.backing.is_none(), "Kernel heap initialized twice");
+        state.base = usize::from(base) * Page::SIZE;
+        state.limit = len * Page::SIZE;
+        // Erases the borrow's lifetime: the caller promises `backing` outlives every use of the heap.
+        state.backing = Some(unsafe {
+            mem::transmute::<NonNull<dyn HeapBacking + '_>, NonNull<dyn HeapBacking + 'static>>(
+                NonNull::from(backing),
+            )
+        });
+    }
+
+    /// Returns the current counters.
+    pub fn usage(&self) -> HeapUsage {
+        let state = self.state.lock();
+        let mut usage = HeapUsage {
+            mapped: state.mapped,
+            ..HeapUsage::default()
+        };
+        let mut block = state.free;
+        while let Some(current) = block {
+            let current = unsafe { current.as_ref() };
+            usage.free += current.size;
+            usage.free_blocks += 1;
+            block = current.next;
+        }
+        usage
+    }
+}
+impl Default for KernelHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let mut state = self.state.lock();
+        state
+            .take(size, align)
+            .or_else(|| {
+                state.grow(size + align + MIN_BLOCK);
+                state.take(size, align)
+            })
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        unsafe { self.state.lock().give(ptr.addr(), size) };
+    }
+}
+
+/// Rounds a layout up so every block can later hold a [`FreeBlock`].
+fn block_layout(layout: Layout) -> (usize, usize) {
+    (
+        layout
+            .size()
+            .max(MIN_BLOCK)
+            .next_multiple_of(align_of::<FreeBlock>()),
+        layout.align().max(align_of::<FreeBlock>()),
+    )
+}
+
+fn block_at(addr: usize) -> NonNull<FreeBlock> {
+    NonNull::new(ptr::with_exposed_provenance_mut(addr)).expect("heap block at null")
+}
+
+impl HeapState {
+    /// Carves `size` bytes aligned to `align` out of the first free block that fits.
+    fn take(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let mut link = &raw mut self.free;
+        while let Some(block) = unsafe { *link } {
+            let start = block.addr().get();
+            let FreeBlock { size: len, next } = unsafe { block.read() };
+            let mut aligned = start.next_multiple_of(align);
+            if aligned != start && aligned - start < MIN_BLOCK {
+                aligned = (start + MIN_BLOCK).next_multiple_of(align);
+            }
+            match (start + len).checked_sub(aligned + size) {
+                Some(tail) if tail == 0 || tail >= MIN_BLOCK => {
+                    let mut rest = next;
+                    if tail > 0 {
+                        let after = block_at(aligned + size);
+                        unsafe { after.write(FreeBlock { size: tail, next }) };
+                        rest = Some(after);
+                    }
+                    if aligned > start {
+                        unsafe {
+                            block.write(FreeBlock {
+                                size: aligned - start,
+                                next: rest,
+                            });
+                        }
+                    } else {
+                        unsafe { *link = rest };
+                    }
+                    return Some(block_at(aligned).cast());
+                }
+                _ => link = unsafe { &raw mut (*block.as_ptr()).next },
+            }
+        }
+        None
+    }
+
+    /// Returns `[addr, addr + size)` to the free list, merging it with its neighbours.
+    /// # Safety
+    /// The range must be mapped, unused and not already free.
+    unsafe fn give(&mut self, addr: usize, mut size: usize) {
+        let mut link = &raw mut self.free;
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        while let Some(block) = unsafe { *link }
+            && block.addr().get() < addr
+        {
+            prev = Some(block);
+            link = unsafe { &raw mut (*block.as_ptr()).next };
+        }
+        let mut next = unsafe { *link };
+        if let Some(after) = next
+            && addr + size == after.addr().get()
+        {
+            let after = unsafe { after.read() };
+            size += after.size;
+            next = after.next;
+        }
+        match prev {
+            Some(before) if before.addr().get() + unsafe { before.as_ref() }.size == addr => unsafe {
+                (*before.as_ptr()).size += size;
+                (*before.as_ptr()).next = next;
+            },
+            _ => {
+                let block = block_at(addr);
+                unsafe {
+                    block.write(FreeBlock { size, next });
+                    *link = Some(block);
+                }
+            }
+        }
+    }
+
+    /// Maps at least `bytes` more bytes at the end of the heap, if the window allows.
+    fn grow(&mut self, bytes: usize) {
+        let Some(backing) = self.backing else {
+            return;
+        };
+        let pages = bytes
+            .div_ceil(Page::SIZE)
+            .max(GROW_PAGES)
+            .min((self.limit - self.mapped) / Page::SIZE);
+        if pages == 0 {
+            return;
+        }
+        let end = self.base + self.mapped;
+        let grown = unsafe { backing.as_ref() }.grow(VirtPageNumber::from(end / Page::SIZE), pages);
+        if grown > 0 {
+            self.mapped += grown * Page::SIZE;
+            MEMORY_STATS.add(MemoryCounter::Heap, grown);
+            unsafe { self.give(end, grown * Page::SIZE) };
+        }
+    }
+}
+
+pub static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+/// The global allocator: the kernel heap, checked by [`DebugHeap`] in debug builds.
+#[cfg_attr(target_os = "none", global_allocator)]
+pub static GLOBAL_HEAP: DebugHeap<KernelHeap> = DebugHeap::new(&KERNEL_HEAP);
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::{Cell, UnsafeCell},
+    };
+
+    use super::{FreeBlock, HeapBacking, HeapState, KernelHeap, MIN_BLOCK, block_layout};
+    use crate::{Page, VirtPageNumber};
+
+    /// Host pages the heap grows into, handing out no more than `limit` of them.
+    struct VecBacking {
+        pages: Vec<UnsafeCell<Page>>,
+        mapped: Cell<usize>,
+        limit: usize,
+    }
+    impl VecBacking {
+        fn new(count: usize, limit: usize) -> Self {
+            VecBacking {
+                pages: (0..count)
+                    .map(|_| UnsafeCell::new(Page([0; Page::SIZE])))
+                    .collect(),
+                mapped: Cell::new(0),
+                limit,
+            }
+        }
+
+        fn base(&self) -> usize {
+            self.pages
+                .as_ptr()
+                .cast::<u8>()
+                .cast_mut()
+                .expose_provenance()
+        }
+    }
+    impl HeapBacking for VecBacking {
+        fn grow(&self, at: VirtPageNumber, count: usize) -> usize {
+            let mapped = self.mapped.get();
+            assert_eq!(
+                usize::from(at) * Page::SIZE,
+                self.base() + mapped * Page::SIZE
+            );
+            let grown = count.min(self.limit - mapped);
+            self.mapped.set(mapped + grown);
+            grown
+        }
+    }
+
+    /// A heap over the pages of `backing`, whose first `free` bytes are free.
+    fn state(backing: &VecBacking, free: usize) -> HeapState {
+        let mut state = HeapState {
+            base: backing.base(),
+            mapped: 0,
+            limit: backing.pages.len() * Page::SIZE,
+            backing: None,
+            free: None,
+        };
+        if free > 0 {
+            unsafe { state.give(backing.base(), free) };
+        }
+        state
+    }
+
+    /// Returns the free blocks as `(offset from the heap base, size)`.
+    fn blocks(state: &HeapState) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut block = state.free;
+        while let Some(current) = block {
+            let FreeBlock { size, next } = unsafe { current.read() };
+            blocks.push((current.addr().get() - state.base, size));
+            block = next;
+        }
+        blocks
+    }
+
+    /// Takes a block, returning its offset from the heap base.
+    fn take(state: &mut HeapState, size: usize, align: usize) -> Option<usize> {
+        state
+            .take(size, align)
+            .map(|block| block.addr().get() - state.base)
+    }
+
+    #[test]
+    fn layouts_fit_a_free_block() {
+        let layout = |size, align| block_layout(Layout::from_size_align(size, align).unwrap());
+        let word = align_of::<FreeBlock>();
+        assert_eq!(layout(1, 1), (MIN_BLOCK, word));
+        assert_eq!(layout(MIN_BLOCK + 1, 1), (MIN_BLOCK + word, word));
+        assert_eq!(layout(8, 64), (MIN_BLOCK, 64));
+    }
+
+    #[test]
+    fn take_is_first_fit() {
+        let backing = VecBacking::new(1, 0);
+        let mut state = state(&backing, 256);
+        assert_eq!(take(&mut state, 64, 8).unwrap(), 0);
+        assert_eq!(take(&mut state, 64, 8).unwrap(), 64);
+        assert_eq!(blocks(&state), [(128, 128)]);
+        // Taking the rest exactly leaves no block behind.
+        assert_eq!(take(&mut state, 128, 8).unwrap(), 128);
+        assert!(blocks(&state).is_empty());
+        assert_eq!(take(&mut state, MIN_BLOCK, 8), None);
+    }
+
+    #[test]
+    fn take_never_leaves_slivers() {
+        let backing = VecBacking::new(1, 0);
+        let mut state = state(&backing, 256);
+        // A tail shorter than a free block cannot be split off, so the block is skipped.
+        assert_eq!(take(&mut state, 256 - MIN_BLOCK / 2, 8), None);
+        // Padding of one word is too short for a free block, so the block moves to the next aligned spot.
+        let mut state = self::state(&backing, 0);
+        unsafe { state.give(state.base + 24, 232) };
+        assert_eq!(take(&mut state, MIN_BLOCK, 32).unwrap(), 64);
+        assert_eq!(
+            blocks(&state),
+            [(24, 40), (64 + MIN_BLOCK, 192 - MIN_BLOCK)]
+        );
+        // Padding long enough stays a free block of its own.
+        assert_eq!(take(&mut state, MIN_BLOCK, 128).unwrap(), 128);
+        assert_eq!(
+            blocks(&state),
+            [
+                (24, 40),
+                (64 + MIN_BLOCK, 64 - MIN_BLOCK),
+                (128 + MIN_BLOCK, 128 - MIN_BLOCK)
+            ]
+        );
+    }
+
+    #[test]
+    fn give_merges_with_both_neighbours() {
+        let backing = VecBacking::new(1, 0);
+        let mut state = state(&backing, 0);
+        let base = state.base;
+        unsafe {
+            state.give(base + 128, 64);
+            state.give(base, 64);
+            assert_eq!(blocks(&state), [(0, 64), (128, 64)]);
+            // Touching the block after it only.
+            state.give(base + 96, 32);
+            assert_eq!(blocks(&state), [(0, 64), (96, 96)]);
+            // Touching both.
+            state.give(base + 64, 32);
+        }
+        assert_eq!(blocks(&state), [(0, 192)]);
+    }
+
+    #[test]
+    fn grows_through_the_backing() {
+        static HEAP: KernelHeap = KernelHeap::new();
+        let backing = VecBacking::new(32, 20);
+        let base = VirtPageNumber::from(backing.base() / Page::SIZE);
+        unsafe { HEAP.init(base, 32, &backing) };
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let first = unsafe { HEAP.alloc(small) };
+        assert_eq!(first.addr(), backing.base());
+        // The heap grows by at least 16 pages at once.
+        let usage = HEAP.usage();
+        assert_eq!(usage.mapped, 16 * Page::SIZE);
+        assert_eq!(usage.free, 16 * Page::SIZE - 104);
+        // The backing runs out after four more pages.
+        let large = Layout::from_size_align(20 * Page::SIZE, Page::SIZE).unwrap();
+        assert!(unsafe { HEAP.alloc(large) }.is_null());
+        assert_eq!(HEAP.usage().mapped, 20 * Page::SIZE);
+        let medium = Layout::from_size_align(19 * Page::SIZE, 8).unwrap();
+        let second = unsafe { HEAP.alloc(medium) };
+        assert_eq!(second.addr(), backing.base() + 104);
+        unsafe {
+            HEAP.dealloc(first, small);
+            HEAP.dealloc(second, medium);
+        }
+        let usage = HEAP.usage();
+        assert_eq!((usage.free, usage.free_blocks), (20 * Page::SIZE, 1));
+    }
+}
@@ -0,0 +1,371 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr, slice,
+};
+
+use arrayvec::ArrayVec;
+
+use crate::{Arch, ArchImpl, sync::SpinLock};
+
+/// Bytes of redzone on each side of an allocation.
+pub const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xBB;
+/// Fill of fresh allocations, so reads of uninitialized memory stand out.
+const ALLOC_POISON: u8 = 0xA5;
+/// Fill of freed allocations, checked when they leave the quarantine.
+const FREE_POISON: u8 = 0x6B;
+/// Return addresses recorded per allocation.
+const FRAMES: usize = 6;
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    ptr: usize,
+    layout: Layout,
+    frames: [usize; FRAMES],
+}
+
+#[derive(Debug)]
+struct DebugState<const N: usize, const Q: usize> {
+    live: ArrayVec<Allocation, N>,
+    /// Freed allocations kept poisoned for a while, oldest first.
+    quarantine: ArrayVec<Allocation, Q>,
+    /// Allocations that did not fit in `live`.
+    untracked: usize,
+}
+
+#[derive(Debug)]
+/// A [`GlobalAlloc`] wrapper surrounding every allocation with redzones,
+/// poisoning freed memory, and keeping a table of live allocations with their call stacks.
+/// Freed blocks sit in a quarantine of `Q` blocks, whose poison is checked before they are really freed.
+/// Every check is skipped when `debug_assertions` is off, so the wrapper becomes a pass-through.
+pub struct DebugHeap<H: GlobalAlloc + 'static, const N: usize = 1024, const Q: usize = 64> {
+    inner: &'static H,
+    state: SpinLock<DebugState<N, Q>>,
+}
+impl<H: GlobalAlloc + 'static, const N: usize, const Q: usize> DebugHeap<H, N, Q> {
+    #[must_use]
+    pub const fn new(inner: &'static H) -> Self {
+        DebugHeap {
+            inner,
+            state: SpinLock::new(DebugState {
+                live: ArrayVec::new_const(),
+                quarantine: ArrayVec::new_const(),
+                untracked: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of live allocations, including untracked ones.
+    pub fn live(&self) -> usize {
+        let state = self.state.lock();
+        state.live.len() + state.untracked
+    }
+
+    /// Logs every live allocation with the call stack that made it.
+    pub fn dump(&self) {
+        let Some(state) = self.state.try_lock() else {
+            log::warn!("Heap allocation table is busy, skipping dump");
+            return;
+        };
+        log::info!(
+            "{} live heap allocations, {} untracked",
+            state.live.len(),
+            state.untracked
+        );
+        for allocation in &state.live {
+            log::info!(
+                "Heap allocation {:#x}+{} from {:#x?}",
+                allocation.ptr,
+                allocation.layout.size(),
+                allocation.frames
+            );
+        }
+    }
+
+    /// Checks the poison of every quarantined block and frees them all.
+    /// # Panics
+    /// Panics if a quarantined block was written after being freed.
+    pub fn flush_quarantine(&self) {
+        let mut state = self.state.lock();
+        while !state.quarantine.is_empty() {
+            let allocation = state.quarantine.remove(0);
+            unsafe { self.release(&allocation) };
+        }
+    }
+
+    /// Checks the poison of a quarantined block and returns it to the inner allocator.
+    unsafe fn release(&self, allocation: &Allocation) {
+        let (base, padded) = padded(allocation.ptr, allocation.layout);
+        let block = unsafe {
+            slice::from_raw_parts(ptr::with_exposed_provenance::<u8>(base), padded.size())
+        };
+        if let Some(offset) = block.iter().position(|&byte| byte != FREE_POISON) {
+            panic!(
+                "Heap allocation {:#x}+{} from {:#x?} written after free at {:#x}",
+                allocation.ptr,
+                allocation.layout.size(),
+                allocation.frames,
+                base + offset
+            );
+        }
+        unsafe {
+            self.inner
+                .dealloc(ptr::with_exposed_provenance_mut(base), padded);
+        }
+    }
+}
+unsafe impl<H: GlobalAlloc + 'static, const N: usize, const Q: usize> GlobalAlloc
+    for DebugHeap<H, N, Q>
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !cfg!(debug_assertions) {
+            return unsafe { self.inner.alloc(layout) };
+        }
+        let front = front(layout);
+        let Ok(padded) = Layout::from_size_align(front + layout.size() + REDZONE, layout.align())
+        else {
+            return ptr::null_mut();
+        };
+        let base = unsafe { self.inner.alloc(padded) };
+        if base.is_null() {
+            return base;
+        }
+        unsafe {
+            base.write_bytes(REDZONE_BYTE, front);
+            base.add(front).write_bytes(ALLOC_POISON, layout.size());
+            base.add(front + layout.size())
+                .write_bytes(REDZONE_BYTE, REDZONE);
+        }
+        let ptr = unsafe { base.add(front) };
+        let mut allocation = Allocation {
+            ptr: ptr.expose_provenance(),
+            layout,
+            frames: [0; FRAMES],
+        };
+        Arch::backtrace(&mut allocation.frames);
+        let mut state = self.state.lock();
+        if state.live.try_push(allocation).is_err() {
+            state.untracked += 1;
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !cfg!(debug_assertions) {
+            unsafe { self.inner.dealloc(ptr, layout) };
+            return;
+        }
+        let mut state = self.state.lock();
+        let allocation =
+            if let Some(index) = state.live.iter().position(|live| live.ptr == ptr.addr()) {
+                state.live.swap_remove(index)
+            } else {
+                if let Some(freed) = state
+                    .quarantine
+                    .iter()
+                    .find(|freed| freed.ptr == ptr.addr())
+                {
+                    panic!(
+                        "Double free of heap allocation {ptr:p}+{} from {:#x?}",
+                        freed.layout.size(),
+                        freed.frames
+                    );
+                }
+                assert!(
+                    state.untracked > 0,
+                    "Freed heap pointer {ptr:p} was never allocated"
+                );
+                state.untracked -= 1;
+                Allocation {
+                    ptr: ptr.expose_provenance(),
+                    layout,
+                    frames: [0; FRAMES],
+                }
+            };
+        assert!(
+            allocation.layout == layout,
+            "Heap allocation {ptr:p} from {:#x?} made as {:?} but freed as {layout:?}",
+            allocation.frames,
+            allocation.layout
+        );
+        let (base, padded) = padded(allocation.ptr, layout);
+        let front = unsafe {
+            slice::from_raw_parts(ptr::with_exposed_provenance::<u8>(base), front(layout))
+        };
+        let back = unsafe { slice::from_raw_parts(ptr.add(layout.size()), REDZONE) };
+        assert!(
+            front.iter().chain(back).all(|&byte| byte == REDZONE_BYTE),
+            "Redzone of heap allocation {ptr:p}+{} from {:#x?} overwritten",
+            layout.size(),
+            allocation.frames
+        );
+        unsafe {
+            ptr::with_exposed_provenance_mut::<u8>(base).write_bytes(FREE_POISON, padded.size());
+        }
+        if state.quarantine.is_full() {
+            let oldest = state.quarantine.remove(0);
+            unsafe { self.release(&oldest) };
+        }
+        state.quarantine.push(allocation);
+    }
+}
+
+/// Returns the size of the front redzone, keeping the allocation aligned.
+fn front(layout: Layout) -> usize {
+    REDZONE.max(layout.align())
+}
+
+/// Returns the start and layout of the whole block, redzones included.
+fn padded(ptr: usize, layout: Layout) -> (usize, Layout) {
+    let front = front(layout);
+    (
+        ptr - front,
+        Layout::from_size_align(front + layout.size() + REDZONE, layout.align()).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+    use std::alloc::System;
+
+    use super::DebugHeap;
+    #[cfg(debug_assertions)]
+    use super::{ALLOC_POISON, REDZONE};
+
+    const LAYOUT: Layout = Layout::new::<[u64; 4]>();
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn passes_through_in_release() {
+        static HEAP: DebugHeap<System, 2, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        assert_eq!(HEAP.live(), 0);
+        unsafe { HEAP.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn fresh_allocations_are_poisoned_and_tracked() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, LAYOUT.size()) };
+        assert!(bytes.iter().all(|&byte| byte == ALLOC_POISON));
+        let aligned = Layout::from_size_align(8, 64).unwrap();
+        let other = unsafe { HEAP.alloc(aligned) };
+        assert!(other.addr().is_multiple_of(64));
+        assert_eq!(HEAP.live(), 2);
+        HEAP.dump();
+        unsafe {
+            HEAP.dealloc(ptr, LAYOUT);
+            HEAP.dealloc(other, aligned);
+        }
+        assert_eq!(HEAP.live(), 0);
+        HEAP.flush_quarantine();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn dump_skips_a_busy_table() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let _state = HEAP.state.lock();
+        HEAP.dump();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Redzone")]
+    fn write_past_the_end() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        unsafe {
+            ptr.add(LAYOUT.size() + REDZONE - 1).write(0);
+            HEAP.dealloc(ptr, LAYOUT);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Redzone")]
+    fn write_before_the_start() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        unsafe {
+            ptr.sub(1).write(0);
+            HEAP.dealloc(ptr, LAYOUT);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "written after free")]
+    fn write_after_free_found_leaving_the_quarantine() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptrs = [(); 3].map(|()| unsafe { HEAP.alloc(LAYOUT) });
+        unsafe {
+            HEAP.dealloc(ptrs[0], LAYOUT);
+            ptrs[0].add(3).write(0);
+            HEAP.dealloc(ptrs[1], LAYOUT);
+            // Pushes the first block out of the quarantine.
+            HEAP.dealloc(ptrs[2], LAYOUT);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "written after free")]
+    fn write_after_free_found_by_a_flush() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        unsafe {
+            HEAP.dealloc(ptr, LAYOUT);
+            ptr.write(0);
+        }
+        HEAP.flush_quarantine();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Double free")]
+    fn double_free() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        unsafe {
+            HEAP.dealloc(ptr, LAYOUT);
+            HEAP.dealloc(ptr, LAYOUT);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "freed as")]
+    fn free_with_another_layout() {
+        static HEAP: DebugHeap<System, 4, 2> = DebugHeap::new(&System);
+        let ptr = unsafe { HEAP.alloc(LAYOUT) };
+        unsafe { HEAP.dealloc(ptr, Layout::new::<[u64; 2]>()) };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn allocations_past_the_table_are_counted() {
+        static HEAP: DebugHeap<System, 2, 2> = DebugHeap::new(&System);
+        let ptrs = [(); 3].map(|()| unsafe { HEAP.alloc(LAYOUT) });
+        assert_eq!(HEAP.live(), 3);
+        assert_eq!(HEAP.state.lock().untracked, 1);
+        // Untracked blocks are still checked and poisoned when freed.
+        for ptr in ptrs.into_iter().rev() {
+            unsafe { HEAP.dealloc(ptr, LAYOUT) };
+        }
+        assert_eq!(HEAP.live(), 0);
+        HEAP.flush_quarantine();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "never allocated")]
+    fn free_of_an_unknown_pointer() {
+        static HEAP: DebugHeap<System, 2, 2> = DebugHeap::new(&System);
+        let mut block = [0u64; 8];
+        unsafe { HEAP.dealloc(block.as_mut_ptr().cast(), LAYOUT) };
+    }
+}
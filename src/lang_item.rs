@@ -1,27 +1,18 @@
-unsafe extern "system" {
-    #[link_name = "___nonexist_symbol___"]
-    safe fn unreachable_comptime() -> !;
-}
-struct PesudoGlobalAlloctator;
-unsafe impl alloc::alloc::GlobalAlloc for PesudoGlobalAlloctator {
-    unsafe fn alloc(&self, _: alloc::alloc::Layout) -> *mut u8 {
-        unreachable_comptime()
-    }
-
-    unsafe fn dealloc(&self, _: *mut u8, _: alloc::alloc::Layout) {
-        unreachable_comptime()
-    }
-}
-
-#[global_allocator]
-static ALLOCATOR: PesudoGlobalAlloctator = PesudoGlobalAlloctator;
-
 use crate::arch::Arch;
 use crate::arch::ArchImpl;
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     log::error!("Panic occurred: {info}");
+    if cfg!(debug_assertions) {
+        crate::heap::GLOBAL_HEAP.dump();
+    }
 
     Arch::halt()
 }
+
+/// Called by compiled stack protection when a frame's canary no longer matches the guard.
+#[unsafe(no_mangle)]
+extern "C" fn __stack_chk_fail() -> ! {
+    panic!("Stack smashing detected");
+}
@@ -8,6 +8,7 @@
     slice_as_array,
     ptr_as_ref_unchecked,
     allocator_api,
+    associated_type_defaults,
     exact_div,
     pointer_is_aligned_to,
     ptr_as_uninit,
@@ -19,27 +20,54 @@
 )]
 extern crate alloc;
 
-#[cfg(not(test))]
+#[cfg(target_os = "none")]
 mod lang_item;
 
 pub mod arch;
 pub use arch::{Arch, ArchImpl};
+pub mod fdt;
+pub mod hardening;
+pub mod heap;
+pub mod object;
 pub mod page;
 pub use arch::page::{PhyPageNumber, VirtPageNumber};
 pub use page::Page;
 pub mod rng;
 pub use rng::Rng;
+pub mod slab;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod timer;
 
-use crate::page::PhysicalPageAccessor;
+use crate::arch::page::{PageCache, PagePrivilege};
+use crate::fdt::DeviceTree;
+use crate::page::{
+    PageTree, PhysicalPageDirectMap,
+    buddy::BuddyAllocator,
+    bump::BumpAllocator,
+    cache::HartCachedAllocator,
+    debug::DebugPageTracker,
+    descriptor::{PageDatabase, PageKind},
+    region::FreeRegions,
+    scrub::ScrubbingAllocator,
+    stats::MEMORY_STATS,
+    zone::{Zone, ZoneLimits, ZonedAllocator},
+};
+use crate::slab::KernelCaches;
 
 pub trait BootParms {
     /// Returns the initial random number generator.
     fn take_rng(&mut self) -> Rng;
 
-    /// Returns a physical page accessor.
-    fn take_phy_page_accessor(&mut self) -> impl PhysicalPageAccessor;
+    /// Returns a random seed handed over by the bootloader, if it provides one.
+    fn entropy_seed(&self) -> Option<&[u8]>;
+
+    /// Returns a physical page accessor backed by a permanent mapping of physical memory.
+    fn take_phy_page_accessor(&mut self) -> impl PhysicalPageDirectMap;
 
     /// Accesses the physical memory map provided by the bootloader.
+    /// Entries must be sorted and must not overlap, as produced by [`page::memmap::MemoryMap`].
     fn make_memory_map_accessor(
         &self,
     ) -> impl Iterator<Item = (PhyPageNumber, usize, MemoryMapType)> + '_;
@@ -47,6 +75,24 @@ pub trait BootParms {
     /// Returns the required extra memory map on paging.
     fn extra_map_iter(&self) -> impl Iterator<Item = (PhyPageNumber, VirtPageNumber, usize)> + '_;
 
+    /// Returns the flattened device tree passed by the firmware, if any.
+    fn device_tree(&self) -> Option<DeviceTree<'_>>;
+
+    /// Returns the hardware ID of the boot hart, such as its RISC-V hart ID or x86 local APIC ID.
+    fn boot_hart(&self) -> usize;
+
+    /// Returns the hardware IDs of the other harts the bootloader is able to start.
+    fn secondary_harts(&self) -> impl Iterator<Item = usize> + '_;
+
+    /// Starts the secondary hart with the given hardware ID in [`smp::secondary_entry`].
+    /// Takes no boot parameters, as harts are started once the kernel holds on to them for good.
+    /// Returns false if the hart could not be started.
+    fn start_hart(hardware_id: usize) -> bool;
+
+    /// Maps the devices the bootloader drives itself, such as its console, once the kernel page tree is set.
+    /// `map` maps pages uncached for the kernel, as `(physical, virtual, count)`, returning false if it cannot.
+    fn map_devices(map: impl FnMut(PhyPageNumber, VirtPageNumber, usize) -> bool);
+
     /// Get the kernel address for both physical and virtual.
     /// First element is for main kernel, second is for the bootloader droppable.
     fn kernel_address(&self) -> KernelAddress;
@@ -75,11 +121,153 @@ pub enum MemoryMapType {
     Reserved,
     /// Memory that is used by the bootloader.
     BootloaderReserved,
+    /// ACPI tables, reusable once they have been parsed.
+    AcpiReclaimable,
+    /// Memory the firmware keeps using across sleep states.
+    AcpiNvs,
+    /// Memory reported as defective.
+    BadMemory,
+    /// The framebuffer handed over by the bootloader.
+    Framebuffer,
+    /// The kernel executable and the modules loaded with it.
+    ExecutableAndModules,
+}
+impl MemoryMapType {
+    /// Returns how restrictive the type is; where entries overlap, the higher one wins.
+    #[must_use]
+    pub const fn restrictiveness(self) -> u8 {
+        match self {
+            MemoryMapType::Unused => 0,
+            MemoryMapType::BootloaderReserved => 1,
+            MemoryMapType::AcpiReclaimable => 2,
+            MemoryMapType::ExecutableAndModules => 3,
+            MemoryMapType::Framebuffer => 4,
+            MemoryMapType::AcpiNvs => 5,
+            MemoryMapType::Reserved => 6,
+            MemoryMapType::BadMemory => 7,
+        }
+    }
+
+    /// Returns true if the memory may eventually be handed out by the allocators.
+    #[must_use]
+    pub const fn is_reclaimable(self) -> bool {
+        matches!(
+            self,
+            MemoryMapType::Unused
+                | MemoryMapType::BootloaderReserved
+                | MemoryMapType::AcpiReclaimable
+        )
+    }
 }
 
+#[cfg(debug_assertions)]
+/// Tracks the frames the kernel page tree and heap take and touch, in debug builds.
+static PAGE_TRACKER: DebugPageTracker<4096> = DebugPageTracker::new();
+
+/// Frames scrubbed into the pool of each zone every time the boot hart idles.
+const IDLE_REFILL: usize = 16;
+
 /// Starts the kernel.
+/// # Panics
+/// Panics if the boot environment leaves no usable physical memory.
 pub fn start_kernel<P: BootParms>(parms: &mut P) -> ! {
     log::info!("Starting kernel...");
-    let _rng = parms.take_rng();
-    todo!("Kernel ended, more development needed!");
+    unsafe { smp::init(parms) };
+    let mut boot_rng = parms.take_rng();
+    unsafe { hardening::init(&mut boot_rng) };
+    Arch::probe(parms.device_tree().as_ref());
+    Arch::install_traps();
+    arch::trap::set_handler(arch::trap::TrapKind::UserEnvCall, syscall::handle_trap);
+    timer::init();
+    rng::collect_boot_entropy(parms, boot_rng);
+    let mut regions = FreeRegions::from_boot_parms(parms);
+    let memory = regions.clone();
+    // Descriptors cover the whole memory map, so reserved and reclaimable frames have one too.
+    let span = parms
+        .make_memory_map_accessor()
+        .map(|(start, len, _)| (start, start + len))
+        .reduce(|(low, high), (start, end)| (low.min(start), high.max(end)))
+        .map(|(low, high)| (low, usize::from(high) - usize::from(low)))
+        .expect("empty memory map");
+    let limits = ZoneLimits::from_boot_parms(parms);
+    MEMORY_STATS.record_boot(parms);
+    // The boot page tables the kernel page tree adopts lie outside the allocators.
+    #[cfg(debug_assertions)]
+    for (start, len, ty) in parms.make_memory_map_accessor() {
+        if ty != MemoryMapType::Unused {
+            PAGE_TRACKER.mark_allocated(start, len);
+        }
+    }
+    let accessor = parms.take_phy_page_accessor();
+    let early = BumpAllocator::new(&mut regions).expect("no usable physical memory");
+    let database =
+        PageDatabase::new(&accessor, span, &memory, &early).expect("no usable physical memory");
+    let (base, count) = database.range();
+    log::info!("Page descriptors cover {count} pages from {base:?}");
+    let allocator = ZonedAllocator::new(&regions, limits, |regions| {
+        BuddyAllocator::new(&accessor, regions, &early).map(|buddy| {
+            ScrubbingAllocator::<_, _>::new(&accessor, HartCachedAllocator::<_>::new(buddy))
+        })
+    })
+    .expect("no usable physical memory");
+    for (start, len) in early.used() {
+        for page in (0..len).map(|i| start + i) {
+            database.set_kind(page, PageKind::Kernel, 0);
+        }
+    }
+    let used = early.finish(&allocator);
+    log::info!("Early allocator handed over {used} pages");
+    let described = database.allocator(&allocator, 0);
+    #[cfg(debug_assertions)]
+    let (tables, frames) = (
+        PAGE_TRACKER.accessor(&accessor),
+        PAGE_TRACKER.allocator(&described),
+    );
+    #[cfg(not(debug_assertions))]
+    let (tables, frames) = (&accessor, &described);
+    let (mode, root) = Arch::get_mmu().expect("paging is not enabled");
+    let kernel_space = unsafe { PageTree::from_root(tables, frames, mode, root) };
+    P::map_devices(|phy, virt, count| {
+        // The bootloader may have mapped them already.
+        if (0..count).all(|page| kernel_space.translate(virt + page) == Some(phy + page)) {
+            return true;
+        }
+        let mapped = kernel_space.map(
+            phy,
+            virt,
+            count,
+            PagePrivilege::ReadWrite,
+            PageCache::IO,
+            false,
+        );
+        mapped
+            .inspect_err(|error| log::warn!("Cannot map device pages at {phy:?}: {error}"))
+            .is_ok()
+    });
+    let (heap_base, heap_len) = heap::heap_window(mode);
+    // `kernel_space` is never moved or dropped: this function never returns, so it backs the heap for good.
+    // It cannot be `'static`, as it borrows the memory accessor taken from `parms`.
+    unsafe { heap::KERNEL_HEAP.init(heap_base, heap_len, &kernel_space) };
+    log::info!("Kernel heap reserves {heap_len} pages from {heap_base:?}");
+    let caches = KernelCaches::new(&accessor, kernel_space.allocator());
+    for zone in Zone::ALL {
+        if let Some(zone_allocator) = allocator.zone(zone).map(|zone| zone.backing().backing()) {
+            log::info!(
+                "Zone {zone:?}: {} of {} pages free",
+                zone_allocator.free_count(),
+                zone_allocator.total_count()
+            );
+        }
+    }
+    MEMORY_STATS.log_summary();
+    caches.log_stats();
+    smp::start_secondary_harts::<P>(mode, root);
+    log::info!("Nothing left to start, idling");
+    smp::idle(|| {
+        for zone in Zone::ALL {
+            if let Some(zone_allocator) = allocator.zone(zone) {
+                zone_allocator.refill_pool(IDLE_REFILL);
+            }
+        }
+    })
 }
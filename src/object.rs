@@ -0,0 +1,62 @@
+use core::ptr::NonNull;
+
+use crate::arch::trap::TrapFrame;
+
+/// Slots in every capability node.
+pub const CAPABILITY_SLOTS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Where a thread is in its life.
+pub enum ThreadState {
+    /// Not scheduled yet, or stopped.
+    #[default]
+    Inactive,
+    Runnable,
+    /// Queued on an endpoint.
+    Blocked,
+}
+
+#[derive(Debug)]
+/// A thread of execution: the user registers saved while it is not running and its scheduling state.
+pub struct Thread {
+    pub frame: TrapFrame,
+    pub state: ThreadState,
+    pub priority: u8,
+    /// The next thread queued on the same endpoint.
+    pub next: Option<NonNull<Thread>>,
+}
+impl Thread {
+    /// Creates an inactive thread with every register cleared.
+    #[must_use]
+    pub fn new(priority: u8) -> Self {
+        Thread {
+            frame: TrapFrame::default(),
+            state: ThreadState::Inactive,
+            priority,
+            next: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// A rendezvous point for message passing, holding the threads blocked on it, oldest first.
+pub struct Endpoint {
+    pub head: Option<NonNull<Thread>>,
+    pub tail: Option<NonNull<Thread>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// A reference to a kernel object, held in a capability node slot.
+pub enum Capability {
+    #[default]
+    Null,
+    Thread(NonNull<Thread>),
+    Endpoint(NonNull<Endpoint>),
+    Node(NonNull<CapabilityNode>),
+}
+
+#[derive(Debug, Default)]
+/// A table of capabilities, which may hold further nodes to form a capability space.
+pub struct CapabilityNode {
+    pub slots: [Capability; CAPABILITY_SLOTS],
+}
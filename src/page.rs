@@ -1,8 +1,35 @@
 use crate::{
     Arch, ArchImpl, PhyPageNumber, VirtPageNumber,
-    arch::page::{LeafPageTableEntry, PageTable, PagingMode},
+    arch::page::{
+        LeafPageTableEntry, PageCache, PagePrivilege, PageTable, PageTableEntry, PagingMode,
+        PointerPageTableEntry,
+    },
+    page::descriptor::PageKind,
+    smp,
+    sync::SpinLock,
 };
-use core::{error::Error, fmt::Display, mem::MaybeUninit, ptr, range::Step};
+use arrayvec::ArrayVec;
+use core::{
+    error::Error,
+    fmt::Display,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+    range::Step,
+};
+
+pub mod buddy;
+pub mod bump;
+pub mod cache;
+pub mod debug;
+pub mod descriptor;
+pub mod fault;
+pub mod memmap;
+pub mod region;
+pub mod scrub;
+pub mod stats;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod zone;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PhysicalPageAllocError;
@@ -37,6 +64,33 @@ pub trait PhysicalPageAccessGuard {
     fn get_mut_ptr(&self) -> *mut Page;
 }
 
+impl<C: PhysicalPageAccessor> PhysicalPageAccessor for &C {
+    #[track_caller]
+    fn access_phy_page(&self, phy_page_number: PhyPageNumber) -> impl PhysicalPageAccessGuard + '_ {
+        (**self).access_phy_page(phy_page_number)
+    }
+}
+
+/// An accessor backed by a permanent mapping of physical memory, such as a direct map.
+/// Unlike guards, the pointers it returns stay valid as long as the frames do.
+pub trait PhysicalPageDirectMap: PhysicalPageAccessor {
+    /// Returns the permanent address of a physical page.
+    fn phy_to_virt(&self, phy_page_number: PhyPageNumber) -> NonNull<Page>;
+
+    /// Returns the physical page containing `ptr`, or `None` if `ptr` is outside the mapping.
+    fn virt_to_phy(&self, ptr: *const u8) -> Option<PhyPageNumber>;
+}
+
+impl<C: PhysicalPageDirectMap> PhysicalPageDirectMap for &C {
+    fn phy_to_virt(&self, phy_page_number: PhyPageNumber) -> NonNull<Page> {
+        (**self).phy_to_virt(phy_page_number)
+    }
+
+    fn virt_to_phy(&self, ptr: *const u8) -> Option<PhyPageNumber> {
+        (**self).virt_to_phy(ptr)
+    }
+}
+
 pub(crate) unsafe fn access_phy<T, R>(
     guard: &mut impl PhysicalPageAccessGuard,
     f: impl FnOnce(&mut T) -> R,
@@ -63,6 +117,32 @@ pub trait PhysicalPageAllocator {
     /// # Errors
     /// Returns an error if the allocation fails.
     fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError>;
+    /// Allocate count number of physical pages, the first of which is aligned to align pages.
+    /// Returns the physical page number of the first page.
+    /// # Panics
+    /// Panics if align is not a power of two.
+    /// # Errors
+    /// Returns an error if the allocation fails.
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        let slack = align - 1;
+        let page = self.allocate_contiguous(count + slack)?;
+        let aligned = PhyPageNumber::from(usize::from(page).next_multiple_of(align));
+        let head = usize::from(aligned) - usize::from(page);
+        unsafe {
+            if head > 0 {
+                self.deallocate_contiguous(page, head);
+            }
+            if slack > head {
+                self.deallocate_contiguous(aligned + count, slack - head);
+            }
+        }
+        Ok(aligned)
+    }
     /// Deallocate a physical page.
     /// # Safety
     /// The caller must ensure that the page is not in use and that it was allocated by this allocator.
@@ -77,15 +157,53 @@ pub trait PhysicalPageAllocator {
             unsafe { self.deallocate(page + i) };
         }
     }
+    /// Records that `count` frames from `page`, handed out by this allocator, are now used as `kind`.
+    /// Only allocators keeping per-frame descriptors, such as [`descriptor::DescribedAllocator`], record it.
+    fn retype(&self, page: PhyPageNumber, count: usize, kind: PageKind) {
+        let _ = (page, count, kind);
+    }
+}
+
+impl<A: PhysicalPageAllocator> PhysicalPageAllocator for &A {
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        (**self).allocate()
+    }
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        (**self).allocate_contiguous(count)
+    }
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        (**self).allocate_aligned(count, align)
+    }
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        unsafe { (**self).deallocate(page) }
+    }
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        unsafe { (**self).deallocate_contiguous(page, count) }
+    }
+    fn retype(&self, page: PhyPageNumber, count: usize, kind: PageKind) {
+        (**self).retype(page, count, kind);
+    }
 }
 
+/// Spare tables an unmap may need to split the large pages at both ends of its range.
+const MAX_SPLITS: usize = 2 * (PagingMode::MAX_LAYERS - 1);
+
 #[derive(Debug)]
 /// A page mapping tree.
+/// Changes to it are serialized, as unmapping frees tables a concurrent walk could be in.
+/// Dropping the tree frees its tables, so it must no longer be in use by any hart by then.
 pub struct PageTree<C: PhysicalPageAccessor, A: PhysicalPageAllocator> {
     phy_accessor: C,
     root_ppn: PhyPageNumber,
     allocator: A,
     mode: PagingMode,
+    /// Adopted trees never free tables, as they need not come from `allocator`.
+    adopted: bool,
+    lock: SpinLock<()>,
 }
 impl<C, A> PageTree<C, A>
 where
@@ -101,93 +219,501 @@ where
     }
 
     /// Create a new page tree.
-    /// # Panics
-    /// Panics if allocator fails to allocate the root page.
     /// # Errors
-    /// Returns an error if the allocation fails.
+    /// Returns an error if the root table cannot be allocated.
     pub fn new(
         phy_accessor: C,
         allocator: A,
         mode: PagingMode,
     ) -> Result<Self, PhysicalPageAllocError> {
         let root_ppn = allocator.allocate()?;
-        let mut root = phy_accessor.access_phy_page(root_ppn);
-        unsafe {
-            access_phy::<MaybeUninit<PageTable>, ()>(&mut root, |table| {
-                table.write(PageTable::default());
-            });
-        }
-        drop(root);
-        Ok(PageTree {
+        allocator.retype(root_ppn, 1, PageKind::PageTable);
+        let tree = PageTree {
+            phy_accessor,
+            root_ppn,
+            allocator,
+            mode,
+            adopted: false,
+            lock: SpinLock::new(()),
+        };
+        tree.init_table(root_ppn, |_| PageTableEntry::default());
+        stats::MEMORY_STATS.add(stats::MemoryCounter::PageTables, 1);
+        Ok(tree)
+    }
+
+    /// Adopts an existing page tree, such as the one the bootloader left active.
+    /// # Safety
+    /// `root_ppn` must be a valid root page table for `mode`, whose tables are accessible through `phy_accessor`.
+    /// Tables added later come from `allocator`, but no table is ever freed,
+    /// neither when emptied by [`PageTree::unmap`] nor when the tree is dropped.
+    pub unsafe fn from_root(
+        phy_accessor: C,
+        allocator: A,
+        mode: PagingMode,
+        root_ppn: PhyPageNumber,
+    ) -> Self {
+        PageTree {
             phy_accessor,
             root_ppn,
             allocator,
             mode,
+            adopted: true,
+            lock: SpinLock::new(()),
+        }
+    }
+
+    #[must_use]
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    /// Returns the allocator page tables are taken from.
+    #[must_use]
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Returns every mapping in the tree in address order, lower half first,
+    /// as the first page it maps, the number of pages it maps and its entry.
+    pub fn iter(&self) -> impl Iterator<Item = (VirtPageNumber, usize, LeafPageTableEntry)> + '_ {
+        // The tables being walked, with their level, the page their first entry maps and the next index to read.
+        let mut stack = ArrayVec::<_, { PagingMode::MAX_LAYERS }>::new();
+        stack.push((self.root_ppn, self.mode.levels() - 1, 0, 0));
+        core::iter::from_fn(move || {
+            loop {
+                let (table, level, base, index) = *stack.last()?;
+                if index == PageTable::COUNT {
+                    stack.pop();
+                    continue;
+                }
+                stack.last_mut()?.3 += 1;
+                let virt = base + index * level_len(level);
+                match self.entry(table, level, index) {
+                    PageTableEntry::Leaf(leaf) => {
+                        return Some((self.canonical(virt), level_len(level), leaf));
+                    }
+                    PageTableEntry::Pointer(pointer) => {
+                        stack.push((pointer.to, level - 1, virt, 0));
+                    }
+                    PageTableEntry::Invalid(_) => {}
+                }
+            }
         })
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = LeafPageTableEntry> {
-        // let page_to_iter = |virt_size: usize, virt_start: VirtPageNumber, ppn: PhyPageNumber| {
-        //     let entry_len = size_to_len(virt_size).exact_div(PageTable::COUNT);
-        //     let guard = self.phy_accessor.access_phy_page(ppn);
-        //     let table = unsafe { guard.get_mut_ptr().cast::<PageTable>().as_ref_unchecked() };
-        //     table
-        //         .iter()
-        //         .enumerate()
-        //         .map(|(i, value)| (virt_size + i.strict_mul(entry_len), value))
-        // };
-        // let guard = self.phy_accessor.access_phy_page(self.root_ppn);
-        // let table = unsafe { guard.get_mut_ptr().cast::<PageTable>().as_ref_unchecked() };
-        // let root_len = size_to_len(self.mode.virt_size()).exact_div(PageTable::COUNT);
-        // let root_iter = table.iter().enumerate().map(|(i, value)| {
-        //     (
-        //         if i < PageTable::COUNT.exact_div(2) {
-        //             VirtPageNumber::MIN + i.strict_mul(root_len)
-        //         } else {
-        //             VirtPageNumber::MAX - (PageTable::COUNT.strict_sub(i)).strict_mul(root_len)
-        //         },
-        //         value,
-        //     )
-        // });
-        core::iter::empty()
+    /// Returns the frame `virt_page_number` is mapped to, or `None` if it is not mapped.
+    #[must_use]
+    pub fn translate(&self, virt_page_number: VirtPageNumber) -> Option<PhyPageNumber> {
+        let virt = usize::from(virt_page_number);
+        let mut table = self.root_ppn;
+        for level in (0..self.mode.levels()).rev() {
+            match self.entry(table, level, table_index(virt, level)) {
+                PageTableEntry::Pointer(pointer) => table = pointer.to,
+                PageTableEntry::Leaf(leaf) => {
+                    return Some(leaf.to + (virt & (level_len(level) - 1)));
+                }
+                PageTableEntry::Invalid(_) => return None,
+            }
+        }
+        None
     }
 
+    /// Maps `len` pages from `virt_page_number` to `phy_page_number` with the `cache` type,
+    /// for the kernel, or for user space if `user`.
+    /// Large pages are used where both ranges are aligned for them.
+    /// # Panics
+    /// Panics if the ranges are invalid or any of the pages is already mapped.
+    /// # Errors
+    /// Returns an error if a page table cannot be allocated; the tree is then left as it was.
     pub fn map(
         &self,
         phy_page_number: PhyPageNumber,
         virt_page_number: VirtPageNumber,
         len: usize,
+        privilege: PagePrivilege,
+        cache: PageCache,
+        user: bool,
     ) -> Result<(), PhysicalPageAllocError> {
-        assert!(
-            virt_page_number.is_valid(self.mode)
-                && VirtPageNumber::forward_checked(virt_page_number, len)
-                    .is_some_and(|v| v.is_valid(self.mode)),
-            "Virtual page number is not valid: {virt_page_number:?}, len: {len}"
-        );
+        self.check_range(virt_page_number, len);
         assert!(
             PhyPageNumber::forward_checked(phy_page_number, len).is_some(),
             "Physical page number is not valid: {phy_page_number:?}, len: {len}"
         );
-        // let mut visit = |start: VirtPageNumber, len: usize| -> bool {
-        //     // Input region's start and len, output true if continue to spilt this region or false if done.
-        //     assert!(
-
-        // };
-        todo!();
+        let _lock = self.lock.lock();
+        let mut done = 0;
+        while done < len {
+            let phy = usize::from(phy_page_number) + done;
+            let virt = usize::from(virt_page_number) + done;
+            let level = (0..self.mode.levels().min(Arch::max_leaf_level() + 1))
+                .rev()
+                .find(|&level| {
+                    let size = level_len(level);
+                    phy.is_multiple_of(size) && virt.is_multiple_of(size) && len - done >= size
+                })
+                .unwrap_or_default();
+            let table = match self.walk(virt, level) {
+                Ok(table) => table,
+                Err(error) => {
+                    // Take back the pages mapped so far and the tables left empty on the way to `virt`.
+                    self.clear_range(usize::from(virt_page_number), done, &mut ArrayVec::new());
+                    self.clear_range(virt, 1, &mut ArrayVec::new());
+                    flush_all_harts();
+                    return Err(error);
+                }
+            };
+            let entry = PageTableEntry::Leaf(LeafPageTableEntry {
+                to: phy.into(),
+                privilege,
+                cache,
+                global: !user,
+                user,
+                accessed: true,
+                dirty: true,
+                reserved: false,
+            });
+            let mut guard = self.phy_accessor.access_phy_page(table);
+            let result = unsafe {
+                access_phy(&mut guard, |table: &mut PageTable| {
+                    table.update_at(table_index(virt, level), level, |old| match old {
+                        PageTableEntry::Invalid(_) => Some(entry),
+                        _ => None,
+                    })
+                })
+            };
+            assert!(
+                result.is_ok(),
+                "Virtual page {virt:#x} is already mapped: {result:?}"
+            );
+            done += level_len(level);
+        }
+        flush_all_harts();
+        Ok(())
     }
+
+    /// Unmaps `len` pages from `virt_page_number`, skipping those not mapped.
+    /// Large pages reaching past the range are split first, and tables left empty are freed.
+    /// The frames that were mapped stay allocated.
+    /// # Panics
+    /// Panics if the range is invalid.
+    /// # Errors
+    /// Returns an error if the tables to split large pages cannot be allocated; the tree is then left as it was.
     pub fn unmap(
         &self,
         virt_page_number: VirtPageNumber,
         len: usize,
     ) -> Result<(), PhysicalPageAllocError> {
+        self.check_range(virt_page_number, len);
+        if len == 0 {
+            return Ok(());
+        }
+        let _lock = self.lock.lock();
+        let start = usize::from(virt_page_number);
+        let needed = self.splits_needed(start, start, start + len)
+            + self.splits_needed(start + len - 1, start, start + len);
+        let mut spares = ArrayVec::<_, MAX_SPLITS>::new();
+        for _ in 0..needed {
+            match self.allocate_table(|_| PageTableEntry::default()) {
+                Ok(table) => spares.push(table),
+                Err(error) => {
+                    for table in spares {
+                        unsafe { self.free_table(table) };
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        self.clear_range(start, len, &mut spares);
+        flush_all_harts();
+        for table in spares {
+            unsafe { self.free_table(table) };
+        }
+        Ok(())
+    }
+
+    /// Copies the tree into fresh tables from a clone of its allocator, sharing the mapped frames.
+    /// # Errors
+    /// Returns an error if the tables cannot be allocated; none of them stays allocated then.
+    pub fn try_clone(&self) -> Result<Self, PhysicalPageAllocError>
+    where
+        C: Clone,
+        A: Clone,
+    {
+        let _lock = self.lock.lock();
+        let tree = PageTree::new(self.phy_accessor.clone(), self.allocator.clone(), self.mode)?;
+        self.copy_table(&tree, self.root_ppn, tree.root_ppn, self.mode.levels() - 1)?;
+        Ok(tree)
+    }
+
+    fn check_range(&self, virt_page_number: VirtPageNumber, len: usize) {
         assert!(
             virt_page_number.is_valid(self.mode)
-                && VirtPageNumber::forward_checked(virt_page_number, len)
+                && VirtPageNumber::forward_checked(virt_page_number, len.saturating_sub(1))
                     .is_some_and(|v| v.is_valid(self.mode)),
             "Virtual page number is not valid: {virt_page_number:?}, len: {len}"
         );
-        let mut root = self.phy_accessor.access_phy_page(self.root_ppn);
-        todo!();
+    }
+
+    /// Returns the entry at `index` of `table`, a table of `level`.
+    fn entry(&self, table: PhyPageNumber, level: usize, index: usize) -> PageTableEntry {
+        let mut guard = self.phy_accessor.access_phy_page(table);
+        unsafe {
+            access_phy(&mut guard, |table: &mut PageTable| {
+                table.iter(level).nth(index).unwrap()
+            })
+        }
+    }
+
+    fn set_entry(&self, table: PhyPageNumber, index: usize, entry: PageTableEntry) {
+        let mut guard = self.phy_accessor.access_phy_page(table);
+        unsafe {
+            access_phy(&mut guard, |table: &mut PageTable| table.set(index, entry));
+        }
+    }
+
+    fn is_empty(&self, table: PhyPageNumber, level: usize) -> bool {
+        let mut guard = self.phy_accessor.access_phy_page(table);
+        unsafe {
+            access_phy(&mut guard, |table: &mut PageTable| {
+                table
+                    .iter(level)
+                    .all(|entry| matches!(entry, PageTableEntry::Invalid(_)))
+            })
+        }
+    }
+
+    /// Fills `table` with the entries `entry` returns for each index.
+    fn init_table(&self, table: PhyPageNumber, entry: impl Fn(usize) -> PageTableEntry) {
+        let mut guard = self.phy_accessor.access_phy_page(table);
+        unsafe {
+            access_phy::<MaybeUninit<PageTable>, ()>(&mut guard, |table| {
+                let table = table.write(PageTable::default());
+                for index in 0..PageTable::COUNT {
+                    table.set(index, entry(index));
+                }
+            });
+        }
+    }
+
+    fn allocate_table(
+        &self,
+        entry: impl Fn(usize) -> PageTableEntry,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let table = self.allocator.allocate()?;
+        self.allocator.retype(table, 1, PageKind::PageTable);
+        self.init_table(table, entry);
+        stats::MEMORY_STATS.add(stats::MemoryCounter::PageTables, 1);
+        Ok(table)
+    }
+
+    /// # Safety
+    /// `table` must come from [`PageTree::allocate_table`] and no longer be reachable from the tree.
+    unsafe fn free_table(&self, table: PhyPageNumber) {
+        unsafe { self.allocator.deallocate(table) };
+        stats::MEMORY_STATS.sub(stats::MemoryCounter::PageTables, 1);
+    }
+
+    /// Walks down to the table holding the entries of `level` for `virt`, creating missing tables.
+    fn walk(&self, virt: usize, level: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let mut table = self.root_ppn;
+        for current in (level + 1..self.mode.levels()).rev() {
+            table = match self.entry(table, current, table_index(virt, current)) {
+                PageTableEntry::Pointer(pointer) => pointer.to,
+                PageTableEntry::Leaf(_) => {
+                    panic!("Virtual page {virt:#x} is already mapped by a large page")
+                }
+                PageTableEntry::Invalid(_) => {
+                    self.insert_table(table, current, table_index(virt, current))?
+                }
+            };
+        }
+        Ok(table)
+    }
+
+    /// Installs a fresh table at `index` of `parent`, a table of `level`, unless another one won the race.
+    /// Returns the table now at `index`.
+    fn insert_table(
+        &self,
+        parent: PhyPageNumber,
+        level: usize,
+        index: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let fresh = self.allocate_table(|_| PageTableEntry::default())?;
+        let pointer = PageTableEntry::Pointer(PointerPageTableEntry {
+            to: fresh,
+            global: false,
+            reserved: false,
+        });
+        let mut guard = self.phy_accessor.access_phy_page(parent);
+        let result = unsafe {
+            access_phy(&mut guard, |table: &mut PageTable| {
+                table.update_at(index, level, |old| match old {
+                    PageTableEntry::Invalid(_) => Some(pointer),
+                    _ => None,
+                })
+            })
+        };
+        drop(guard);
+        match result {
+            Ok(_) => Ok(fresh),
+            Err(PageTableEntry::Pointer(pointer)) => {
+                unsafe { self.free_table(fresh) };
+                Ok(pointer.to)
+            }
+            Err(entry) => panic!("Page table entry changed under a mapping: {entry:?}"),
+        }
+    }
+
+    /// Returns how many tables it takes to split the large page holding `virt` down to the edges of `[start, end)`.
+    fn splits_needed(&self, virt: usize, start: usize, end: usize) -> usize {
+        let (virt, start, end) = (virt & self.mask(), start & self.mask(), end & self.mask());
+        let mut table = self.root_ppn;
+        for level in (1..self.mode.levels()).rev() {
+            match self.entry(table, level, table_index(virt, level)) {
+                PageTableEntry::Pointer(pointer) => table = pointer.to,
+                PageTableEntry::Leaf(_) => {
+                    return (1..=level)
+                        .rev()
+                        .take_while(|&level| {
+                            let base = virt & !(level_len(level) - 1);
+                            base < start || base + level_len(level) > end
+                        })
+                        .count();
+                }
+                PageTableEntry::Invalid(_) => return 0,
+            }
+        }
+        0
+    }
+
+    /// Unmaps `len` pages from `start`, splitting large pages with the tables in `spares`.
+    fn clear_range(
+        &self,
+        start: usize,
+        len: usize,
+        spares: &mut ArrayVec<PhyPageNumber, MAX_SPLITS>,
+    ) {
+        if len > 0 {
+            let start = start & self.mask();
+            let root_level = self.mode.levels() - 1;
+            self.clear(self.root_ppn, root_level, 0, start, start + len, spares);
+        }
+    }
+
+    /// Unmaps `[start, end)` from `table`, a table of `level` whose first entry maps `base`.
+    /// Large pages reaching past the range are split with the tables in `spares`,
+    /// and tables left empty are freed unless the tree is adopted.
+    /// Returns true if `table` is left empty.
+    fn clear(
+        &self,
+        table: PhyPageNumber,
+        level: usize,
+        base: usize,
+        start: usize,
+        end: usize,
+        spares: &mut ArrayVec<PhyPageNumber, MAX_SPLITS>,
+    ) -> bool {
+        let len = level_len(level);
+        for index in (start - base) / len..=(end - 1 - base) / len {
+            let entry_base = base + index * len;
+            let mut entry = self.entry(table, level, index);
+            if let PageTableEntry::Leaf(leaf) = entry
+                && (entry_base < start || entry_base + len > end)
+            {
+                let split = spares
+                    .pop()
+                    .expect("no spare table left to split a large page");
+                self.init_table(split, |i| {
+                    PageTableEntry::Leaf(LeafPageTableEntry {
+                        to: leaf.to + i * level_len(level - 1),
+                        ..leaf
+                    })
+                });
+                entry = PageTableEntry::Pointer(PointerPageTableEntry {
+                    to: split,
+                    global: false,
+                    reserved: false,
+                });
+                self.set_entry(table, index, entry);
+            }
+            match entry {
+                PageTableEntry::Leaf(_) => self.set_entry(table, index, PageTableEntry::default()),
+                PageTableEntry::Pointer(pointer) => {
+                    let (child_start, child_end) =
+                        (start.max(entry_base), end.min(entry_base + len));
+                    let empty = self.clear(
+                        pointer.to,
+                        level - 1,
+                        entry_base,
+                        child_start,
+                        child_end,
+                        spares,
+                    );
+                    if empty && !self.adopted {
+                        self.set_entry(table, index, PageTableEntry::default());
+                        // No hart may still walk through the table once it is reused.
+                        flush_all_harts();
+                        unsafe { self.free_table(pointer.to) };
+                    }
+                }
+                PageTableEntry::Invalid(_) => {}
+            }
+        }
+        self.is_empty(table, level)
+    }
+
+    /// Copies the entries of `from`, a table of `level`, into `to`, a table of `into`, copying the tables they point to.
+    fn copy_table(
+        &self,
+        into: &Self,
+        from: PhyPageNumber,
+        to: PhyPageNumber,
+        level: usize,
+    ) -> Result<(), PhysicalPageAllocError> {
+        for index in 0..PageTable::COUNT {
+            match self.entry(from, level, index) {
+                PageTableEntry::Pointer(pointer) => {
+                    // Linked right away, so dropping `into` frees it if a later copy fails.
+                    let table = into.allocate_table(|_| PageTableEntry::default())?;
+                    into.set_entry(
+                        to,
+                        index,
+                        PageTableEntry::Pointer(PointerPageTableEntry {
+                            to: table,
+                            ..pointer
+                        }),
+                    );
+                    self.copy_table(into, pointer.to, table, level - 1)?;
+                }
+                entry @ PageTableEntry::Leaf(_) => into.set_entry(to, index, entry),
+                PageTableEntry::Invalid(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees `table`, a table of `level`, and every table below it.
+    fn free_tree(&self, table: PhyPageNumber, level: usize) {
+        for index in 0..PageTable::COUNT {
+            if let PageTableEntry::Pointer(pointer) = self.entry(table, level, index) {
+                self.free_tree(pointer.to, level - 1);
+            }
+        }
+        unsafe { self.free_table(table) };
+    }
+
+    /// Sign-extends the translated bits `virt` into a canonical page number.
+    fn canonical(&self, virt: usize) -> VirtPageNumber {
+        let high = usize::from(VirtPageNumber::MAX) & !self.mask();
+        if virt & (self.mask() >> 1) == virt {
+            virt.into()
+        } else {
+            (virt | high).into()
+        }
+    }
+
+    /// Returns the mask of the virtual page number bits the tree translates.
+    fn mask(&self) -> usize {
+        size_to_len(self.mode.virt_size()) - 1
     }
 }
 impl<C, A> Drop for PageTree<C, A>
@@ -196,7 +722,9 @@ where
     A: PhysicalPageAllocator,
 {
     fn drop(&mut self) {
-        todo!()
+        if !self.adopted {
+            self.free_tree(self.root_ppn, self.mode.levels() - 1);
+        }
     }
 }
 impl<C, A> Clone for PageTree<C, A>
@@ -205,10 +733,328 @@ where
     A: PhysicalPageAllocator + Clone,
 {
     fn clone(&self) -> Self {
-        todo!()
+        self.try_clone()
+            .expect("no memory left for the tables of a page tree copy")
     }
 }
 
+/// The number of virtual address bits translated by each page table level.
+const TABLE_BITS: usize = PageTable::COUNT.trailing_zeros() as usize;
+
 fn size_to_len(size: usize) -> usize {
     1 << (size - Page::BITS)
 }
+
+/// Flushes the MMU of the current hart, then of every other online hart, which may share the tables changed.
+fn flush_all_harts() {
+    Arch::flush_mmu(None, None);
+    if smp::online_count() > 1 {
+        Arch::flush_remote_mmu(None, None);
+    }
+}
+
+/// Returns the number of pages an entry of `level` maps.
+fn level_len(level: usize) -> usize {
+    size_to_len(Page::BITS + level * TABLE_BITS)
+}
+
+fn table_index(virt: usize, level: usize) -> usize {
+    (virt >> (level * TABLE_BITS)) & (PageTable::COUNT - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::{Cell, RefCell};
+
+    use super::{
+        PageTree, PhysicalPageAllocError, PhysicalPageAllocator,
+        buddy::BuddyAllocator,
+        fault::{FaultInjectingAllocator, FaultPolicy},
+        testing::HostMemory,
+    };
+    use crate::{
+        PhyPageNumber, VirtPageNumber,
+        arch::page::{PageCache, PagePrivilege, PagingMode},
+    };
+
+    /// Hands out frames in address order and records what is given back.
+    struct Sequential {
+        next: Cell<usize>,
+        freed: RefCell<Vec<usize>>,
+    }
+    impl Sequential {
+        fn new(next: usize) -> Self {
+            Sequential {
+                next: Cell::new(next),
+                freed: RefCell::new(Vec::new()),
+            }
+        }
+    }
+    impl PhysicalPageAllocator for Sequential {
+        fn allocate_contiguous(
+            &self,
+            count: usize,
+        ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+            let page = self.next.get();
+            self.next.set(page + count);
+            Ok(page.into())
+        }
+        unsafe fn deallocate(&self, page: PhyPageNumber) {
+            self.freed.borrow_mut().push(page.into());
+        }
+    }
+
+    #[test]
+    fn aligned_allocation_trims_head_and_tail() {
+        let allocator = Sequential::new(5);
+        assert_eq!(allocator.allocate_aligned(3, 8), Ok(PhyPageNumber::from(8)));
+        assert_eq!(*allocator.freed.borrow(), [5, 6, 7, 11, 12, 13, 14]);
+    }
+
+    #[test]
+    fn aligned_allocation_trims_tail_only() {
+        let allocator = Sequential::new(16);
+        assert_eq!(
+            allocator.allocate_aligned(2, 4),
+            Ok(PhyPageNumber::from(16))
+        );
+        assert_eq!(*allocator.freed.borrow(), [18, 19, 20]);
+    }
+
+    #[test]
+    fn aligned_allocation_trims_head_only() {
+        let allocator = Sequential::new(13);
+        assert_eq!(
+            allocator.allocate_aligned(1, 4),
+            Ok(PhyPageNumber::from(16))
+        );
+        assert_eq!(*allocator.freed.borrow(), [13, 14, 15]);
+    }
+
+    /// Pages an entry of each level maps with 4 KiB pages and 512 entry tables.
+    const LEVEL: [usize; 3] = [1, 512, 512 * 512];
+    const LOW: usize = 0x4_0000;
+    const HIGH: usize = 0xf_ffff_fc00_0000;
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        Map(usize, usize, usize),
+        Unmap(usize, usize),
+    }
+
+    /// Maps small and large pages in both halves, then unmaps parts of them, splitting the large ones.
+    const OPS: [Op; 10] = [
+        Op::Map(0x1000, LOW + 3, 5),
+        Op::Map(0x200, LOW + LEVEL[1], LEVEL[1] + 2),
+        Op::Map(LEVEL[2], 2 * LEVEL[2], LEVEL[2]),
+        Op::Map(0x2000, HIGH + 7, 2),
+        Op::Unmap(LOW + 4, 2),
+        Op::Unmap(LOW + LEVEL[1] + 100, 10),
+        Op::Unmap(2 * LEVEL[2] + 1000, 1),
+        Op::Unmap(HIGH, LEVEL[1]),
+        Op::Map(0x3000, HIGH + 7, 1),
+        Op::Unmap(LOW, 2 * LEVEL[2]),
+    ];
+
+    /// The pages around the edges of every range in [`OPS`].
+    fn probes() -> Vec<VirtPageNumber> {
+        let mut probes = Vec::new();
+        for op in OPS {
+            let (start, len) = match op {
+                Op::Map(_, virt, len) | Op::Unmap(virt, len) => (virt, len),
+            };
+            for virt in [
+                start - 1,
+                start,
+                start + 1,
+                start + len / 2,
+                start + len - 1,
+                start + len,
+            ] {
+                probes.push(virt.into());
+            }
+        }
+        probes
+    }
+
+    fn snapshot<C: super::PhysicalPageAccessor, A: PhysicalPageAllocator>(
+        tree: &PageTree<C, A>,
+    ) -> Vec<Option<PhyPageNumber>> {
+        probes()
+            .into_iter()
+            .map(|virt| tree.translate(virt))
+            .collect()
+    }
+
+    fn apply<C: super::PhysicalPageAccessor, A: PhysicalPageAllocator>(
+        tree: &PageTree<C, A>,
+        op: Op,
+    ) -> Result<(), PhysicalPageAllocError> {
+        match op {
+            Op::Map(phy, virt, len) => tree.map(
+                phy.into(),
+                virt.into(),
+                len,
+                PagePrivilege::ReadWrite,
+                PageCache::Cacheable,
+                false,
+            ),
+            Op::Unmap(virt, len) => tree.unmap(virt.into(), len),
+        }
+    }
+
+    /// Runs `f` with host memory for 64 tables and an allocator over them failing by `policy`.
+    fn with_tables<R>(
+        policy: FaultPolicy,
+        f: impl FnOnce(&FaultInjectingAllocator<&BuddyAllocator<&HostMemory>>, &HostMemory) -> R,
+    ) -> R {
+        let memory = HostMemory::new(0, 65);
+        let buddy = memory.buddy(64);
+        let faulty = FaultInjectingAllocator::new(&buddy, policy);
+        let result = f(&faulty, &memory);
+        assert_eq!(faulty.outstanding(), 0, "page tables leaked");
+        assert_eq!(buddy.free_count(), 64);
+        result
+    }
+
+    #[test]
+    fn map_translate_and_unmap() {
+        with_tables(FaultPolicy::Never, |tables, memory| {
+            let tree = PageTree::new(memory, tables, PagingMode::Layer3).unwrap();
+            for op in &OPS[..4] {
+                apply(&tree, *op).unwrap();
+            }
+            // The root, a middle table for each half, and a last level table for each run of small pages.
+            assert_eq!(tables.outstanding(), 6);
+            let translate = |virt: usize| tree.translate(virt.into()).map(usize::from);
+            assert_eq!(translate(LOW + 4), Some(0x1001));
+            assert_eq!(translate(LOW + LEVEL[1] + 3), Some(0x203));
+            assert_eq!(translate(LOW + 2 * LEVEL[1] + 1), Some(0x201 + LEVEL[1]));
+            assert_eq!(translate(3 * LEVEL[2] - 1), Some(2 * LEVEL[2] - 1));
+            assert_eq!(translate(HIGH + 8), Some(0x2001));
+            assert_eq!(translate(LOW + 8), None);
+
+            apply(&tree, OPS[4]).unwrap();
+            assert_eq!(translate(LOW + 3), Some(0x1000));
+            assert_eq!(translate(LOW + 4), None);
+            assert_eq!(translate(LOW + 6), Some(0x1003));
+            apply(&tree, OPS[5]).unwrap();
+            assert_eq!(translate(LOW + LEVEL[1] + 99), Some(0x200 + 99));
+            assert_eq!(translate(LOW + LEVEL[1] + 100), None);
+            assert_eq!(translate(LOW + LEVEL[1] + 110), Some(0x200 + 110));
+            apply(&tree, OPS[6]).unwrap();
+            assert_eq!(translate(2 * LEVEL[2] + 999), Some(LEVEL[2] + 999));
+            assert_eq!(translate(2 * LEVEL[2] + 1000), None);
+            assert_eq!(translate(3 * LEVEL[2] - 1), Some(2 * LEVEL[2] - 1));
+
+            // Unmapping everything leaves the root alone.
+            apply(&tree, Op::Unmap(HIGH, LEVEL[1])).unwrap();
+            apply(&tree, Op::Unmap(LOW, 3 * LEVEL[2] - LOW)).unwrap();
+            assert!(
+                probes()
+                    .into_iter()
+                    .all(|virt| tree.translate(virt).is_none())
+            );
+            assert_eq!(tables.outstanding(), 1);
+        });
+    }
+
+    #[test]
+    fn iterates_mappings_in_order() {
+        with_tables(FaultPolicy::Never, |tables, memory| {
+            let tree = PageTree::new(memory, tables, PagingMode::Layer3).unwrap();
+            assert_eq!(tree.iter().count(), 0);
+            for op in &OPS[..4] {
+                apply(&tree, *op).unwrap();
+            }
+            let mappings: Vec<_> = tree
+                .iter()
+                .map(|(virt, len, leaf)| (usize::from(virt), len, usize::from(leaf.to)))
+                .collect();
+            let small = |virt: usize, phy: usize| (virt, 1, phy);
+            assert_eq!(
+                mappings,
+                [
+                    small(LOW + 3, 0x1000),
+                    small(LOW + 4, 0x1001),
+                    small(LOW + 5, 0x1002),
+                    small(LOW + 6, 0x1003),
+                    small(LOW + 7, 0x1004),
+                    (LOW + LEVEL[1], LEVEL[1], 0x200),
+                    small(LOW + 2 * LEVEL[1], 0x200 + LEVEL[1]),
+                    small(LOW + 2 * LEVEL[1] + 1, 0x201 + LEVEL[1]),
+                    (2 * LEVEL[2], LEVEL[2], LEVEL[2]),
+                    small(HIGH + 7, 0x2000),
+                    small(HIGH + 8, 0x2001),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "already mapped")]
+    fn map_over_a_mapping() {
+        with_tables(FaultPolicy::Never, |tables, memory| {
+            let tree = PageTree::new(memory, tables, PagingMode::Layer3).unwrap();
+            apply(&tree, OPS[1]).unwrap();
+            apply(&tree, Op::Map(0x9000, LOW + LEVEL[1] + 5, 1)).unwrap();
+        });
+    }
+
+    #[test]
+    fn clone_shares_frames_but_not_tables() {
+        with_tables(FaultPolicy::Never, |tables, memory| {
+            let tree = PageTree::new(memory, tables, PagingMode::Layer3).unwrap();
+            for op in OPS {
+                apply(&tree, op).unwrap();
+            }
+            let copy = tree.clone();
+            assert_eq!(snapshot(&copy), snapshot(&tree));
+            apply(&copy, Op::Unmap(HIGH + 7, 1)).unwrap();
+            assert_eq!(tree.translate((HIGH + 7).into()), Some(0x3000.into()));
+            assert_eq!(copy.translate((HIGH + 7).into()), None);
+        });
+    }
+
+    /// Runs every operation with the `n`th allocation failing, and returns the allocations the run made.
+    fn run_failing(n: Option<usize>) -> usize {
+        let policy = n.map_or(FaultPolicy::Never, FaultPolicy::FailNth);
+        with_tables(policy, |tables, memory| {
+            let Ok(tree) = PageTree::new(memory, tables, PagingMode::Layer3) else {
+                assert_eq!(tables.outstanding(), 0);
+                return tables.calls();
+            };
+            for op in OPS {
+                let (before, outstanding) = (snapshot(&tree), tables.outstanding());
+                if apply(&tree, op).is_err() {
+                    assert_eq!(
+                        snapshot(&tree),
+                        before,
+                        "tree changed by a failed operation"
+                    );
+                    assert_eq!(
+                        tables.outstanding(),
+                        outstanding,
+                        "tables leaked by a failed operation"
+                    );
+                }
+            }
+            let outstanding = tables.outstanding();
+            match tree.try_clone() {
+                Ok(copy) => assert_eq!(snapshot(&copy), snapshot(&tree)),
+                Err(_) => assert_eq!(tables.outstanding(), outstanding),
+            }
+            drop(tree);
+            tables.calls()
+        })
+    }
+
+    #[test]
+    fn out_of_memory_at_every_allocation() {
+        let calls = run_failing(None);
+        for n in 0..calls {
+            run_failing(Some(n));
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use core::mem::MaybeUninit;
+
+use super::{
+    PhysicalPageAccessor, PhysicalPageAllocError, PhysicalPageAllocator, access_phy,
+    region::FreeRegions,
+    stats::{MEMORY_STATS, MemoryCounter},
+};
+use crate::{Page, PhyPageNumber, sync::SpinLock};
+
+/// The largest block order, 1 GiB with 4 KiB pages.
+pub const MAX_ORDER: usize = 18;
+const NOT_FREE: u8 = u8::MAX;
+const NONE: usize = usize::MAX;
+
+type MetaPage = [u8; Page::SIZE];
+
+#[derive(Debug, Clone, Copy)]
+/// Free list links, kept in the first frame of each free block.
+struct Link {
+    prev: usize,
+    next: usize,
+}
+
+#[derive(Debug)]
+struct BuddyState {
+    heads: [usize; MAX_ORDER + 1],
+    free: usize,
+}
+
+#[derive(Debug)]
+/// A binary buddy physical page allocator.
+/// Blocks of `2^order` pages are naturally aligned, and freed blocks coalesce with their buddies.
+/// One metadata byte per frame records the order of each free block head.
+pub struct BuddyAllocator<C: PhysicalPageAccessor> {
+    accessor: C,
+    base: PhyPageNumber,
+    count: usize,
+    meta: PhyPageNumber,
+    state: SpinLock<BuddyState>,
+}
+impl<C: PhysicalPageAccessor> BuddyAllocator<C> {
+    /// Creates the allocator covering `regions`, taking its metadata from `metadata`.
+    /// `metadata` must not hand out frames in `regions`.
+    /// # Errors
+    /// Returns an error if `regions` is empty or the metadata cannot be allocated.
+    pub fn new(
+        accessor: C,
+        regions: &FreeRegions,
+        metadata: &impl PhysicalPageAllocator,
+    ) -> Result<Self, PhysicalPageAllocError> {
+        let (base, count) = regions.span().ok_or(PhysicalPageAllocError)?;
+        let meta_len = count.div_ceil(Page::SIZE);
+        let meta = metadata.allocate_contiguous(meta_len)?;
+        let allocator = BuddyAllocator {
+            accessor,
+            base,
+            count,
+            meta,
+            state: SpinLock::new(BuddyState {
+                heads: [NONE; _],
+                free: 0,
+            }),
+        };
+        for i in 0..meta_len {
+            let mut guard = allocator.accessor.access_phy_page(meta + i);
+            unsafe {
+                access_phy::<MaybeUninit<MetaPage>, ()>(&mut guard, |orders| {
+                    orders.write([NOT_FREE; _]);
+                });
+            }
+        }
+        let mut state = allocator.state.lock();
+        for (start, len) in regions.iter() {
+            allocator.free_range(&mut state, start.into(), len);
+        }
+        drop(state);
+        Ok(allocator)
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_count(&self) -> usize {
+        self.state.lock().free
+    }
+
+    /// Returns the number of frames covered by the allocator, free or not.
+    pub fn total_count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the number of free blocks of each order.
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let state = self.state.lock();
+        core::array::from_fn(|order| {
+            let mut count = 0;
+            let mut block = state.heads[order];
+            while block != NONE {
+                count += 1;
+                block = self.link(block).next;
+            }
+            count
+        })
+    }
+
+    fn contains(&self, page: usize) -> bool {
+        page >= usize::from(self.base) && page - usize::from(self.base) < self.count
+    }
+
+    fn order(&self, page: usize) -> u8 {
+        let index = page - usize::from(self.base);
+        let mut guard = self
+            .accessor
+            .access_phy_page(self.meta + index / Page::SIZE);
+        unsafe {
+            access_phy(&mut guard, |orders: &mut MetaPage| {
+                orders[index % Page::SIZE]
+            })
+        }
+    }
+
+    fn set_order(&self, page: usize, order: u8) {
+        let index = page - usize::from(self.base);
+        let mut guard = self
+            .accessor
+            .access_phy_page(self.meta + index / Page::SIZE);
+        unsafe {
+            access_phy(&mut guard, |orders: &mut MetaPage| {
+                orders[index % Page::SIZE] = order;
+            });
+        }
+    }
+
+    fn link(&self, page: usize) -> Link {
+        let mut guard = self.accessor.access_phy_page(page.into());
+        unsafe { access_phy(&mut guard, |link: &mut Link| *link) }
+    }
+
+    fn set_link(&self, page: usize, link: Link) {
+        let mut guard = self.accessor.access_phy_page(page.into());
+        unsafe {
+            access_phy::<MaybeUninit<Link>, ()>(&mut guard, |slot| {
+                slot.write(link);
+            });
+        }
+    }
+
+    fn push(&self, state: &mut BuddyState, block: usize, order: usize) {
+        let next = state.heads[order];
+        if next != NONE {
+            self.set_link(
+                next,
+                Link {
+                    prev: block,
+                    ..self.link(next)
+                },
+            );
+        }
+        self.set_link(block, Link { prev: NONE, next });
+        self.set_order(block, u8::try_from(order).unwrap());
+        state.heads[order] = block;
+    }
+
+    fn unlink(&self, state: &mut BuddyState, block: usize, order: usize) {
+        let Link { prev, next } = self.link(block);
+        if prev == NONE {
+            state.heads[order] = next;
+        } else {
+            self.set_link(
+                prev,
+                Link {
+                    next,
+                    ..self.link(prev)
+                },
+            );
+        }
+        if next != NONE {
+            self.set_link(
+                next,
+                Link {
+                    prev,
+                    ..self.link(next)
+                },
+            );
+        }
+        self.set_order(block, NOT_FREE);
+    }
+
+    /// Takes a free block of exactly `order`, splitting a larger one if needed.
+    fn allocate_block(&self, state: &mut BuddyState, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&o| state.heads[o] != NONE)?;
+        let block = state.heads[found];
+        self.unlink(state, block, found);
+        for o in (order..found).rev() {
+            self.push(state, block + (1 << o), o);
+        }
+        Some(block)
+    }
+
+    /// Returns true if a free block starts anywhere in `[page, page + count)`.
+    fn any_free_head(&self, page: usize, count: usize) -> bool {
+        let mut index = page - usize::from(self.base);
+        let end = index + count;
+        while index < end {
+            let chunk_end = end.min((index / Page::SIZE + 1) * Page::SIZE);
+            let mut guard = self
+                .accessor
+                .access_phy_page(self.meta + index / Page::SIZE);
+            let found = unsafe {
+                access_phy(&mut guard, |orders: &mut MetaPage| {
+                    orders[index % Page::SIZE..=(chunk_end - 1) % Page::SIZE]
+                        .iter()
+                        .any(|&order| order != NOT_FREE)
+                })
+            };
+            if found {
+                return true;
+            }
+            index = chunk_end;
+        }
+        false
+    }
+
+    fn free_block(&self, state: &mut BuddyState, mut block: usize, mut order: usize) {
+        // A page of the block is already free if a larger free block covers it, or a free block starts inside it.
+        // Finding blocks inside it takes a scan of its metadata, so release builds only check its own head.
+        let covered = (order + 1..=MAX_ORDER).any(|o| {
+            let head = block & !((1 << o) - 1);
+            self.contains(head) && usize::from(self.order(head)) == o
+        });
+        let inside = if cfg!(debug_assertions) {
+            self.any_free_head(block, 1 << order)
+        } else {
+            self.order(block) != NOT_FREE
+        };
+        assert!(
+            !covered && !inside,
+            "Double free of physical page {block:#x}"
+        );
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if !self.contains(buddy) || usize::from(self.order(buddy)) != order {
+                break;
+            }
+            self.unlink(state, buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(state, block, order);
+    }
+
+    /// Frees `[page, page + count)` as the largest aligned blocks that fit.
+    fn free_range(&self, state: &mut BuddyState, mut page: usize, mut count: usize) {
+        state.free += count;
+        MEMORY_STATS.add(MemoryCounter::Free, count);
+        while count > 0 {
+            let order = usize::try_from(page.trailing_zeros())
+                .unwrap()
+                .min(count.ilog2().try_into().unwrap())
+                .min(MAX_ORDER);
+            self.free_block(state, page, order);
+            page += 1 << order;
+            count -= 1 << order;
+        }
+    }
+}
+impl<C: PhysicalPageAccessor> PhysicalPageAllocator for BuddyAllocator<C> {
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.allocate_aligned(count, 1)
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        assert!(count > 0, "Cannot allocate zero pages");
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        let order = usize::try_from(
+            count
+                .next_power_of_two()
+                .trailing_zeros()
+                .max(align.trailing_zeros()),
+        )
+        .unwrap();
+        if order > MAX_ORDER {
+            return Err(PhysicalPageAllocError);
+        }
+        let mut state = self.state.lock();
+        let block = self
+            .allocate_block(&mut state, order)
+            .ok_or(PhysicalPageAllocError)?;
+        state.free -= 1 << order;
+        MEMORY_STATS.sub(MemoryCounter::Free, 1 << order);
+        let excess = (1 << order) - count;
+        if excess > 0 {
+            self.free_range(&mut state, block + count, excess);
+        }
+        Ok(block.into())
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        unsafe { self.deallocate_contiguous(page, 1) };
+    }
+
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        if count == 0 {
+            return;
+        }
+        assert!(
+            self.contains(page.into()) && self.contains(usize::from(page) + count - 1),
+            "Physical pages {page:?}+{count} are not managed by this allocator"
+        );
+        let mut state = self.state.lock();
+        self.free_range(&mut state, page.into(), count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_ORDER;
+    use crate::{
+        PhyPageNumber,
+        page::{PhysicalPageAllocator, testing::HostMemory},
+    };
+
+    fn blocks(orders: &[usize]) -> [usize; MAX_ORDER + 1] {
+        let mut blocks = [0; _];
+        for &order in orders {
+            blocks[order] += 1;
+        }
+        blocks
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let memory = HostMemory::new(64, 65);
+        let buddy = memory.buddy(64);
+        assert_eq!(buddy.free_blocks(), blocks(&[6]));
+        let page = buddy.allocate().unwrap();
+        assert_eq!(page, PhyPageNumber::from(64));
+        assert_eq!(buddy.free_blocks(), blocks(&[0, 1, 2, 3, 4, 5]));
+        let three = buddy.allocate_contiguous(3).unwrap();
+        assert_eq!(three, PhyPageNumber::from(68));
+        assert_eq!(buddy.free_blocks(), blocks(&[0, 0, 1, 3, 4, 5]));
+        assert_eq!(buddy.free_count(), 60);
+        unsafe {
+            buddy.deallocate(page);
+            buddy.deallocate_contiguous(three, 3);
+        }
+        assert_eq!(buddy.free_blocks(), blocks(&[6]));
+        assert_eq!(buddy.free_count(), 64);
+    }
+
+    #[test]
+    fn aligned_allocation_returns_the_excess() {
+        let memory = HostMemory::new(64, 65);
+        let buddy = memory.buddy(64);
+        buddy.allocate().unwrap();
+        let page = buddy.allocate_aligned(5, 16).unwrap();
+        assert_eq!(page, PhyPageNumber::from(80));
+        assert_eq!(buddy.free_count(), 58);
+        unsafe { buddy.deallocate_contiguous(page, 5) };
+        assert_eq!(buddy.free_blocks(), blocks(&[0, 1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn free_nothing() {
+        let memory = HostMemory::new(64, 65);
+        let buddy = memory.buddy(64);
+        let page = buddy.allocate().unwrap();
+        unsafe { buddy.deallocate_contiguous(page, 0) };
+        assert_eq!(buddy.free_count(), 63);
+    }
+
+    #[test]
+    #[should_panic(expected = "Double free")]
+    fn double_free_inside_free_block() {
+        let memory = HostMemory::new(64, 65);
+        let buddy = memory.buddy(64);
+        let page = buddy.allocate_contiguous(4).unwrap();
+        unsafe {
+            buddy.deallocate_contiguous(page, 4);
+            buddy.deallocate(page + 2);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Double free")]
+    fn double_free_covering_free_block() {
+        let memory = HostMemory::new(64, 65);
+        let buddy = memory.buddy(64);
+        let page = buddy.allocate_contiguous(4).unwrap();
+        unsafe {
+            buddy.deallocate(page + 2);
+            buddy.deallocate_contiguous(page, 4);
+        }
+    }
+}
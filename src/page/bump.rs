@@ -0,0 +1,194 @@
+use arrayvec::ArrayVec;
+
+use super::{PhysicalPageAllocError, PhysicalPageAllocator, region::FreeRegions};
+use crate::{PhyPageNumber, sync::SpinLock};
+
+const MAX_GAPS: usize = 32;
+
+#[derive(Debug)]
+struct BumpState {
+    next: PhyPageNumber,
+    /// Pages skipped to satisfy alignment, still free.
+    gaps: ArrayVec<(PhyPageNumber, usize), MAX_GAPS>,
+}
+
+#[derive(Debug)]
+/// A bump allocator for the frames needed before the real allocator exists,
+/// such as the real allocator's own metadata and the first page tables.
+/// Pages are never freed individually; [`BumpAllocator::finish`] hands the unused rest over instead.
+pub struct BumpAllocator {
+    start: PhyPageNumber,
+    end: PhyPageNumber,
+    state: SpinLock<BumpState>,
+}
+impl BumpAllocator {
+    /// Takes the largest region out of `regions` to allocate from.
+    /// # Errors
+    /// Returns an error if `regions` is empty.
+    pub fn new(regions: &mut FreeRegions) -> Result<Self, PhysicalPageAllocError> {
+        let (start, len) = regions.take_largest().ok_or(PhysicalPageAllocError)?;
+        Ok(BumpAllocator {
+            start,
+            end: start + len,
+            state: SpinLock::new(BumpState {
+                next: start,
+                gaps: ArrayVec::new(),
+            }),
+        })
+    }
+
+    /// Returns the ranges handed out so far.
+    pub fn used(&self) -> impl Iterator<Item = (PhyPageNumber, usize)> {
+        let state = self.state.lock();
+        let mut gaps = state.gaps.clone();
+        gaps.sort_unstable();
+        let end = state.next;
+        let mut cursor = self.start;
+        gaps.into_iter()
+            .chain([(end, 0)])
+            .filter_map(move |(gap, len)| {
+                let used = (cursor, usize::from(gap) - usize::from(cursor));
+                cursor = gap + len;
+                Some(used).filter(|(_, len)| *len > 0)
+            })
+    }
+
+    /// Ends early allocation, freeing every page not handed out into `allocator`.
+    /// Pages handed out stay in use and belong to `allocator` from now on.
+    /// Returns the number of pages handed out.
+    pub fn finish(self, allocator: &impl PhysicalPageAllocator) -> usize {
+        let state = self.state.into_inner();
+        let mut unused = usize::from(self.end) - usize::from(state.next);
+        if unused > 0 {
+            unsafe { allocator.deallocate_contiguous(state.next, unused) };
+        }
+        for (gap, len) in state.gaps {
+            unused += len;
+            unsafe { allocator.deallocate_contiguous(gap, len) };
+        }
+        usize::from(self.end) - usize::from(self.start) - unused
+    }
+}
+impl PhysicalPageAllocator for BumpAllocator {
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.allocate_aligned(count, 1)
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        let mut state = self.state.lock();
+        let page = PhyPageNumber::from(usize::from(state.next).next_multiple_of(align));
+        if page > self.end || usize::from(self.end) - usize::from(page) < count {
+            return Err(PhysicalPageAllocError);
+        }
+        if page > state.next {
+            let gap = (state.next, usize::from(page) - usize::from(state.next));
+            state
+                .gaps
+                .try_push(gap)
+                .map_err(|_| PhysicalPageAllocError)?;
+        }
+        state.next = page + count;
+        Ok(page)
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        panic!("Early bump allocator cannot free physical page {page:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::BumpAllocator;
+    use crate::{
+        PhyPageNumber,
+        page::{PhysicalPageAllocError, PhysicalPageAllocator, testing::regions},
+    };
+
+    /// Records the ranges given back to it, as `(first frame, count)`.
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<(usize, usize)>>);
+    impl PhysicalPageAllocator for Recorder {
+        fn allocate_contiguous(
+            &self,
+            _count: usize,
+        ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+            Err(PhysicalPageAllocError)
+        }
+        unsafe fn deallocate(&self, page: PhyPageNumber) {
+            unsafe { self.deallocate_contiguous(page, 1) };
+        }
+        unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+            self.0.borrow_mut().push((page.into(), count));
+        }
+    }
+
+    fn used(bump: &BumpAllocator) -> Vec<(usize, usize)> {
+        bump.used()
+            .map(|(start, len)| (usize::from(start), len))
+            .collect()
+    }
+
+    #[test]
+    fn takes_the_largest_region() {
+        let mut free = regions(100, 4);
+        free.insert(10.into(), 20);
+        let bump = BumpAllocator::new(&mut free).unwrap();
+        assert_eq!(bump.allocate().unwrap(), 10.into());
+        assert!(BumpAllocator::new(&mut free).is_ok());
+        assert!(BumpAllocator::new(&mut free).is_err());
+    }
+
+    #[test]
+    fn alignment_leaves_gaps() {
+        let bump = BumpAllocator::new(&mut regions(3, 29)).unwrap();
+        assert!(used(&bump).is_empty());
+        assert_eq!(bump.allocate_contiguous(2), Ok(3.into()));
+        assert_eq!(bump.allocate_aligned(4, 8), Ok(8.into()));
+        assert_eq!(bump.allocate_aligned(1, 4), Ok(12.into()));
+        assert_eq!(bump.allocate_aligned(2, 16), Ok(16.into()));
+        assert_eq!(used(&bump), [(3, 2), (8, 5), (16, 2)]);
+        // Past the end, with or without the alignment.
+        assert_eq!(bump.allocate_aligned(1, 32), Err(PhysicalPageAllocError));
+        assert_eq!(bump.allocate_contiguous(15), Err(PhysicalPageAllocError));
+        assert_eq!(bump.allocate_contiguous(14), Ok(18.into()));
+        assert_eq!(used(&bump), [(3, 2), (8, 5), (16, 16)]);
+    }
+
+    #[test]
+    fn finish_hands_back_the_rest_and_the_gaps() {
+        let bump = BumpAllocator::new(&mut regions(3, 29)).unwrap();
+        bump.allocate().unwrap();
+        bump.allocate_aligned(2, 8).unwrap();
+        bump.allocate_aligned(1, 16).unwrap();
+        let recorder = Recorder::default();
+        assert_eq!(bump.finish(&recorder), 4);
+        let mut freed = recorder.0.into_inner();
+        freed.sort_unstable();
+        assert_eq!(freed, [(4, 4), (10, 6), (17, 15)]);
+    }
+
+    #[test]
+    fn finish_with_nothing_left() {
+        let bump = BumpAllocator::new(&mut regions(0, 8)).unwrap();
+        bump.allocate_contiguous(8).unwrap();
+        let recorder = Recorder::default();
+        assert_eq!(bump.finish(&recorder), 8);
+        assert!(recorder.0.into_inner().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot free")]
+    fn deallocate_panics() {
+        let bump = BumpAllocator::new(&mut regions(0, 8)).unwrap();
+        let page = bump.allocate().unwrap();
+        unsafe { bump.deallocate(page) };
+    }
+}
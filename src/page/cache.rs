@@ -0,0 +1,242 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
+
+use super::{PhysicalPageAllocError, PhysicalPageAllocator};
+use crate::{Arch, ArchImpl, PhyPageNumber, sync::SpinLock};
+
+#[derive(Debug, Default)]
+struct HartCache<const SIZE: usize> {
+    pages: SpinLock<ArrayVec<PhyPageNumber, SIZE>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Counters of a single hart's cache.
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub cached: usize,
+}
+
+#[derive(Debug)]
+/// Per-hart magazines of single free frames in front of a shared allocator.
+/// Magazines refill and drain half of their capacity at once, so the backing allocator is only hit in batches.
+/// Harts whose index is not below `HARTS` bypass the cache.
+pub struct HartCachedAllocator<
+    A: PhysicalPageAllocator,
+    const HARTS: usize = 8,
+    const SIZE: usize = 64,
+> {
+    backing: A,
+    caches: [HartCache<SIZE>; HARTS],
+}
+impl<A: PhysicalPageAllocator, const HARTS: usize, const SIZE: usize>
+    HartCachedAllocator<A, HARTS, SIZE>
+{
+    const BATCH: usize = SIZE.div_ceil(2);
+
+    pub fn new(backing: A) -> Self {
+        HartCachedAllocator {
+            backing,
+            caches: core::array::from_fn(|_| HartCache::default()),
+        }
+    }
+
+    pub fn backing(&self) -> &A {
+        &self.backing
+    }
+
+    /// Returns the counters of the given hart's cache.
+    /// # Panics
+    /// Panics if `hart` is not below `HARTS`.
+    pub fn stats(&self, hart: usize) -> CacheStats {
+        let cache = &self.caches[hart];
+        CacheStats {
+            hits: cache.hits.load(Ordering::Relaxed),
+            misses: cache.misses.load(Ordering::Relaxed),
+            cached: cache.pages.lock().len(),
+        }
+    }
+
+    /// Returns every cached frame to the backing allocator.
+    pub fn flush_all(&self) {
+        for cache in &self.caches {
+            let mut pages = cache.pages.lock();
+            let len = pages.len();
+            self.drain(&mut pages, len);
+        }
+    }
+
+    fn current(&self) -> Option<&HartCache<SIZE>> {
+        self.caches.get(Arch::hart_id())
+    }
+
+    /// Frees the last `count` cached frames, merging neighbours into contiguous runs.
+    fn drain(&self, pages: &mut ArrayVec<PhyPageNumber, SIZE>, count: usize) {
+        let start = pages.len() - count;
+        pages[start..].sort_unstable();
+        let mut iter = pages.drain(start..).peekable();
+        while let Some(first) = iter.next() {
+            let mut len = 1;
+            while iter.next_if_eq(&(first + len)).is_some() {
+                len += 1;
+            }
+            unsafe { self.backing.deallocate_contiguous(first, len) };
+        }
+    }
+
+    fn refill(
+        &self,
+        pages: &mut ArrayVec<PhyPageNumber, SIZE>,
+    ) -> Result<(), PhysicalPageAllocError> {
+        // Single frames, so the cache never breaks up the contiguous blocks others ask for.
+        for _ in 0..Self::BATCH {
+            match self.backing.allocate() {
+                Ok(page) => pages.push(page),
+                Err(error) if pages.is_empty() => return Err(error),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` against the backing allocator, flushing every cache and retrying once if it fails.
+    fn with_retry<R>(
+        &self,
+        f: impl Fn(&A) -> Result<R, PhysicalPageAllocError>,
+    ) -> Result<R, PhysicalPageAllocError> {
+        f(&self.backing).or_else(|_| {
+            self.flush_all();
+            f(&self.backing)
+        })
+    }
+}
+impl<A: PhysicalPageAllocator, const HARTS: usize, const SIZE: usize> PhysicalPageAllocator
+    for HartCachedAllocator<A, HARTS, SIZE>
+{
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let Some(cache) = self.current() else {
+            return self.with_retry(A::allocate);
+        };
+        let mut pages = cache.pages.lock();
+        if let Some(page) = pages.pop() {
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(page);
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        if self.refill(&mut pages).is_err() {
+            drop(pages);
+            self.flush_all();
+            pages = cache.pages.lock();
+            self.refill(&mut pages)?;
+        }
+        Ok(pages.pop().unwrap())
+    }
+
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        if count == 1 {
+            return self.allocate();
+        }
+        self.with_retry(|backing| backing.allocate_contiguous(count))
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        if count == 1 && align == 1 {
+            return self.allocate();
+        }
+        self.with_retry(|backing| backing.allocate_aligned(count, align))
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        let Some(cache) = self.current() else {
+            return unsafe { self.backing.deallocate(page) };
+        };
+        let mut pages = cache.pages.lock();
+        if pages.is_full() {
+            self.drain(&mut pages, Self::BATCH);
+        }
+        pages.push(page);
+    }
+
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        if count == 1 {
+            unsafe { self.deallocate(page) };
+        } else {
+            unsafe { self.backing.deallocate_contiguous(page, count) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{CacheStats, HartCachedAllocator};
+    use crate::page::{PhysicalPageAllocator, testing::HostMemory};
+
+    #[test]
+    fn hits_misses_and_flush() {
+        let memory = HostMemory::new(0, 65);
+        let buddy = memory.buddy(64);
+        let cache: HartCachedAllocator<_, 1, 8> = HartCachedAllocator::new(&buddy);
+
+        let first = cache.allocate().unwrap();
+        assert_eq!(
+            cache.stats(0),
+            CacheStats {
+                hits: 0,
+                misses: 1,
+                cached: 3,
+            }
+        );
+        assert_eq!(buddy.free_count(), 60);
+        let pages: Vec<_> = (0..3).map(|_| cache.allocate().unwrap()).collect();
+        assert_eq!(cache.stats(0).hits, 3);
+        cache.allocate().unwrap();
+        assert_eq!(cache.stats(0).misses, 2);
+
+        unsafe {
+            cache.deallocate(first);
+            for &page in &pages {
+                cache.deallocate(page);
+            }
+        }
+        assert_eq!(cache.stats(0).cached, 7);
+        cache.flush_all();
+        assert_eq!(cache.stats(0).cached, 0);
+        assert_eq!(buddy.free_count(), 63);
+    }
+
+    #[test]
+    fn full_cache_drains_a_batch() {
+        let memory = HostMemory::new(0, 65);
+        let buddy = memory.buddy(64);
+        let cache: HartCachedAllocator<_, 1, 8> = HartCachedAllocator::new(&buddy);
+
+        let pages = buddy.allocate_contiguous(9).unwrap();
+        for i in 0..9 {
+            unsafe { cache.deallocate(pages + i) };
+        }
+        assert_eq!(cache.stats(0).cached, 5);
+        assert_eq!(buddy.free_count(), 59);
+    }
+
+    #[test]
+    fn exhaustion_flushes_the_cache() {
+        let memory = HostMemory::new(0, 9);
+        let buddy = memory.buddy(8);
+        let cache: HartCachedAllocator<_, 1, 8> = HartCachedAllocator::new(&buddy);
+
+        let pages: Vec<_> = (0..8).map(|_| cache.allocate().unwrap()).collect();
+        assert!(cache.allocate().is_err());
+        unsafe { cache.deallocate(pages[0]) };
+        assert!(cache.allocate_contiguous(2).is_err());
+        assert_eq!(cache.allocate_contiguous(1), Ok(pages[0]));
+    }
+}
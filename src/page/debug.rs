@@ -0,0 +1,373 @@
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use arrayvec::ArrayVec;
+
+use super::{
+    PhysicalPageAccessGuard, PhysicalPageAccessor, PhysicalPageAllocError, PhysicalPageAllocator,
+    descriptor::PageKind,
+};
+use crate::{Page, PhyPageNumber, sync::SpinLock};
+
+type CallSite = &'static Location<'static>;
+
+#[derive(Debug, Default)]
+/// Debug-only bookkeeping of allocated frames and live access guards.
+/// Every check is skipped when `debug_assertions` is off, so the wrappers become pass-throughs.
+pub struct DebugPageTracker<const N: usize = 1024> {
+    guards: SpinLock<ArrayVec<(PhyPageNumber, CallSite), N>>,
+    /// Allocated ranges sorted by their first frame, with adjacent ranges merged.
+    allocated: SpinLock<ArrayVec<(PhyPageNumber, usize, CallSite), N>>,
+    /// Set once a range did not fit in `allocated`, after which unallocated frames are not reported.
+    overflowed: AtomicBool,
+}
+impl<const N: usize> DebugPageTracker<N> {
+    #[must_use]
+    pub const fn new() -> Self {
+        DebugPageTracker {
+            guards: SpinLock::new(ArrayVec::new_const()),
+            allocated: SpinLock::new(ArrayVec::new_const()),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Wraps an accessor so every guard it hands out is checked against this tracker.
+    pub const fn accessor<C: PhysicalPageAccessor>(&self, inner: C) -> DebugAccessor<'_, C, N> {
+        DebugAccessor {
+            inner,
+            tracker: self,
+        }
+    }
+
+    /// Wraps an allocator so every frame it hands out is recorded in this tracker.
+    pub const fn allocator<A: PhysicalPageAllocator>(&self, inner: A) -> DebugAllocator<'_, A, N> {
+        DebugAllocator {
+            inner,
+            tracker: self,
+        }
+    }
+
+    /// Marks frames not coming from a tracked allocator (kernel image, boot page tables, ...) as accessible.
+    /// Ranges adjacent to one already marked are merged into it, keeping its call site.
+    /// # Panics
+    /// Panics if any of the frames is already marked.
+    #[track_caller]
+    pub fn mark_allocated(&self, page: PhyPageNumber, count: usize) {
+        if !cfg!(debug_assertions) || count == 0 {
+            return;
+        }
+        let at = Location::caller();
+        let mut allocated = self.allocated.lock();
+        let index = allocated.partition_point(|(start, _, _)| *start < page);
+        let before = index.checked_sub(1).map(|before| allocated[before]);
+        let after = allocated.get(index).copied();
+        if let Some((start, len, by)) = [before, after]
+            .into_iter()
+            .flatten()
+            .find(|(start, len, _)| overlaps(*start, *len, page, count))
+        {
+            panic!(
+                "Physical pages {page:?}+{count} allocated at {at} overlap {start:?}+{len} allocated at {by}"
+            );
+        }
+        let joins_before = before.is_some_and(|(start, len, _)| start + len == page);
+        let joins_after = after.is_some_and(|(start, _, _)| start == page + count);
+        match (joins_before, joins_after) {
+            (true, true) => {
+                allocated[index - 1].1 += count + allocated[index].1;
+                allocated.remove(index);
+            }
+            (true, false) => allocated[index - 1].1 += count,
+            (false, true) => {
+                let (_, len, by) = allocated[index];
+                allocated[index] = (page, count + len, by);
+            }
+            (false, false) => {
+                if allocated.try_insert(index, (page, count, at)).is_err() {
+                    self.overflow();
+                }
+            }
+        }
+    }
+
+    /// Marks frames as freed.
+    /// # Panics
+    /// Panics if any of the frames is not allocated or is still being accessed.
+    #[track_caller]
+    pub fn mark_freed(&self, page: PhyPageNumber, count: usize) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let at = Location::caller();
+        if let Some((live, by)) = self
+            .guards
+            .lock()
+            .iter()
+            .find(|(live, _)| overlaps(page, count, *live, 1))
+        {
+            panic!("Physical page {live:?} freed at {at} while still accessed at {by}");
+        }
+        let mut allocated = self.allocated.lock();
+        let end = page + count;
+        let mut current = page;
+        while current < end {
+            let Some(index) = find(&allocated, current) else {
+                assert!(
+                    self.overflowed.load(Ordering::Relaxed),
+                    "Physical page {current:?} freed at {at} is not allocated"
+                );
+                current = current + 1;
+                continue;
+            };
+            // Split the range in place, so at most one new entry is needed.
+            let (start, len, by) = allocated[index];
+            let freed = (start + len).min(end);
+            let before = usize::from(current) - usize::from(start);
+            let after = usize::from(start + len) - usize::from(freed);
+            match (before, after) {
+                (0, 0) => {
+                    allocated.remove(index);
+                }
+                (0, _) => allocated[index] = (freed, after, by),
+                (_, 0) => allocated[index] = (start, before, by),
+                _ => {
+                    allocated[index] = (start, before, by);
+                    if allocated.try_insert(index + 1, (freed, after, by)).is_err() {
+                        self.overflow();
+                    }
+                }
+            }
+            current = freed;
+        }
+    }
+
+    /// Gives up on checking that accessed and freed frames are allocated, as a range could not be recorded.
+    fn overflow(&self) {
+        if !self.overflowed.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Too many allocated physical page ranges to track, accesses to free pages are no longer caught"
+            );
+        }
+    }
+
+    fn acquire(&self, page: PhyPageNumber, at: CallSite) {
+        assert!(
+            find(&self.allocated.lock(), page).is_some() || self.overflowed.load(Ordering::Relaxed),
+            "Physical page {page:?} accessed at {at} is not allocated"
+        );
+        let mut guards = self.guards.lock();
+        if let Some((_, by)) = guards.iter().find(|(live, _)| *live == page) {
+            panic!("Physical page {page:?} accessed at {at} while already accessed at {by}");
+        }
+        guards
+            .try_push((page, at))
+            .expect("too many live physical page guards to track");
+    }
+
+    fn release(&self, page: PhyPageNumber) {
+        let mut guards = self.guards.lock();
+        let index = guards
+            .iter()
+            .position(|(live, _)| *live == page)
+            .expect("released a physical page guard that was never acquired");
+        guards.swap_remove(index);
+    }
+}
+
+/// Returns the index of the range in `allocated` containing `page`.
+fn find(allocated: &[(PhyPageNumber, usize, CallSite)], page: PhyPageNumber) -> Option<usize> {
+    let index = allocated
+        .partition_point(|(start, _, _)| *start <= page)
+        .checked_sub(1)?;
+    let (start, len, _) = allocated[index];
+    (page < start + len).then_some(index)
+}
+
+fn overlaps(a: PhyPageNumber, a_len: usize, b: PhyPageNumber, b_len: usize) -> bool {
+    a < b + b_len && b < a + a_len
+}
+
+#[derive(Debug, Clone)]
+/// A [`PhysicalPageAccessor`] checked by a [`DebugPageTracker`].
+pub struct DebugAccessor<'t, C: PhysicalPageAccessor, const N: usize> {
+    inner: C,
+    tracker: &'t DebugPageTracker<N>,
+}
+impl<C: PhysicalPageAccessor, const N: usize> PhysicalPageAccessor for DebugAccessor<'_, C, N> {
+    #[track_caller]
+    fn access_phy_page(&self, phy_page_number: PhyPageNumber) -> impl PhysicalPageAccessGuard + '_ {
+        if cfg!(debug_assertions) {
+            self.tracker.acquire(phy_page_number, Location::caller());
+        }
+        DebugGuard {
+            inner: self.inner.access_phy_page(phy_page_number),
+            tracker: self.tracker,
+            page: phy_page_number,
+        }
+    }
+}
+
+struct DebugGuard<'t, G: PhysicalPageAccessGuard, const N: usize> {
+    inner: G,
+    tracker: &'t DebugPageTracker<N>,
+    page: PhyPageNumber,
+}
+impl<G: PhysicalPageAccessGuard, const N: usize> PhysicalPageAccessGuard for DebugGuard<'_, G, N> {
+    fn get_mut_ptr(&self) -> *mut Page {
+        self.inner.get_mut_ptr()
+    }
+}
+impl<G: PhysicalPageAccessGuard, const N: usize> Drop for DebugGuard<'_, G, N> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.tracker.release(self.page);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A [`PhysicalPageAllocator`] recording its frames in a [`DebugPageTracker`].
+pub struct DebugAllocator<'t, A: PhysicalPageAllocator, const N: usize> {
+    inner: A,
+    tracker: &'t DebugPageTracker<N>,
+}
+impl<A: PhysicalPageAllocator, const N: usize> PhysicalPageAllocator for DebugAllocator<'_, A, N> {
+    #[track_caller]
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let page = self.inner.allocate()?;
+        self.tracker.mark_allocated(page, 1);
+        Ok(page)
+    }
+    #[track_caller]
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let page = self.inner.allocate_contiguous(count)?;
+        self.tracker.mark_allocated(page, count);
+        Ok(page)
+    }
+    #[track_caller]
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let page = self.inner.allocate_aligned(count, align)?;
+        self.tracker.mark_allocated(page, count);
+        Ok(page)
+    }
+    #[track_caller]
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        self.tracker.mark_freed(page, 1);
+        unsafe { self.inner.deallocate(page) };
+    }
+    #[track_caller]
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        self.tracker.mark_freed(page, count);
+        unsafe { self.inner.deallocate_contiguous(page, count) };
+    }
+    fn retype(&self, page: PhyPageNumber, count: usize, kind: PageKind) {
+        self.inner.retype(page, count, kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DebugPageTracker;
+    use crate::page::{PhysicalPageAccessor, testing::HostMemory};
+
+    #[test]
+    #[should_panic(expected = "while already accessed")]
+    fn conflicting_guard() {
+        let memory = HostMemory::new(0, 16);
+        let tracker: DebugPageTracker = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 16);
+        let accessor = tracker.accessor(&memory);
+        let _first = accessor.access_phy_page(3.into());
+        let _second = accessor.access_phy_page(3.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allocated")]
+    fn use_after_free() {
+        let memory = HostMemory::new(0, 16);
+        let tracker: DebugPageTracker = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 16);
+        tracker.mark_freed(5.into(), 2);
+        let accessor = tracker.accessor(&memory);
+        drop(accessor.access_phy_page(4.into()));
+        drop(accessor.access_phy_page(7.into()));
+        let _freed = accessor.access_phy_page(6.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "still accessed")]
+    fn free_while_accessed() {
+        let memory = HostMemory::new(0, 16);
+        let tracker: DebugPageTracker = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 16);
+        let accessor = tracker.accessor(&memory);
+        let _guard = accessor.access_phy_page(9.into());
+        tracker.mark_freed(8.into(), 4);
+    }
+
+    #[test]
+    fn free_splits_in_place() {
+        let tracker: DebugPageTracker<2> = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 16);
+        tracker.mark_allocated(32.into(), 1);
+        tracker.mark_freed(0.into(), 1);
+        tracker.mark_freed(15.into(), 1);
+        tracker.mark_freed(32.into(), 1);
+        tracker.mark_freed(5.into(), 2);
+        tracker.mark_freed(1.into(), 4);
+        tracker.mark_freed(7.into(), 8);
+        assert!(tracker.allocated.lock().is_empty());
+    }
+
+    #[test]
+    fn adjacent_allocations_merge() {
+        let tracker: DebugPageTracker<2> = DebugPageTracker::new();
+        for page in [4, 5, 3, 8, 6, 2] {
+            tracker.mark_allocated(page.into(), 1);
+        }
+        // 7 closes the gap between both ranges.
+        tracker.mark_allocated(7.into(), 1);
+        let allocated = tracker.allocated.lock();
+        assert_eq!(allocated.len(), 1);
+        assert_eq!(allocated[0].0, 2.into());
+        assert_eq!(allocated[0].1, 7);
+    }
+
+    #[test]
+    fn free_spans_merged_ranges() {
+        let tracker: DebugPageTracker<2> = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 4);
+        tracker.mark_allocated(4.into(), 4);
+        tracker.mark_allocated(10.into(), 2);
+        tracker.mark_freed(2.into(), 4);
+        tracker.mark_freed(0.into(), 2);
+        tracker.mark_freed(6.into(), 2);
+        tracker.mark_freed(10.into(), 2);
+        assert!(tracker.allocated.lock().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "overlap")]
+    fn overlapping_allocation() {
+        let tracker: DebugPageTracker = DebugPageTracker::new();
+        tracker.mark_allocated(4.into(), 4);
+        tracker.mark_allocated(0.into(), 5);
+    }
+
+    #[test]
+    fn overflow_stops_checking_allocations() {
+        let memory = HostMemory::new(0, 16);
+        let tracker: DebugPageTracker<1> = DebugPageTracker::new();
+        tracker.mark_allocated(0.into(), 16);
+        tracker.mark_freed(5.into(), 1);
+        // The range after the freed page did not fit, so it is no longer known to be allocated.
+        drop(tracker.accessor(&memory).access_phy_page(10.into()));
+        tracker.mark_freed(10.into(), 1);
+    }
+}
@@ -0,0 +1,452 @@
+use core::{
+    mem::MaybeUninit,
+    ops::{BitAnd, BitOr, Not},
+    sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, Ordering},
+};
+
+use arrayvec::ArrayVec;
+
+use super::{
+    PhysicalPageAccessor, PhysicalPageAllocError, PhysicalPageAllocator, access_phy,
+    region::{FreeRegions, MAX_REGIONS},
+};
+use crate::{Page, PhyPageNumber};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(u8)]
+/// What a physical frame is used for.
+pub enum PageKind {
+    #[default]
+    Free,
+    /// Not managed by any allocator, such as firmware memory or holes.
+    Reserved,
+    /// Kernel image or memory management metadata.
+    Kernel,
+    PageTable,
+    UserPage,
+    KernelHeap,
+    /// Handed out as untyped memory, to be retyped by its owner.
+    Untyped,
+}
+impl PageKind {
+    fn from_number(num: u8) -> Self {
+        match num {
+            0 => PageKind::Free,
+            1 => PageKind::Reserved,
+            2 => PageKind::Kernel,
+            3 => PageKind::PageTable,
+            4 => PageKind::UserPage,
+            5 => PageKind::KernelHeap,
+            6 => PageKind::Untyped,
+            _ => panic!("Invalid page kind code: {num}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Per-frame flags.
+pub struct PageFlags(u8);
+impl PageFlags {
+    pub const EMPTY: Self = PageFlags(0);
+    /// Shared mappings must be copied before the next write.
+    pub const COPY_ON_WRITE: Self = PageFlags(1 << 0);
+    /// Mapped into more than one address space on purpose.
+    pub const SHARED: Self = PageFlags(1 << 1);
+    /// Must not be moved or reclaimed.
+    pub const PINNED: Self = PageFlags(1 << 2);
+    /// Known to contain only zeros.
+    pub const ZEROED: Self = PageFlags(1 << 3);
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+impl BitOr for PageFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        PageFlags(self.0 | rhs.0)
+    }
+}
+impl BitAnd for PageFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        PageFlags(self.0 & rhs.0)
+    }
+}
+impl Not for PageFlags {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        PageFlags(!self.0)
+    }
+}
+
+#[derive(Debug)]
+#[repr(C, align(16))]
+/// The descriptor of a single physical frame.
+struct PageDescriptor {
+    refcount: AtomicU32,
+    kind: AtomicU8,
+    flags: AtomicU8,
+    owner: AtomicUsize,
+}
+
+const PER_PAGE: usize = Page::SIZE / size_of::<PageDescriptor>();
+
+type DescriptorPage = [PageDescriptor; PER_PAGE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// A snapshot of a frame's descriptor.
+pub struct PageInfo {
+    pub refcount: u32,
+    pub kind: PageKind,
+    pub flags: PageFlags,
+    /// Identifier of the owning object, meaningful only together with `kind`.
+    pub owner: usize,
+}
+
+#[derive(Debug)]
+/// An array of descriptors indexed by physical page number.
+pub struct PageDatabase<C: PhysicalPageAccessor> {
+    accessor: C,
+    base: PhyPageNumber,
+    count: usize,
+    descriptors: PhyPageNumber,
+}
+impl<C: PhysicalPageAccessor> PageDatabase<C> {
+    /// Creates a database covering the `count` frames from `base`, normally the span of the whole memory map,
+    /// taking the descriptors from `metadata`.
+    /// Frames in `regions` start out [`PageKind::Free`] and every other frame [`PageKind::Reserved`].
+    /// `metadata` must not hand out frames in `regions`.
+    /// # Errors
+    /// Returns an error if `count` is 0 or the descriptors cannot be allocated.
+    pub fn new(
+        accessor: C,
+        (base, count): (PhyPageNumber, usize),
+        regions: &FreeRegions,
+        metadata: &impl PhysicalPageAllocator,
+    ) -> Result<Self, PhysicalPageAllocError> {
+        if count == 0 {
+            return Err(PhysicalPageAllocError);
+        }
+        let len = count.div_ceil(PER_PAGE);
+        let descriptors = metadata.allocate_contiguous(len)?;
+        let mut free: ArrayVec<_, MAX_REGIONS> = regions.iter().collect();
+        free.sort_unstable_by_key(|&(start, _)| start);
+        let mut free = free.into_iter().peekable();
+        for i in 0..len {
+            let mut guard = accessor.access_phy_page(descriptors + i);
+            unsafe {
+                access_phy::<MaybeUninit<DescriptorPage>, ()>(&mut guard, |page| {
+                    page.write(core::array::from_fn(|j| {
+                        // Frames come in address order, so the regions are walked once alongside them.
+                        let frame = base + (i * PER_PAGE + j);
+                        while free.next_if(|&(start, len)| start + len <= frame).is_some() {}
+                        let kind = if free.peek().is_some_and(|&(start, _)| start <= frame) {
+                            PageKind::Free
+                        } else {
+                            PageKind::Reserved
+                        };
+                        PageDescriptor {
+                            refcount: AtomicU32::new(0),
+                            kind: AtomicU8::new(kind as u8),
+                            flags: AtomicU8::new(0),
+                            owner: AtomicUsize::new(0),
+                        }
+                    }));
+                });
+            }
+        }
+        let database = PageDatabase {
+            accessor,
+            base,
+            count,
+            descriptors,
+        };
+        for i in 0..len {
+            database.set_kind(descriptors + i, PageKind::Kernel, 0);
+        }
+        Ok(database)
+    }
+
+    /// Wraps an allocator so the frames it hands out become [`PageKind::Untyped`] for `owner`,
+    /// until retyped through [`PhysicalPageAllocator::retype`], and the frames given back become [`PageKind::Free`].
+    pub fn allocator<A: PhysicalPageAllocator>(
+        &self,
+        inner: A,
+        owner: usize,
+    ) -> DescribedAllocator<'_, C, A> {
+        DescribedAllocator {
+            inner,
+            database: self,
+            owner,
+        }
+    }
+
+    /// Returns the frames covered by the database.
+    #[must_use]
+    pub fn range(&self) -> (PhyPageNumber, usize) {
+        (self.base, self.count)
+    }
+
+    fn with<R>(&self, page: PhyPageNumber, f: impl FnOnce(&PageDescriptor) -> R) -> R {
+        assert!(
+            page >= self.base && usize::from(page) - usize::from(self.base) < self.count,
+            "Physical page {page:?} has no descriptor"
+        );
+        let index = usize::from(page) - usize::from(self.base);
+        let mut guard = self
+            .accessor
+            .access_phy_page(self.descriptors + index / PER_PAGE);
+        unsafe {
+            access_phy(&mut guard, |page: &mut DescriptorPage| {
+                f(&page[index % PER_PAGE])
+            })
+        }
+    }
+
+    /// Returns a snapshot of the descriptor of `page`.
+    /// # Panics
+    /// Panics if `page` is not covered by the database.
+    pub fn get(&self, page: PhyPageNumber) -> PageInfo {
+        self.with(page, |descriptor| PageInfo {
+            refcount: descriptor.refcount.load(Ordering::Acquire),
+            kind: PageKind::from_number(descriptor.kind.load(Ordering::Acquire)),
+            flags: PageFlags(descriptor.flags.load(Ordering::Acquire)),
+            owner: descriptor.owner.load(Ordering::Acquire),
+        })
+    }
+
+    /// Changes the kind and owner of `page`, resetting its flags.
+    /// # Panics
+    /// Panics if `page` is not covered by the database.
+    pub fn set_kind(&self, page: PhyPageNumber, kind: PageKind, owner: usize) {
+        self.with(page, |descriptor| {
+            descriptor.owner.store(owner, Ordering::Release);
+            descriptor.flags.store(0, Ordering::Release);
+            descriptor.kind.store(kind as u8, Ordering::Release);
+        });
+    }
+
+    /// Sets `flags` on `page`, returning the previous flags.
+    /// # Panics
+    /// Panics if `page` is not covered by the database.
+    pub fn insert_flags(&self, page: PhyPageNumber, flags: PageFlags) -> PageFlags {
+        self.with(page, |descriptor| {
+            PageFlags(descriptor.flags.fetch_or(flags.0, Ordering::AcqRel))
+        })
+    }
+
+    /// Clears `flags` on `page`, returning the previous flags.
+    /// # Panics
+    /// Panics if `page` is not covered by the database.
+    pub fn remove_flags(&self, page: PhyPageNumber, flags: PageFlags) -> PageFlags {
+        self.with(page, |descriptor| {
+            PageFlags(descriptor.flags.fetch_and(!flags.0, Ordering::AcqRel))
+        })
+    }
+
+    /// Adds a reference to `page`, returning the new count.
+    /// # Panics
+    /// Panics if `page` is not covered by the database or the count overflows.
+    pub fn acquire(&self, page: PhyPageNumber) -> u32 {
+        self.with(page, |descriptor| {
+            descriptor
+                .refcount
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    count.checked_add(1)
+                })
+                .expect("page reference count overflow")
+                + 1
+        })
+    }
+
+    /// Drops a reference to `page`, returning the new count.
+    /// # Panics
+    /// Panics if `page` is not covered by the database or has no reference.
+    pub fn release(&self, page: PhyPageNumber) -> u32 {
+        self.with(page, |descriptor| {
+            descriptor
+                .refcount
+                .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |count| {
+                    count.checked_sub(1)
+                })
+                .unwrap_or_else(|_| panic!("Physical page {page:?} released with no reference"))
+                - 1
+        })
+    }
+}
+
+#[derive(Debug)]
+/// A [`PhysicalPageAllocator`] keeping the kind and owner of its frames in a [`PageDatabase`].
+pub struct DescribedAllocator<'d, C: PhysicalPageAccessor, A: PhysicalPageAllocator> {
+    inner: A,
+    database: &'d PageDatabase<C>,
+    owner: usize,
+}
+impl<C: PhysicalPageAccessor, A: PhysicalPageAllocator> DescribedAllocator<'_, C, A> {
+    fn handed_out(&self, page: PhyPageNumber, count: usize) -> PhyPageNumber {
+        for page in (0..count).map(|i| page + i) {
+            self.database.set_kind(page, PageKind::Untyped, self.owner);
+        }
+        page
+    }
+
+    fn given_back(&self, page: PhyPageNumber, count: usize) {
+        for page in (0..count).map(|i| page + i) {
+            assert!(
+                self.database.get(page).kind != PageKind::Free,
+                "Physical page {page:?} is already free"
+            );
+            self.database.set_kind(page, PageKind::Free, 0);
+        }
+    }
+}
+impl<C: PhysicalPageAccessor, A: PhysicalPageAllocator> PhysicalPageAllocator
+    for DescribedAllocator<'_, C, A>
+{
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.inner.allocate().map(|page| self.handed_out(page, 1))
+    }
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.inner
+            .allocate_contiguous(count)
+            .map(|page| self.handed_out(page, count))
+    }
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.inner
+            .allocate_aligned(count, align)
+            .map(|page| self.handed_out(page, count))
+    }
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        self.given_back(page, 1);
+        unsafe { self.inner.deallocate(page) };
+    }
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        self.given_back(page, count);
+        unsafe { self.inner.deallocate_contiguous(page, count) };
+    }
+    fn retype(&self, page: PhyPageNumber, count: usize, kind: PageKind) {
+        for page in (0..count).map(|i| page + i) {
+            self.database.set_kind(page, kind, self.owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PageDatabase, PageKind};
+    use crate::{
+        arch::page::PagingMode,
+        heap::HeapBacking,
+        page::{
+            PageTree, PhysicalPageAllocator,
+            bump::BumpAllocator,
+            testing::{HostMemory, regions},
+        },
+    };
+
+    #[test]
+    fn covers_the_memory_map() {
+        let memory = HostMemory::new(0, 32);
+        let metadata = BumpAllocator::new(&mut regions(23, 1)).unwrap();
+        let database =
+            PageDatabase::new(&memory, (0.into(), 24), &regions(8, 8), &metadata).unwrap();
+        assert_eq!(database.range(), (0.into(), 24));
+        assert_eq!(database.get(0.into()).kind, PageKind::Reserved);
+        assert_eq!(database.get(8.into()).kind, PageKind::Free);
+        assert_eq!(database.get(20.into()).kind, PageKind::Reserved);
+        assert_eq!(database.get(23.into()).kind, PageKind::Kernel);
+    }
+
+    #[test]
+    fn regions_in_any_order() {
+        let memory = HostMemory::new(0, 32);
+        let metadata = BumpAllocator::new(&mut regions(23, 1)).unwrap();
+        let mut free = regions(20, 3);
+        free.insert(2.into(), 4);
+        free.insert(10.into(), 1);
+        let database = PageDatabase::new(&memory, (0.into(), 24), &free, &metadata).unwrap();
+        // Frame 23 holds the descriptors themselves.
+        let kinds: alloc::vec::Vec<_> = (0..23).map(|i| database.get(i.into()).kind).collect();
+        let free = |i: usize| (2..6).contains(&i) || i == 10 || (20..23).contains(&i);
+        for (i, kind) in kinds.into_iter().enumerate() {
+            let expected = if free(i) {
+                PageKind::Free
+            } else {
+                PageKind::Reserved
+            };
+            assert_eq!(kind, expected, "frame {i}");
+        }
+    }
+
+    #[test]
+    fn allocator_updates_descriptors() {
+        let memory = HostMemory::new(0, 32);
+        let buddy = memory.buddy(16);
+        let metadata = BumpAllocator::new(&mut regions(17, 15)).unwrap();
+        let database =
+            PageDatabase::new(&memory, (0.into(), 32), &regions(0, 16), &metadata).unwrap();
+        let allocator = database.allocator(&buddy, 7);
+
+        let page = allocator.allocate_contiguous(3).unwrap();
+        for i in 0..3 {
+            let info = database.get(page + i);
+            assert_eq!((info.kind, info.owner), (PageKind::Untyped, 7));
+        }
+        assert_eq!(database.get(page + 3).kind, PageKind::Free);
+        unsafe { allocator.deallocate_contiguous(page, 3) };
+        for i in 0..3 {
+            let info = database.get(page + i);
+            assert_eq!((info.kind, info.owner), (PageKind::Free, 0));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is already free")]
+    fn allocator_catches_double_free() {
+        let memory = HostMemory::new(0, 32);
+        let buddy = memory.buddy(16);
+        let metadata = BumpAllocator::new(&mut regions(17, 15)).unwrap();
+        let database =
+            PageDatabase::new(&memory, (0.into(), 32), &regions(0, 16), &metadata).unwrap();
+        let allocator = database.allocator(&buddy, 0);
+        let page = allocator.allocate().unwrap();
+        unsafe {
+            allocator.deallocate(page);
+            allocator.deallocate(page);
+        }
+    }
+
+    #[test]
+    fn page_trees_and_the_heap_retype_their_frames() {
+        let memory = HostMemory::new(0, 32);
+        let buddy = memory.buddy(16);
+        let metadata = BumpAllocator::new(&mut regions(17, 15)).unwrap();
+        let database =
+            PageDatabase::new(&memory, (0.into(), 32), &regions(0, 16), &metadata).unwrap();
+        let allocator = database.allocator(&buddy, 5);
+        let count = |kind| {
+            (0..16)
+                .filter(|&i| database.get(i.into()).kind == kind)
+                .count()
+        };
+
+        let tree = PageTree::new(&memory, &allocator, PagingMode::Layer3).unwrap();
+        assert_eq!(tree.grow(0x4_0000.into(), 2), 2);
+        // The root, a middle and a last level table.
+        assert_eq!(count(PageKind::PageTable), 3);
+        assert_eq!(count(PageKind::KernelHeap), 2);
+        let heap = tree.translate(0x4_0000.into()).unwrap();
+        assert_eq!(database.get(heap).owner, 5);
+        drop(tree);
+        assert_eq!(count(PageKind::PageTable), 0);
+    }
+}
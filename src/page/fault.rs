@@ -0,0 +1,190 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{PhysicalPageAllocError, PhysicalPageAllocator, descriptor::PageKind};
+use crate::{Page, PhyPageNumber, Rng, sync::SpinLock};
+
+#[derive(Debug)]
+/// When a [`FaultInjectingAllocator`] fails requests.
+pub enum FaultPolicy {
+    Never,
+    /// Fail only the allocation request with this index, counting from zero.
+    FailNth(usize),
+    /// Fail each request with probability `numerator / denominator`, never if `denominator` is 0.
+    Random {
+        rng: Rng,
+        numerator: u64,
+        denominator: u64,
+    },
+    /// Fail every request that would bring the total allocated bytes above the budget.
+    /// Deallocations do not refund the budget.
+    Budget(usize),
+}
+
+#[derive(Debug)]
+/// Wraps an allocator and fails requests according to a [`FaultPolicy`], to exercise out-of-memory paths.
+/// Also counts outstanding frames so callers can check nothing leaked.
+pub struct FaultInjectingAllocator<A: PhysicalPageAllocator> {
+    backing: A,
+    policy: SpinLock<FaultPolicy>,
+    calls: AtomicUsize,
+    injected: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    outstanding: AtomicUsize,
+}
+impl<A: PhysicalPageAllocator> FaultInjectingAllocator<A> {
+    pub const fn new(backing: A, policy: FaultPolicy) -> Self {
+        FaultInjectingAllocator {
+            backing,
+            policy: SpinLock::new(policy),
+            calls: AtomicUsize::new(0),
+            injected: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replaces the policy and resets the request and budget counters.
+    pub fn set_policy(&self, policy: FaultPolicy) {
+        *self.policy.lock() = policy;
+        self.calls.store(0, Ordering::Relaxed);
+        self.allocated_bytes.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the number of allocation requests seen since the policy was set.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failures injected so far.
+    pub fn injected(&self) -> usize {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames allocated through this wrapper and not yet freed.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    fn should_fail(&self, count: usize) -> bool {
+        let index = self.calls.fetch_add(1, Ordering::Relaxed);
+        let fail = match &mut *self.policy.lock() {
+            FaultPolicy::Never => false,
+            FaultPolicy::FailNth(nth) => index == *nth,
+            FaultPolicy::Random {
+                rng,
+                numerator,
+                denominator,
+            } => *denominator != 0 && rng.next().unwrap() % *denominator < *numerator,
+            FaultPolicy::Budget(budget) => {
+                let bytes = count * Page::SIZE;
+                let total = self.allocated_bytes.load(Ordering::Relaxed) + bytes;
+                if total > *budget {
+                    true
+                } else {
+                    self.allocated_bytes.store(total, Ordering::Relaxed);
+                    false
+                }
+            }
+        };
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+
+    fn track(
+        &self,
+        count: usize,
+        allocate: impl FnOnce(&A) -> Result<PhyPageNumber, PhysicalPageAllocError>,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        if self.should_fail(count) {
+            return Err(PhysicalPageAllocError);
+        }
+        let page = allocate(&self.backing)?;
+        self.outstanding.fetch_add(count, Ordering::Relaxed);
+        Ok(page)
+    }
+}
+impl<A: PhysicalPageAllocator> PhysicalPageAllocator for FaultInjectingAllocator<A> {
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.track(1, A::allocate)
+    }
+
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.track(count, |backing| backing.allocate_contiguous(count))
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.track(count, |backing| backing.allocate_aligned(count, align))
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+        unsafe { self.backing.deallocate(page) };
+    }
+
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        self.outstanding.fetch_sub(count, Ordering::Relaxed);
+        unsafe { self.backing.deallocate_contiguous(page, count) };
+    }
+
+    fn retype(&self, page: PhyPageNumber, count: usize, kind: PageKind) {
+        self.backing.retype(page, count, kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjectingAllocator, FaultPolicy};
+    use crate::{
+        Rng,
+        page::{PhysicalPageAllocator, testing::HostMemory},
+    };
+
+    #[test]
+    fn policies() {
+        let memory = HostMemory::new(0, 17);
+        let buddy = memory.buddy(16);
+        let faulty = FaultInjectingAllocator::new(&buddy, FaultPolicy::FailNth(1));
+        let page = faulty.allocate().unwrap();
+        assert!(faulty.allocate().is_err());
+        let pages = faulty.allocate_contiguous(2).unwrap();
+        assert_eq!(
+            (faulty.calls(), faulty.injected(), faulty.outstanding()),
+            (3, 1, 3)
+        );
+
+        faulty.set_policy(FaultPolicy::Budget(3 * crate::Page::SIZE));
+        let more = faulty.allocate_contiguous(3).unwrap();
+        assert!(faulty.allocate().is_err());
+        unsafe {
+            faulty.deallocate(page);
+            faulty.deallocate_contiguous(pages, 2);
+            faulty.deallocate_contiguous(more, 3);
+        }
+        assert_eq!(faulty.outstanding(), 0);
+        assert_eq!(buddy.free_count(), 16);
+    }
+
+    #[test]
+    fn random_with_zero_denominator_never_fails() {
+        let memory = HostMemory::new(0, 17);
+        let buddy = memory.buddy(16);
+        let faulty = FaultInjectingAllocator::new(
+            &buddy,
+            FaultPolicy::Random {
+                rng: Rng::default(),
+                numerator: 1,
+                denominator: 0,
+            },
+        );
+        for _ in 0..16 {
+            faulty.allocate().unwrap();
+        }
+        assert_eq!(faulty.injected(), 0);
+    }
+}
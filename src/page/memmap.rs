@@ -0,0 +1,203 @@
+use arrayvec::ArrayVec;
+
+use crate::{MemoryMapType, Page, PhyPageNumber};
+
+pub const MAX_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, Default)]
+/// A sanitized physical memory map: page granular, sorted, free of overlaps,
+/// with adjacent entries of the same type merged.
+pub struct MemoryMap {
+    entries: ArrayVec<(PhyPageNumber, usize, MemoryMapType), MAX_ENTRIES>,
+}
+impl MemoryMap {
+    #[must_use]
+    pub const fn new() -> Self {
+        MemoryMap {
+            entries: ArrayVec::new_const(),
+        }
+    }
+
+    /// Builds the map from byte granular `(base, len, type)` entries in any order.
+    /// Reclaimable entries shrink to the pages they fully cover, the others grow to every page they touch.
+    /// Where entries overlap, the more restrictive type wins.
+    /// # Panics
+    /// Panics if there are too many entries to track.
+    pub fn from_entries(entries: impl IntoIterator<Item = (usize, usize, MemoryMapType)>) -> Self {
+        let mut clipped = ArrayVec::<(usize, usize, MemoryMapType), MAX_ENTRIES>::new();
+        for (base, len, ty) in entries {
+            let end = base.saturating_add(len);
+            let (start, end) = if ty.is_reclaimable() {
+                (base.div_ceil(Page::SIZE), end / Page::SIZE)
+            } else {
+                (base / Page::SIZE, end.div_ceil(Page::SIZE))
+            };
+            if start < end {
+                clipped
+                    .try_push((start, end, ty))
+                    .expect("too many memory map entries to track");
+            }
+        }
+        let mut bounds = ArrayVec::<usize, { 2 * MAX_ENTRIES }>::new();
+        for &(start, end, _) in &clipped {
+            bounds.push(start);
+            bounds.push(end);
+        }
+        bounds.sort_unstable();
+        let mut map = MemoryMap::new();
+        for window in bounds.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            if start == end {
+                continue;
+            }
+            let mut covering = clipped
+                .iter()
+                .filter(|(entry_start, entry_end, _)| *entry_start <= start && end <= *entry_end)
+                .map(|(_, _, ty)| *ty);
+            let Some(first) = covering.next() else {
+                continue;
+            };
+            let ty = covering.fold(first, |kept, ty| {
+                if ty != kept {
+                    log::warn!(
+                        "Memory map entries {kept:?} and {ty:?} overlap at pages {start:#x}..{end:#x}"
+                    );
+                }
+                if ty.restrictiveness() > kept.restrictiveness() {
+                    ty
+                } else {
+                    kept
+                }
+            });
+            map.push(start.into(), end - start, ty);
+        }
+        map
+    }
+
+    /// Appends an entry after every existing one, merging it with the last one if possible.
+    fn push(&mut self, start: PhyPageNumber, len: usize, ty: MemoryMapType) {
+        if let Some((last_start, last_len, last_ty)) = self.entries.last_mut()
+            && *last_ty == ty
+            && *last_start + *last_len == start
+        {
+            *last_len += len;
+            return;
+        }
+        self.entries
+            .try_push((start, len, ty))
+            .expect("too many memory map entries to track");
+    }
+
+    /// Returns the number of pages of type `ty`.
+    #[must_use]
+    pub fn total(&self, ty: MemoryMapType) -> usize {
+        self.iter()
+            .filter(|(_, _, entry_ty)| *entry_ty == ty)
+            .map(|(_, len, _)| len)
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PhyPageNumber, usize, MemoryMapType)> + '_ {
+        self.entries.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::MemoryMap;
+    use crate::MemoryMapType::{
+        self, AcpiNvs, AcpiReclaimable, BadMemory, Framebuffer, Reserved, Unused,
+    };
+
+    type Entries = &'static [(usize, usize, MemoryMapType)];
+
+    /// Byte granular entries, and the page granular map expected from them.
+    const CASES: &[(&str, Entries, Entries)] = &[
+        (
+            "adjacent entries of a type merge",
+            &[(0, 0x2000, Unused), (0x2000, 0x1000, Unused)],
+            &[(0, 3, Unused)],
+        ),
+        (
+            "adjacent entries of different types stay apart",
+            &[(0, 0x1000, Unused), (0x1000, 0x1000, Reserved)],
+            &[(0, 1, Unused), (1, 1, Reserved)],
+        ),
+        (
+            "entries with a gap between them stay apart",
+            &[(0, 0x1000, Unused), (0x3000, 0x1000, Unused)],
+            &[(0, 1, Unused), (3, 1, Unused)],
+        ),
+        (
+            "unsorted entries are sorted",
+            &[(0x5000, 0x1000, Unused), (0, 0x1000, Framebuffer)],
+            &[(0, 1, Framebuffer), (5, 1, Unused)],
+        ),
+        (
+            "the more restrictive of overlapping entries wins",
+            &[(0, 0x4000, Unused), (0x1000, 0x1000, BadMemory)],
+            &[(0, 1, Unused), (1, 1, BadMemory), (2, 2, Unused)],
+        ),
+        (
+            "the more restrictive entry wins whichever comes first",
+            &[(0x1000, 0x1000, Reserved), (0, 0x4000, AcpiNvs)],
+            &[(0, 1, AcpiNvs), (1, 1, Reserved), (2, 2, AcpiNvs)],
+        ),
+        (
+            "identical entries of different types",
+            &[(0, 0x1000, AcpiNvs), (0, 0x1000, AcpiReclaimable)],
+            &[(0, 1, AcpiNvs)],
+        ),
+        (
+            "unaligned reclaimable entries shrink",
+            &[(0x800, 0x2000, Unused)],
+            &[(1, 1, Unused)],
+        ),
+        (
+            "unaligned reserved entries grow",
+            &[(0x800, 0x2000, Reserved)],
+            &[(0, 3, Reserved)],
+        ),
+        (
+            "reclaimable entries within a page vanish",
+            &[(0x100, 0x800, AcpiReclaimable)],
+            &[],
+        ),
+        (
+            "a reserved byte takes its whole page",
+            &[(0, 0x3000, Unused), (0x1800, 1, Reserved)],
+            &[(0, 1, Unused), (1, 1, Reserved), (2, 1, Unused)],
+        ),
+        (
+            "empty entries vanish",
+            &[(0x1000, 0, Reserved), (0x2000, 0, Unused)],
+            &[],
+        ),
+    ];
+
+    #[test]
+    fn from_entries() {
+        for &(name, entries, expected) in CASES {
+            let map = MemoryMap::from_entries(entries.iter().copied());
+            let pages: Vec<_> = map
+                .iter()
+                .map(|(start, len, ty)| (usize::from(start), len, ty))
+                .collect();
+            assert_eq!(pages, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn totals() {
+        let map = MemoryMap::from_entries([
+            (0x800, 0x3000, Unused),
+            (0x10000, 0x2000, Unused),
+            (0x2000, 0x800, Reserved),
+        ]);
+        assert_eq!(map.total(Unused), 3);
+        assert_eq!(map.total(Reserved), 1);
+        assert_eq!(map.total(BadMemory), 0);
+    }
+}
@@ -0,0 +1,124 @@
+use arrayvec::ArrayVec;
+
+use crate::{BootParms, MemoryMapType, PhyPageNumber};
+
+pub const MAX_REGIONS: usize = 128;
+
+#[derive(Debug, Clone, Default)]
+/// A set of disjoint free physical page ranges, used to seed allocators.
+/// The set also remembers the extent of every range ever inserted,
+/// so frames taken out of it early still fall inside the allocator built from it.
+pub struct FreeRegions {
+    regions: ArrayVec<(PhyPageNumber, usize), MAX_REGIONS>,
+    extent: Option<(PhyPageNumber, PhyPageNumber)>,
+}
+impl FreeRegions {
+    #[must_use]
+    pub const fn new() -> Self {
+        FreeRegions {
+            regions: ArrayVec::new_const(),
+            extent: None,
+        }
+    }
+
+    /// Collects the `Unused` entries of the boot memory map, leaving out the kernel image.
+    /// # Panics
+    /// Panics if the memory map is too fragmented to be tracked.
+    pub fn from_boot_parms(parms: &impl BootParms) -> Self {
+        let mut regions = FreeRegions::new();
+        for (start, len, ty) in parms.make_memory_map_accessor() {
+            if ty == MemoryMapType::Unused {
+                regions.insert(start, len);
+            }
+        }
+        let kernel = parms.kernel_address();
+        for map in [kernel.text, kernel.ro, kernel.data, kernel.bl] {
+            regions.remove(map.phy_base, map.len);
+        }
+        regions
+    }
+
+    /// Adds `[start, start + len)`, which must not overlap any existing region.
+    /// # Panics
+    /// Panics if there are too many regions.
+    pub fn insert(&mut self, start: PhyPageNumber, len: usize) {
+        if len > 0 {
+            self.regions
+                .try_push((start, len))
+                .expect("too many free memory regions to track");
+            self.extent = Some(self.extent.map_or((start, start + len), |(low, high)| {
+                (low.min(start), high.max(start + len))
+            }));
+        }
+    }
+
+    /// Removes `[start, start + len)` from every region, keeping the extent.
+    pub fn remove(&mut self, start: PhyPageNumber, len: usize) {
+        let end = start + len;
+        let mut i = 0;
+        while i < self.regions.len() {
+            let (region_start, region_len) = self.regions[i];
+            let region_end = region_start + region_len;
+            if region_end <= start || end <= region_start {
+                i += 1;
+                continue;
+            }
+            self.regions.swap_remove(i);
+            if region_start < start {
+                self.insert(region_start, usize::from(start) - usize::from(region_start));
+            }
+            if end < region_end {
+                self.insert(end, usize::from(region_end) - usize::from(end));
+            }
+        }
+    }
+
+    /// Removes the largest region and returns it.
+    pub fn take_largest(&mut self) -> Option<(PhyPageNumber, usize)> {
+        let (start, len) = self.iter().max_by_key(|(_, len)| *len)?;
+        self.remove(start, len);
+        Some((start, len))
+    }
+
+    /// Splits the regions into the parts below and at or above `page`.
+    #[must_use]
+    pub fn split_at(&self, page: PhyPageNumber) -> (Self, Self) {
+        let mut low = self.clone();
+        let mut high = self.clone();
+        low.remove(page, usize::MAX - usize::from(page));
+        high.remove(0.into(), page.into());
+        low.extent = self
+            .extent
+            .map(|(start, end)| (start, end.min(page)))
+            .filter(|(start, end)| start < end);
+        high.extent = self
+            .extent
+            .map(|(start, end)| (start.max(page), end))
+            .filter(|(start, end)| start < end);
+        (low, high)
+    }
+
+    /// Returns the smallest range containing every region ever inserted, including removed parts.
+    #[must_use]
+    pub fn span(&self) -> Option<(PhyPageNumber, usize)> {
+        self.extent
+            .map(|(start, end)| (start, usize::from(end) - usize::from(start)))
+    }
+
+    /// Returns true if `page` is in any region.
+    #[must_use]
+    pub fn contains(&self, page: PhyPageNumber) -> bool {
+        self.iter()
+            .any(|(start, len)| start <= page && page < start + len)
+    }
+
+    /// Returns the number of pages in all regions.
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.iter().map(|(_, len)| len).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PhyPageNumber, usize)> + '_ {
+        self.regions.iter().copied()
+    }
+}
@@ -0,0 +1,353 @@
+use core::ptr;
+
+use arrayvec::ArrayVec;
+
+use super::{
+    PhysicalPageAccessGuard, PhysicalPageAccessor, PhysicalPageAllocError, PhysicalPageAllocator,
+    access_phy,
+};
+use crate::{Page, PhyPageNumber, sync::SpinLock};
+
+/// The byte freed frames are filled with in debug builds.
+pub const POISON: u8 = 0xA5;
+/// Bytes at the start of a quarantined frame holding the link to the next one,
+/// left out of its poison check.
+const LINK: usize = size_of::<usize>();
+
+#[derive(Debug)]
+/// Guarantees every frame it hands out is zeroed, so no data leaks between owners.
+/// Freed single frames are zeroed right away and kept in a pool of ready frames;
+/// everything else is zeroed when allocated, so each frame is zeroed once between owners.
+/// In debug builds, freed frames are poisoned instead and checked on reuse to catch writes after free.
+/// Those the pool cannot hold are quarantined rather than given back, so only frames known to be poisoned
+/// are ever checked, and are given back once a contiguous request finds the backing allocator short.
+pub struct ScrubbingAllocator<
+    C: PhysicalPageAccessor,
+    A: PhysicalPageAllocator,
+    const POOL: usize = 64,
+> {
+    accessor: C,
+    backing: A,
+    pool: SpinLock<ArrayVec<PhyPageNumber, POOL>>,
+    /// The first quarantined frame, each chained to the next through its first word.
+    quarantine: SpinLock<Option<PhyPageNumber>>,
+}
+impl<C: PhysicalPageAccessor, A: PhysicalPageAllocator, const POOL: usize>
+    ScrubbingAllocator<C, A, POOL>
+{
+    /// Creates the allocator in front of `backing`.
+    pub const fn new(accessor: C, backing: A) -> Self {
+        ScrubbingAllocator {
+            accessor,
+            backing,
+            pool: SpinLock::new(ArrayVec::new_const()),
+            quarantine: SpinLock::new(None),
+        }
+    }
+
+    pub fn backing(&self) -> &A {
+        &self.backing
+    }
+
+    /// Returns the number of scrubbed frames ready in the pool.
+    pub fn pooled(&self) -> usize {
+        self.pool.lock().len()
+    }
+
+    /// Moves up to `count` frames from the backing allocator into the pool, scrubbing them.
+    /// Meant to run when the hart is otherwise idle.
+    /// Returns the number of frames added.
+    pub fn refill_pool(&self, count: usize) -> usize {
+        let mut added = 0;
+        while added < count {
+            if self.pool.lock().is_full() {
+                break;
+            }
+            let Ok(page) = self.backing.allocate() else {
+                break;
+            };
+            self.scrub(page, 1);
+            if let Err(error) = self.pool.lock().try_push(page) {
+                unsafe { self.backing.deallocate(error.element()) };
+                break;
+            }
+            added += 1;
+        }
+        added
+    }
+
+    /// Prepares frames for the pool: poisons them in debug builds, zeroes them otherwise.
+    fn scrub(&self, page: PhyPageNumber, count: usize) {
+        let byte = if cfg!(debug_assertions) { POISON } else { 0 };
+        for i in 0..count {
+            let guard = self.accessor.access_phy_page(page + i);
+            unsafe { ptr::write_bytes(guard.get_mut_ptr(), byte, 1) };
+        }
+    }
+
+    fn zero(&self, page: PhyPageNumber, count: usize) {
+        for i in 0..count {
+            let guard = self.accessor.access_phy_page(page + i);
+            unsafe { ptr::write_bytes(guard.get_mut_ptr(), 0, 1) };
+        }
+    }
+
+    /// Panics if any frame but its first `skip` bytes lost its poison.
+    fn check_poison(&self, page: PhyPageNumber, count: usize, skip: usize) {
+        for page in (0..count).map(|i| page + i) {
+            let mut guard = self.accessor.access_phy_page(page);
+            let corrupted = unsafe {
+                access_phy(&mut guard, |content: &mut Page| {
+                    content.0[skip..].iter().position(|&byte| byte != POISON)
+                })
+            };
+            if let Some(offset) = corrupted {
+                panic!(
+                    "Physical page {page:?} was written after free at offset {:#x}",
+                    skip + offset
+                );
+            }
+        }
+    }
+
+    /// Turns a pooled frame into a zeroed one, checking its poison in debug builds.
+    fn take_pooled(&self, page: PhyPageNumber) -> PhyPageNumber {
+        if cfg!(debug_assertions) {
+            self.check_poison(page, 1, 0);
+            self.zero(page, 1);
+        }
+        page
+    }
+
+    /// Poisons `count` frames from `page` and chains them into the quarantine.
+    fn quarantine(&self, page: PhyPageNumber, count: usize) {
+        let mut quarantine = self.quarantine.lock();
+        for page in (0..count).map(|i| page + i) {
+            let mut guard = self.accessor.access_phy_page(page);
+            let next = quarantine.map_or(usize::MAX, usize::from);
+            unsafe {
+                access_phy(&mut guard, |content: &mut Page| {
+                    content.0.fill(POISON);
+                    content.0[..LINK].copy_from_slice(&next.to_ne_bytes());
+                });
+            }
+            *quarantine = Some(page);
+        }
+    }
+
+    /// Takes the first quarantined frame, checking its poison and clearing its link.
+    fn release(&self) -> Option<PhyPageNumber> {
+        let mut quarantine = self.quarantine.lock();
+        let page = (*quarantine)?;
+        self.check_poison(page, 1, LINK);
+        let mut guard = self.accessor.access_phy_page(page);
+        let next = unsafe {
+            access_phy(&mut guard, |content: &mut Page| {
+                let next = usize::from_ne_bytes(content.0[..LINK].try_into().unwrap());
+                content.0[..LINK].fill(POISON);
+                next
+            })
+        };
+        *quarantine = (next != usize::MAX).then(|| next.into());
+        Some(page)
+    }
+
+    /// Gives every quarantined frame back to the backing allocator, returning whether there were any.
+    fn drain_quarantine(&self) -> bool {
+        let mut drained = false;
+        while let Some(page) = self.release() {
+            unsafe { self.backing.deallocate(page) };
+            drained = true;
+        }
+        drained
+    }
+
+    /// Hands freed frames the pool cannot take to the backing allocator, to be zeroed when allocated again.
+    /// Debug builds quarantine them instead.
+    /// # Safety
+    /// The frames must have been allocated from this allocator and no longer be used.
+    unsafe fn give_back(&self, page: PhyPageNumber, count: usize) {
+        if cfg!(debug_assertions) {
+            self.quarantine(page, count);
+        } else {
+            unsafe { self.backing.deallocate_contiguous(page, count) };
+        }
+    }
+
+    /// Runs `allocate` against the backing allocator and zeroes what it returns.
+    /// In debug builds, the quarantine is drained to retry once it fails.
+    fn take_backing(
+        &self,
+        count: usize,
+        allocate: impl Fn(&A) -> Result<PhyPageNumber, PhysicalPageAllocError>,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let page = allocate(&self.backing).or_else(|error| {
+            if cfg!(debug_assertions) && self.drain_quarantine() {
+                allocate(&self.backing)
+            } else {
+                Err(error)
+            }
+        })?;
+        self.zero(page, count);
+        Ok(page)
+    }
+}
+impl<C: PhysicalPageAccessor, A: PhysicalPageAllocator, const POOL: usize> PhysicalPageAllocator
+    for ScrubbingAllocator<C, A, POOL>
+{
+    fn allocate(&self) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        let pooled = self.pool.lock().pop();
+        if let Some(page) = pooled {
+            return Ok(self.take_pooled(page));
+        }
+        if cfg!(debug_assertions)
+            && let Some(page) = self.release()
+        {
+            self.zero(page, 1);
+            return Ok(page);
+        }
+        self.take_backing(1, A::allocate)
+    }
+
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        if count == 1 {
+            return self.allocate();
+        }
+        self.take_backing(count, |backing| backing.allocate_contiguous(count))
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        if count == 1 && align == 1 {
+            return self.allocate();
+        }
+        self.take_backing(count, |backing| backing.allocate_aligned(count, align))
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        if self.pool.lock().is_full() {
+            return unsafe { self.give_back(page, 1) };
+        }
+        self.scrub(page, 1);
+        if let Err(error) = self.pool.lock().try_push(page) {
+            unsafe { self.give_back(error.element(), 1) };
+        }
+    }
+
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        if count == 1 {
+            return unsafe { self.deallocate(page) };
+        }
+        unsafe { self.give_back(page, count) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{POISON, ScrubbingAllocator};
+    use crate::{
+        PhyPageNumber,
+        page::{
+            PhysicalPageAccessGuard, PhysicalPageAccessor, PhysicalPageAllocator,
+            testing::HostMemory,
+        },
+    };
+
+    fn filled_with(memory: &HostMemory, page: PhyPageNumber, byte: u8) -> bool {
+        let content = unsafe { &*memory.access_phy_page(page).get_mut_ptr() };
+        content.0.iter().all(|&b| b == byte)
+    }
+
+    fn poke(memory: &HostMemory, page: PhyPageNumber, offset: usize) {
+        unsafe { (*memory.access_phy_page(page).get_mut_ptr()).0[offset] = 9 };
+    }
+
+    /// What freed frames hold until they are reused.
+    const SCRUBBED: u8 = if cfg!(debug_assertions) { POISON } else { 0 };
+
+    #[test]
+    fn frames_are_zeroed_and_freed_frames_scrubbed() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let scrubbing: ScrubbingAllocator<_, _, 4> = ScrubbingAllocator::new(&memory, &buddy);
+        let page = scrubbing.allocate().unwrap();
+        assert!(filled_with(&memory, page, 0));
+        poke(&memory, page, 5);
+        unsafe { scrubbing.deallocate(page) };
+        assert!(filled_with(&memory, page, SCRUBBED));
+        assert_eq!(scrubbing.pooled(), 1);
+        assert_eq!(scrubbing.refill_pool(8), 3);
+        let pages = scrubbing.allocate_contiguous(3).unwrap();
+        assert!((0..3).all(|i| filled_with(&memory, pages + i, 0)));
+        for _ in 0..4 {
+            assert!(filled_with(&memory, scrubbing.allocate().unwrap(), 0));
+        }
+        assert_eq!(scrubbing.pooled(), 0);
+    }
+
+    #[test]
+    fn frames_past_the_pool_are_zeroed_once() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let scrubbing: ScrubbingAllocator<_, _, 1> = ScrubbingAllocator::new(&memory, &buddy);
+        let pages = scrubbing.allocate_contiguous(32).unwrap();
+        poke(&memory, pages, 5);
+        unsafe { scrubbing.deallocate_contiguous(pages, 32) };
+        if !cfg!(debug_assertions) {
+            // Left to be zeroed when allocated again.
+            assert!(!filled_with(&memory, pages, 0));
+        }
+        // Quarantined frames go back to the backing allocator once a contiguous request needs them.
+        let pages = scrubbing.allocate_contiguous(32).unwrap();
+        assert!((0..32).all(|i| filled_with(&memory, pages + i, 0)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "written after free")]
+    fn write_after_free_in_the_pool() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let scrubbing: ScrubbingAllocator<_, _, 4> = ScrubbingAllocator::new(&memory, &buddy);
+        let page = scrubbing.allocate().unwrap();
+        unsafe { scrubbing.deallocate(page) };
+        poke(&memory, page, 100);
+        let _ = scrubbing.allocate();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "written after free")]
+    fn write_after_free_in_quarantine() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let scrubbing: ScrubbingAllocator<_, _, 1> = ScrubbingAllocator::new(&memory, &buddy);
+        let first = scrubbing.allocate().unwrap();
+        let second = scrubbing.allocate().unwrap();
+        unsafe {
+            scrubbing.deallocate(first);
+            scrubbing.deallocate(second);
+        }
+        poke(&memory, second, 100);
+        let _ = scrubbing.allocate();
+        let _ = scrubbing.allocate();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "written after free")]
+    fn write_after_free_found_by_a_contiguous_request() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let scrubbing: ScrubbingAllocator<_, _, 1> = ScrubbingAllocator::new(&memory, &buddy);
+        // Frames never freed are not poisoned, so writes to them are fine.
+        poke(&memory, 7.into(), 200);
+        let pages = scrubbing.allocate_contiguous(4).unwrap();
+        unsafe { scrubbing.deallocate_contiguous(pages, 4) };
+        poke(&memory, pages + 2, 200);
+        let _ = scrubbing.allocate_contiguous(32);
+    }
+}
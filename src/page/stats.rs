@@ -0,0 +1,178 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{BootParms, MemoryMapType, Page};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+/// Memory counters, all in pages.
+pub enum MemoryCounter {
+    /// Usable memory reported by the bootloader, including the kernel image.
+    Total,
+    /// Frames free in the physical allocators.
+    /// Frames held by per-hart caches or scrub pools count as used.
+    Free,
+    /// The kernel image.
+    Kernel,
+    PageTables,
+    Heap,
+    /// Bootloader memory not yet reclaimed.
+    Bootloader,
+    /// Pages held by slab caches.
+    Slab,
+}
+impl MemoryCounter {
+    pub const COUNT: usize = 7;
+    pub const ALL: [MemoryCounter; Self::COUNT] = [
+        MemoryCounter::Total,
+        MemoryCounter::Free,
+        MemoryCounter::Kernel,
+        MemoryCounter::PageTables,
+        MemoryCounter::Heap,
+        MemoryCounter::Bootloader,
+        MemoryCounter::Slab,
+    ];
+}
+impl TryFrom<usize> for MemoryCounter {
+    type Error = usize;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        Self::ALL.get(value).copied().ok_or(value)
+    }
+}
+
+#[derive(Debug)]
+/// Global memory usage counters.
+pub struct MemoryStats([AtomicUsize; MemoryCounter::COUNT]);
+impl MemoryStats {
+    #[must_use]
+    pub const fn new() -> Self {
+        MemoryStats([const { AtomicUsize::new(0) }; _])
+    }
+
+    pub fn add(&self, counter: MemoryCounter, pages: usize) {
+        self.0[counter as usize].fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, counter: MemoryCounter, pages: usize) {
+        self.0[counter as usize].fetch_sub(pages, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self, counter: MemoryCounter) -> usize {
+        self.0[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Records the counters known from the boot parameters.
+    /// Kernel image pages the memory map reports as usable or bootloader memory are only counted as kernel.
+    pub fn record_boot(&self, parms: &impl BootParms) {
+        let kernel = parms.kernel_address();
+        let kernel = [kernel.text, kernel.ro, kernel.data, kernel.bl];
+        let kernel_len = kernel.iter().map(|map| map.len).sum();
+        let (mut usable, mut bootloader) = (0, 0);
+        for (start, len, ty) in parms.make_memory_map_accessor() {
+            let counter = match ty {
+                MemoryMapType::Unused => &mut usable,
+                MemoryMapType::BootloaderReserved => &mut bootloader,
+                MemoryMapType::Reserved
+                | MemoryMapType::AcpiReclaimable
+                | MemoryMapType::AcpiNvs
+                | MemoryMapType::BadMemory
+                | MemoryMapType::Framebuffer
+                | MemoryMapType::ExecutableAndModules => continue,
+            };
+            let (start, end) = (usize::from(start), usize::from(start) + len);
+            let inside: usize = kernel
+                .iter()
+                .map(|map| {
+                    let base = usize::from(map.phy_base);
+                    (base + map.len).min(end).saturating_sub(base.max(start))
+                })
+                .sum();
+            *counter += len - inside;
+        }
+        self.add(MemoryCounter::Total, usable + bootloader + kernel_len);
+        self.add(MemoryCounter::Kernel, kernel_len);
+        self.add(MemoryCounter::Bootloader, bootloader);
+    }
+
+    /// Records `pages` of bootloader memory handed to the physical allocators, which count them free.
+    pub fn reclaim_bootloader(&self, pages: usize) {
+        self.sub(MemoryCounter::Bootloader, pages);
+    }
+
+    /// Logs every counter in KiB, in the style of `/proc/meminfo`.
+    pub fn log_summary(&self) {
+        for counter in MemoryCounter::ALL {
+            log::info!(
+                "Mem{counter:?}: {} kB",
+                self.get(counter) * Page::SIZE / 1024
+            );
+        }
+    }
+}
+impl Default for MemoryStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static MEMORY_STATS: MemoryStats = MemoryStats::new();
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryCounter, MemoryStats};
+    use crate::{MemoryMapType, page::testing::TestBootParms};
+
+    #[test]
+    fn counts_boot_memory() {
+        let mut parms = TestBootParms::new(&[
+            (0, 16, MemoryMapType::Reserved),
+            (16, 100, MemoryMapType::Unused),
+            (116, 20, MemoryMapType::BootloaderReserved),
+            (136, 8, MemoryMapType::ExecutableAndModules),
+        ]);
+        parms.kernel = (136, 8);
+        let stats = MemoryStats::new();
+        stats.record_boot(&parms);
+        assert_eq!(stats.get(MemoryCounter::Total), 128);
+        assert_eq!(stats.get(MemoryCounter::Kernel), 8);
+        assert_eq!(stats.get(MemoryCounter::Bootloader), 20);
+        assert_eq!(stats.get(MemoryCounter::Free), 0);
+    }
+
+    #[test]
+    fn kernel_pages_in_usable_memory_count_once() {
+        let mut parms = TestBootParms::new(&[
+            (16, 100, MemoryMapType::Unused),
+            (116, 20, MemoryMapType::BootloaderReserved),
+        ]);
+        // Straddles both entries.
+        parms.kernel = (110, 10);
+        let stats = MemoryStats::new();
+        stats.record_boot(&parms);
+        assert_eq!(stats.get(MemoryCounter::Total), 120);
+        assert_eq!(stats.get(MemoryCounter::Kernel), 10);
+        assert_eq!(stats.get(MemoryCounter::Bootloader), 16);
+    }
+
+    #[test]
+    fn reclaiming_bootloader_memory() {
+        let parms = TestBootParms::new(&[(116, 20, MemoryMapType::BootloaderReserved)]);
+        let stats = MemoryStats::new();
+        stats.record_boot(&parms);
+        stats.reclaim_bootloader(12);
+        assert_eq!(stats.get(MemoryCounter::Bootloader), 8);
+        assert_eq!(stats.get(MemoryCounter::Total), 20);
+    }
+
+    #[test]
+    fn counters_by_number() {
+        for (number, counter) in MemoryCounter::ALL.into_iter().enumerate() {
+            assert_eq!(MemoryCounter::try_from(number), Ok(counter));
+        }
+        assert_eq!(
+            MemoryCounter::try_from(MemoryCounter::COUNT),
+            Err(MemoryCounter::COUNT)
+        );
+    }
+}
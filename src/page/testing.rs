@@ -0,0 +1,158 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{cell::UnsafeCell, ptr::NonNull};
+
+use super::{
+    PhysicalPageAccessGuard, PhysicalPageAccessor, PhysicalPageDirectMap, buddy::BuddyAllocator,
+    bump::BumpAllocator, region::FreeRegions,
+};
+use crate::{
+    BootParms, KernelAddress, MemoryMapType, Page, PhyPageNumber, PhyVirtMap, Rng, VirtPageNumber,
+    fdt::DeviceTree,
+};
+
+/// Host memory standing in for the physical frames `[base, base + count)` in unit tests.
+/// Frames start filled with `0xAA`, so code relying on zeroed frames is caught.
+pub struct HostMemory {
+    base: PhyPageNumber,
+    pages: Box<[UnsafeCell<Page>]>,
+}
+impl HostMemory {
+    pub fn new(base: usize, count: usize) -> Self {
+        HostMemory {
+            base: base.into(),
+            pages: (0..count)
+                .map(|_| UnsafeCell::new(Page([0xAA; Page::SIZE])))
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        }
+    }
+
+    /// Returns a buddy allocator over the first `count` frames, keeping its metadata in the frame after them.
+    pub fn buddy(&self, count: usize) -> BuddyAllocator<&Self> {
+        let base = usize::from(self.base);
+        let metadata = BumpAllocator::new(&mut regions(base + count, 1)).unwrap();
+        BuddyAllocator::new(self, &regions(base, count), &metadata).unwrap()
+    }
+
+    fn index(&self, page: PhyPageNumber) -> usize {
+        let index = usize::from(page)
+            .checked_sub(usize::from(self.base))
+            .filter(|&index| index < self.pages.len());
+        index.unwrap_or_else(|| panic!("Physical page {page:?} is outside the host memory"))
+    }
+}
+
+/// Returns free regions holding the `count` frames from `base`.
+pub fn regions(base: usize, count: usize) -> FreeRegions {
+    let mut regions = FreeRegions::new();
+    regions.insert(base.into(), count);
+    regions
+}
+
+/// Boot parameters handing out a fixed memory map, device tree and kernel image, with no harts to start.
+pub struct TestBootParms {
+    pub memory_map: Vec<(PhyPageNumber, usize, MemoryMapType)>,
+    pub device_tree: Option<Vec<u8>>,
+    /// Where the kernel image lies, as `(first frame, frame count)`.
+    pub kernel: (usize, usize),
+    pub memory: HostMemory,
+}
+impl TestBootParms {
+    pub fn new(memory_map: &[(usize, usize, MemoryMapType)]) -> Self {
+        TestBootParms {
+            memory_map: memory_map
+                .iter()
+                .map(|&(start, len, ty)| (start.into(), len, ty))
+                .collect(),
+            device_tree: None,
+            kernel: (0, 0),
+            memory: HostMemory::new(0, 1),
+        }
+    }
+}
+impl BootParms for TestBootParms {
+    fn take_rng(&mut self) -> Rng {
+        Rng::default()
+    }
+
+    fn entropy_seed(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn take_phy_page_accessor(&mut self) -> impl PhysicalPageDirectMap {
+        &self.memory
+    }
+
+    fn make_memory_map_accessor(
+        &self,
+    ) -> impl Iterator<Item = (PhyPageNumber, usize, MemoryMapType)> + '_ {
+        self.memory_map.iter().copied()
+    }
+
+    fn extra_map_iter(&self) -> impl Iterator<Item = (PhyPageNumber, VirtPageNumber, usize)> + '_ {
+        core::iter::empty()
+    }
+
+    fn device_tree(&self) -> Option<DeviceTree<'_>> {
+        self.device_tree
+            .as_deref()
+            .map(|blob| DeviceTree::new(blob).unwrap())
+    }
+
+    fn boot_hart(&self) -> usize {
+        0
+    }
+
+    fn secondary_harts(&self) -> impl Iterator<Item = usize> + '_ {
+        core::iter::empty()
+    }
+
+    fn start_hart(_hardware_id: usize) -> bool {
+        false
+    }
+
+    fn map_devices(_map: impl FnMut(PhyPageNumber, VirtPageNumber, usize) -> bool) {}
+
+    fn kernel_address(&self) -> KernelAddress {
+        let empty = PhyVirtMap {
+            phy_base: 0.into(),
+            virt_base: 0.into(),
+            len: 0,
+        };
+        KernelAddress {
+            text: PhyVirtMap {
+                phy_base: self.kernel.0.into(),
+                len: self.kernel.1,
+                ..empty
+            },
+            ro: empty,
+            data: empty,
+            bl: empty,
+        }
+    }
+}
+
+pub struct HostGuard(*mut Page);
+impl PhysicalPageAccessGuard for HostGuard {
+    fn get_mut_ptr(&self) -> *mut Page {
+        self.0
+    }
+}
+
+impl PhysicalPageAccessor for HostMemory {
+    fn access_phy_page(&self, phy_page_number: PhyPageNumber) -> impl PhysicalPageAccessGuard + '_ {
+        HostGuard(self.pages[self.index(phy_page_number)].get())
+    }
+}
+
+impl PhysicalPageDirectMap for HostMemory {
+    fn phy_to_virt(&self, phy_page_number: PhyPageNumber) -> NonNull<Page> {
+        NonNull::new(self.pages[self.index(phy_page_number)].get()).unwrap()
+    }
+
+    fn virt_to_phy(&self, ptr: *const u8) -> Option<PhyPageNumber> {
+        let start = self.pages.as_ptr().addr();
+        let offset = ptr.addr().checked_sub(start)?;
+        (offset < self.pages.len() * Page::SIZE).then(|| self.base + offset / Page::SIZE)
+    }
+}
@@ -0,0 +1,424 @@
+use super::{PhysicalPageAllocError, PhysicalPageAllocator, region::FreeRegions};
+use crate::{
+    BootParms, Page, PhyPageNumber,
+    fdt::{self, read_cells},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Physical memory zones.
+pub enum Zone {
+    /// Frames every DMA-capable device can address.
+    Dma32,
+    /// Any frame.
+    Normal,
+}
+impl Zone {
+    pub const COUNT: usize = 2;
+    pub const ALL: [Zone; Self::COUNT] = [Zone::Dma32, Zone::Normal];
+
+    /// Returns the zones to try, in order, for a request targeting this zone.
+    /// Normal requests may dip into DMA32 memory, but never the other way round.
+    #[must_use]
+    pub const fn fallbacks(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma32 => &[Zone::Dma32],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Where each zone ends.
+pub struct ZoneLimits {
+    /// The first frame above the DMA32 zone.
+    pub dma32_end: PhyPageNumber,
+}
+impl ZoneLimits {
+    /// The first frame above 4 GiB.
+    pub const DMA32_END: usize = (1 << 32) / Page::SIZE;
+
+    /// Computes the limits from the memory map and the device tree.
+    /// The DMA32 zone ends at 4 GiB, lowered to the tightest `dma-ranges` window and to the end of memory.
+    pub fn from_boot_parms(parms: &impl BootParms) -> Self {
+        let memory_end = parms
+            .make_memory_map_accessor()
+            .map(|(start, len, _)| usize::from(start + len))
+            .max()
+            .unwrap_or_default();
+        let dma_end = parms
+            .device_tree()
+            .and_then(|tree| tree.root())
+            .and_then(|root| dma_limit(root, root.address_cells(), &Some))
+            .unwrap_or(usize::MAX);
+        ZoneLimits {
+            dma32_end: Self::DMA32_END.min(dma_end).min(memory_end).into(),
+        }
+    }
+
+    #[must_use]
+    pub fn zone_of(&self, page: PhyPageNumber) -> Zone {
+        if page < self.dma32_end {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A `dma-ranges` window: `size` bytes of bus addresses from `child` reach `parent` on the parent bus.
+struct DmaWindow {
+    /// `None` if the child address is wider than 64 bits, as with PCI.
+    child: Option<u64>,
+    parent: u64,
+    size: u64,
+}
+
+/// Returns the windows of the `dma-ranges` of `node`, or `None` if it has none or an empty one, which maps 1:1.
+/// Windows whose parent address or size cannot be read are left out.
+fn dma_windows(
+    node: fdt::Node<'_>,
+    parent_address_cells: usize,
+) -> Option<impl Iterator<Item = DmaWindow> + '_> {
+    let ranges = node
+        .property("dma-ranges")
+        .filter(|ranges| !ranges.is_empty())?;
+    let child_len = node.address_cells() * size_of::<u32>();
+    let entry_len = child_len + (parent_address_cells + node.size_cells()) * size_of::<u32>();
+    if entry_len == 0 {
+        return None;
+    }
+    Some(ranges.chunks_exact(entry_len).filter_map(move |entry| {
+        let (mut child, mut rest) = entry.split_at(child_len);
+        let child = read_cells(&mut child, node.address_cells());
+        let parent = read_cells(&mut rest, parent_address_cells)?;
+        let size = read_cells(&mut rest, node.size_cells())?;
+        (size > 0).then_some(DmaWindow {
+            child,
+            parent,
+            size,
+        })
+    }))
+}
+
+/// Returns the first frame no `dma-ranges` window at or below `node` can reach.
+/// `to_physical` translates an address on the bus of the parent of `node` into a physical address,
+/// or returns `None` if no window of an ancestor covers it.
+fn dma_limit(
+    node: fdt::Node<'_>,
+    parent_address_cells: usize,
+    to_physical: &dyn Fn(u64) -> Option<u64>,
+) -> Option<usize> {
+    let mut limit = dma_windows(node, parent_address_cells)
+        .and_then(|windows| {
+            windows
+                .filter_map(|window| to_physical(window.parent.checked_add(window.size - 1)?))
+                .max()
+        })
+        .map(|last| {
+            usize::try_from(last.saturating_add(1) / Page::SIZE as u64).unwrap_or(usize::MAX)
+        });
+    // Children see this node's bus, reached through its windows, or 1:1 without any.
+    let translate = |address: u64| match dma_windows(node, parent_address_cells) {
+        Some(mut windows) => windows.find_map(|window| {
+            let offset = address.checked_sub(window.child?)?;
+            (offset < window.size)
+                .then_some(window.parent + offset)
+                .and_then(to_physical)
+        }),
+        None => to_physical(address),
+    };
+    for child in node.children() {
+        if let Some(child_limit) = dma_limit(child, node.address_cells(), &translate) {
+            limit = Some(limit.map_or(child_limit, |limit| limit.min(child_limit)));
+        }
+    }
+    limit
+}
+
+#[derive(Debug)]
+/// One allocator per zone, with requests falling back between zones according to [`Zone::fallbacks`].
+/// The [`PhysicalPageAllocator`] implementation serves [`Zone::Normal`] requests.
+pub struct ZonedAllocator<A: PhysicalPageAllocator> {
+    zones: [Option<A>; Zone::COUNT],
+    limits: ZoneLimits,
+}
+impl<A: PhysicalPageAllocator> ZonedAllocator<A> {
+    /// Splits `regions` at the zone limits and builds an allocator for every non-empty zone.
+    /// # Errors
+    /// Returns an error if every zone is empty or any allocator fails to build.
+    pub fn new(
+        regions: &FreeRegions,
+        limits: ZoneLimits,
+        mut make: impl FnMut(&FreeRegions) -> Result<A, PhysicalPageAllocError>,
+    ) -> Result<Self, PhysicalPageAllocError> {
+        let (low, high) = regions.split_at(limits.dma32_end);
+        let mut zones = [None, None];
+        for (zone, regions) in [(Zone::Dma32, low), (Zone::Normal, high)] {
+            if regions.span().is_some() {
+                zones[zone as usize] = Some(make(&regions)?);
+            }
+        }
+        if zones.iter().all(Option::is_none) {
+            return Err(PhysicalPageAllocError);
+        }
+        Ok(ZonedAllocator { zones, limits })
+    }
+
+    #[must_use]
+    pub fn limits(&self) -> ZoneLimits {
+        self.limits
+    }
+
+    /// Returns the allocator of `zone`, if the zone has any memory.
+    #[must_use]
+    pub fn zone(&self, zone: Zone) -> Option<&A> {
+        self.zones[zone as usize].as_ref()
+    }
+
+    /// Allocate count number of physical pages aligned to align pages from `zone` or its fallbacks.
+    /// # Errors
+    /// Returns an error if no allowed zone can satisfy the request.
+    pub fn allocate_zone(
+        &self,
+        zone: Zone,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        zone.fallbacks()
+            .iter()
+            .filter_map(|&zone| self.zone(zone))
+            .find_map(|allocator| allocator.allocate_aligned(count, align).ok())
+            .ok_or(PhysicalPageAllocError)
+    }
+
+    fn owner(&self, page: PhyPageNumber) -> &A {
+        self.zone(self.limits.zone_of(page))
+            .expect("Deallocated page belongs to an empty zone")
+    }
+}
+impl<A: PhysicalPageAllocator> PhysicalPageAllocator for ZonedAllocator<A> {
+    fn allocate_contiguous(&self, count: usize) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.allocate_zone(Zone::Normal, count, 1)
+    }
+
+    fn allocate_aligned(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhyPageNumber, PhysicalPageAllocError> {
+        self.allocate_zone(Zone::Normal, count, align)
+    }
+
+    unsafe fn deallocate(&self, page: PhyPageNumber) {
+        unsafe { self.owner(page).deallocate(page) };
+    }
+
+    unsafe fn deallocate_contiguous(&self, page: PhyPageNumber, count: usize) {
+        let end = page + count;
+        let boundary = self.limits.dma32_end;
+        if page < boundary && boundary < end {
+            let low = usize::from(boundary) - usize::from(page);
+            unsafe {
+                self.owner(page).deallocate_contiguous(page, low);
+                self.owner(boundary)
+                    .deallocate_contiguous(boundary, count - low);
+            }
+        } else {
+            unsafe { self.owner(page).deallocate_contiguous(page, count) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{Zone, ZoneLimits, ZonedAllocator};
+    use crate::{
+        MemoryMapType,
+        fdt::testing::Builder,
+        page::{
+            PhysicalPageAllocator,
+            buddy::BuddyAllocator,
+            bump::BumpAllocator,
+            testing::{HostMemory, TestBootParms, regions},
+        },
+    };
+
+    /// 8 GiB of memory from 0.
+    fn parms(device_tree: Option<Vec<u8>>) -> TestBootParms {
+        let mut parms = TestBootParms::new(&[(0, 0x20_0000, MemoryMapType::Unused)]);
+        parms.device_tree = device_tree;
+        parms
+    }
+
+    fn dma32_end(device_tree: Vec<u8>) -> usize {
+        ZoneLimits::from_boot_parms(&parms(Some(device_tree)))
+            .dma32_end
+            .into()
+    }
+
+    /// A tree with two address and size cells at the root, around the nodes `inner` adds.
+    fn tree(inner: impl FnOnce(&mut Builder)) -> Vec<u8> {
+        let mut builder = Builder::default();
+        builder
+            .begin("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2);
+        inner(&mut builder);
+        builder.end().finish()
+    }
+
+    /// A bus with one address and size cell, whose bus address 0 is 2 GiB, reachable for 1 GiB.
+    fn soc(builder: &mut Builder) -> &mut Builder {
+        builder
+            .begin("soc")
+            .prop_u32("#address-cells", 1)
+            .prop_u32("#size-cells", 1)
+            .prop_cells("dma-ranges", &[0, 0, 0x8000_0000, 0x4000_0000])
+    }
+
+    #[test]
+    fn ends_at_four_gib_or_the_end_of_memory() {
+        assert_eq!(
+            ZoneLimits::from_boot_parms(&parms(None)).dma32_end,
+            ZoneLimits::DMA32_END.into()
+        );
+        let small = TestBootParms::new(&[
+            (0x8_0000, 0x1000, MemoryMapType::Unused),
+            (0x9_0000, 0x10, MemoryMapType::Reserved),
+        ]);
+        assert_eq!(
+            ZoneLimits::from_boot_parms(&small).dma32_end,
+            0x9_0010.into()
+        );
+        // A tree without `dma-ranges` leaves the limit alone.
+        assert_eq!(dma32_end(tree(|_| {})), ZoneLimits::DMA32_END);
+    }
+
+    #[test]
+    fn dma_ranges_lower_the_limit() {
+        assert_eq!(
+            dma32_end(tree(|builder| {
+                soc(builder).end();
+            })),
+            0xC_0000
+        );
+        // An empty `dma-ranges` maps 1:1.
+        assert_eq!(
+            dma32_end(tree(|builder| {
+                builder.begin("soc").prop("dma-ranges", &[]).end();
+            })),
+            ZoneLimits::DMA32_END
+        );
+    }
+
+    #[test]
+    fn wide_child_addresses_are_read() {
+        // PCI buses have three address cells, the parent address still gives the limit.
+        let pci = tree(|builder| {
+            builder
+                .begin("pci")
+                .prop_u32("#address-cells", 3)
+                .prop_u32("#size-cells", 2)
+                .prop_cells(
+                    "dma-ranges",
+                    &[0x0200_0000, 0, 0, 0, 0x4000_0000, 0, 0x4000_0000],
+                )
+                .end();
+        });
+        assert_eq!(dma32_end(pci), 0x8_0000);
+    }
+
+    #[test]
+    fn unreadable_windows_are_skipped() {
+        let wide = tree(|builder| {
+            builder
+                .begin("bus")
+                .prop_u32("#address-cells", 1)
+                .prop_u32("#size-cells", 3)
+                .prop_cells("dma-ranges", &[0, 0, 0, 0, 0, 0x1000])
+                .end();
+        });
+        assert_eq!(dma32_end(wide), ZoneLimits::DMA32_END);
+    }
+
+    #[test]
+    fn nested_windows_translate_through_their_parents() {
+        // The PCI window ends at soc bus address 256 MiB, which is physical 2.25 GiB.
+        let nested = tree(|builder| {
+            soc(builder)
+                .begin("pci")
+                .prop_u32("#address-cells", 3)
+                .prop_u32("#size-cells", 2)
+                .prop_cells("dma-ranges", &[0x0200_0000, 0, 0, 0, 0, 0x1000_0000])
+                .end()
+                .end();
+        });
+        assert_eq!(dma32_end(nested), 0x9_0000);
+        // A window past every window of the parent reaches nothing, leaving the parent's limit.
+        let outside = tree(|builder| {
+            soc(builder)
+                .begin("dma")
+                .prop_u32("#address-cells", 1)
+                .prop_u32("#size-cells", 1)
+                .prop_cells("dma-ranges", &[0, 0x5000_0000, 0x1000_0000])
+                .end()
+                .end();
+        });
+        assert_eq!(dma32_end(outside), 0xC_0000);
+    }
+
+    #[test]
+    fn fallbacks_only_go_down() {
+        assert_eq!(Zone::Dma32.fallbacks(), [Zone::Dma32]);
+        assert_eq!(Zone::Normal.fallbacks(), [Zone::Normal, Zone::Dma32]);
+        let limits = ZoneLimits {
+            dma32_end: 16.into(),
+        };
+        assert_eq!(limits.zone_of(15.into()), Zone::Dma32);
+        assert_eq!(limits.zone_of(16.into()), Zone::Normal);
+    }
+
+    /// Frames 0 to 16 are DMA32 and 16 to 32 normal, with the metadata above them.
+    fn zoned(memory: &HostMemory) -> ZonedAllocator<BuddyAllocator<&HostMemory>> {
+        let metadata = BumpAllocator::new(&mut regions(32, 2)).unwrap();
+        let limits = ZoneLimits {
+            dma32_end: 16.into(),
+        };
+        ZonedAllocator::new(&regions(0, 32), limits, |regions| {
+            BuddyAllocator::new(memory, regions, &metadata)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn normal_requests_fall_back_to_dma32() {
+        let memory = HostMemory::new(0, 34);
+        let zoned = zoned(&memory);
+        let normal = zoned.allocate_contiguous(16).unwrap();
+        assert_eq!(usize::from(normal), 16);
+        // DMA32 requests never take normal frames.
+        unsafe { zoned.deallocate(normal + 15) };
+        let dma = zoned.allocate_zone(Zone::Dma32, 16, 1).unwrap();
+        assert_eq!(usize::from(dma), 0);
+        assert!(zoned.allocate_zone(Zone::Dma32, 1, 1).is_err());
+        assert_eq!(zoned.allocate().unwrap(), normal + 15);
+        unsafe { zoned.deallocate_contiguous(dma, 16) };
+        assert_eq!(zoned.allocate().unwrap(), dma);
+    }
+
+    #[test]
+    fn straddling_frees_return_to_each_zone() {
+        let memory = HostMemory::new(0, 34);
+        let zoned = zoned(&memory);
+        let low = zoned.allocate_zone(Zone::Dma32, 16, 1).unwrap();
+        let high = zoned.allocate_zone(Zone::Normal, 16, 1).unwrap();
+        assert_eq!((usize::from(low), usize::from(high)), (0, 16));
+        unsafe { zoned.deallocate_contiguous(12.into(), 8) };
+        for zone in Zone::ALL {
+            assert_eq!(zoned.zone(zone).unwrap().free_count(), 4);
+        }
+    }
+}
@@ -1,18 +1,80 @@
+use core::{error::Error, fmt::Display};
+
+use crate::BootParms;
 use crate::arch::Arch;
 use crate::arch::ArchImpl as _;
+use crate::sync::SpinLock;
+
+pub mod chacha;
+pub mod entropy;
+pub mod sha256;
+#[cfg(any(test, feature = "host-tools"))]
+pub mod stats;
+
+use chacha::ChaCha20Rng;
+use entropy::{EntropyPool, EntropySource, READY_BITS};
+
+const FNV_OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+/// Bytes the kernel generator hands out before it reseeds from the pool.
+pub const RESEED_INTERVAL: usize = 1 << 20;
+/// Cycle counter samples taken for timer jitter at boot.
+const JITTER_SAMPLES: usize = 4096;
+/// Bytes requested from the hardware entropy source at boot.
+const HARDWARE_BYTES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RngNotReady;
+impl Display for RngNotReady {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Random number generator has not collected enough entropy"
+        )
+    }
+}
+impl Error for RngNotReady {}
 
 #[derive(Debug)]
 /// A simple random number generator providing entropy while kernel initialization.
 /// Based on FNV-1A-128 & xorshiftr128+
+/// It is not cryptographic: the kernel's [`ChaCha20Rng`] is keyed from the [`EntropyPool`] instead.
 pub struct Rng(u128);
 
 impl Rng {
+    /// Creates a generator seeded only from `seed`, so its output is reproducible.
+    #[must_use]
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let mut rng = Rng(FNV_OFFSET);
+        rng.feed(seed);
+        rng
+    }
+
+    /// Creates a generator from a raw xorshiftr128+ state, whose low half is the first word.
+    /// The state must not be zero.
+    #[must_use]
+    pub const fn from_state(state: u128) -> Self {
+        Rng(state)
+    }
+
     pub fn feed(&mut self, value: &[u8]) {
         for &byte in value {
             self.0 ^= u128::from(byte);
             self.0 = self.0.wrapping_mul(0x100_0000_0000_0000_0000_013B);
         }
     }
+
+    /// Extracts a 256-bit key from the pool, then advances the pool so the same key is never extracted twice.
+    pub fn extract_key(&mut self) -> [u8; 32] {
+        let mut key = [0; 32];
+        for (index, half) in (0u8..).zip(key.chunks_exact_mut(size_of::<u128>())) {
+            let mut branch = Rng(self.0);
+            branch.feed(b"chacha20 key");
+            branch.feed(&[index]);
+            half.copy_from_slice(&branch.0.to_le_bytes());
+        }
+        self.feed(b"extracted");
+        key
+    }
 }
 impl Iterator for Rng {
     type Item = u64;
@@ -23,14 +85,140 @@ impl Iterator for Rng {
         x ^= x << 23;
         x ^= x >> 17;
         x ^= y;
-        self.0 = u128::from(y) & (u128::from(x.wrapping_add(y)) << 64);
+        self.0 = u128::from(y) | (u128::from(x.wrapping_add(y)) << 64);
         Some(x)
     }
 }
 impl Default for Rng {
     fn default() -> Self {
-        let mut rng = Rng(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d);
-        rng.feed(&Arch::arch_rand().to_ne_bytes());
-        rng
+        Rng::from_seed(&Arch::arch_rand().to_ne_bytes())
+    }
+}
+
+static POOL: SpinLock<EntropyPool> = SpinLock::new(EntropyPool::new());
+static KERNEL_RNG: SpinLock<Option<ChaCha20Rng>> = SpinLock::new(None);
+
+/// Mixes `bytes` from `source` into the entropy pool, crediting at most `bits` of entropy.
+/// They take effect at the next reseed.
+pub fn add_entropy(source: EntropySource, bytes: &[u8], bits: usize) {
+    POOL.lock().feed(source, bytes, bits);
+}
+
+/// Returns true once enough entropy has been credited for the kernel generator to be seeded.
+#[must_use]
+pub fn is_ready() -> bool {
+    POOL.lock().is_ready()
+}
+
+/// Gathers entropy from every boot-time source, then seeds the kernel generator if enough was credited.
+/// The boot generator is mixed in uncredited, as its inputs are easy to guess.
+pub fn collect_boot_entropy(parms: &impl BootParms, mut boot: Rng) {
+    add_entropy(EntropySource::Boot, &boot.extract_key(), 0);
+    let mut hardware = [0; HARDWARE_BYTES];
+    let bits = Arch::hardware_entropy(&mut hardware);
+    add_entropy(EntropySource::Hardware, &hardware, bits);
+    chacha::erase(&mut hardware);
+    if let Some(seed) = parms.entropy_seed() {
+        add_entropy(EntropySource::Bootloader, seed, seed.len() * 8);
+    }
+    if let Some(seed) = parms
+        .device_tree()
+        .and_then(|tree| entropy::device_tree_seed(&tree))
+    {
+        add_entropy(EntropySource::DeviceTree, seed, seed.len() * 8);
+    }
+    let (mut jitter, bits) = entropy::timer_jitter(JITTER_SAMPLES);
+    add_entropy(EntropySource::TimerJitter, &jitter, bits);
+    chacha::erase(&mut jitter);
+    let total = {
+        let pool = POOL.lock();
+        pool.log_credits();
+        pool.total()
+    };
+    if reseed().is_err() {
+        log::warn!(
+            "Random number generator is not ready: {total} of {READY_BITS} bits of entropy credited"
+        );
+    }
+}
+
+/// Rekeys the kernel generator from the current pool, seeding it if needed.
+/// # Errors
+/// Returns an error if the pool has not collected enough entropy yet.
+pub fn reseed() -> Result<(), RngNotReady> {
+    rekey(&mut KERNEL_RNG.lock(), &mut POOL.lock())
+}
+
+fn rekey(rng: &mut Option<ChaCha20Rng>, pool: &mut EntropyPool) -> Result<(), RngNotReady> {
+    if !pool.is_ready() {
+        return Err(RngNotReady);
+    }
+    let key = pool.extract_key();
+    match rng.as_mut() {
+        Some(rng) => rng.reseed(key),
+        None => *rng = Some(ChaCha20Rng::new(key)),
+    }
+    Ok(())
+}
+
+/// Fills `dest` with cryptographically secure random bytes.
+/// # Errors
+/// Returns an error if the pool has not collected enough entropy yet.
+pub fn fill_bytes(dest: &mut [u8]) -> Result<(), RngNotReady> {
+    let mut rng = KERNEL_RNG.lock();
+    if rng
+        .as_ref()
+        .is_none_or(|rng| rng.since_reseed() >= RESEED_INTERVAL)
+    {
+        rekey(&mut rng, &mut POOL.lock())?;
+    }
+    rng.as_mut().ok_or(RngNotReady)?.fill_bytes(dest);
+    Ok(())
+}
+
+/// Returns a cryptographically secure random word.
+/// # Errors
+/// Returns an error if the pool has not collected enough entropy yet.
+pub fn next_u64() -> Result<u64, RngNotReady> {
+    let mut bytes = [0; size_of::<u64>()];
+    fill_bytes(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+
+    /// Outputs of the reference xorshiftr128+ from the state `(s[0], s[1])`.
+    const VECTORS: [((u64, u64), [u64; 4]); 2] = [
+        (
+            (1, 2),
+            [
+                0x0000_0000_0080_0043,
+                0x0000_0000_0180_00c7,
+                0x0000_4000_0000_1049,
+                0x0001_4000_0600_5259,
+            ],
+        ),
+        (
+            (0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210),
+            [
+                0x4c3b_736a_c03f_40ea,
+                0xf9ff_e7f1_8087_ffc0,
+                0x0f9b_570f_4f55_2589,
+                0xebf0_aae8_204d_1ff4,
+            ],
+        ),
+    ];
+
+    #[test]
+    fn known_answers() {
+        for ((low, high), expected) in VECTORS {
+            let rng = Rng::from_state(u128::from(low) | (u128::from(high) << 64));
+            assert!(
+                rng.take(expected.len()).eq(expected),
+                "state ({low:#x}, {high:#x})"
+            );
+        }
     }
 }
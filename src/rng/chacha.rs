@@ -0,0 +1,183 @@
+use core::ptr;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const BLOCK: usize = 64;
+/// Blocks generated per refill; the first 32 bytes become the next key.
+const BLOCKS: usize = 4;
+const KEY: usize = 32;
+const BUFFER: usize = BLOCKS * BLOCK - KEY;
+
+/// Computes one `ChaCha20` block (RFC 8439) into `out`.
+pub fn block(key: &[u32; 8], counter: u32, nonce: [u32; 3], out: &mut [u8; BLOCK]) {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(&nonce);
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for ((word, initial), bytes) in state
+        .iter()
+        .zip(initial)
+        .zip(out.chunks_exact_mut(size_of::<u32>()))
+    {
+        bytes.copy_from_slice(&word.wrapping_add(initial).to_le_bytes());
+    }
+    erase(&mut state);
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Overwrites secrets in a way the compiler cannot drop.
+pub(super) fn erase<T: Copy + Default>(values: &mut [T]) {
+    for value in values {
+        unsafe { ptr::write_volatile(value, T::default()) };
+    }
+}
+
+#[derive(Debug)]
+/// A cryptographically secure generator running `ChaCha20` with fast key erasure:
+/// every refill replaces the key with fresh keystream, and output bytes are wiped once handed out,
+/// so a later compromise of the state reveals nothing about earlier output.
+pub struct ChaCha20Rng {
+    key: [u32; 8],
+    buffer: [u8; BUFFER],
+    position: usize,
+    since_reseed: usize,
+}
+impl ChaCha20Rng {
+    #[must_use]
+    pub fn new(key: [u8; KEY]) -> Self {
+        let mut rng = ChaCha20Rng {
+            key: [0; _],
+            buffer: [0; _],
+            position: BUFFER,
+            since_reseed: 0,
+        };
+        rng.rekey(key);
+        rng
+    }
+
+    /// Mixes `key` into the current key, then refills so the old buffer is gone.
+    pub fn reseed(&mut self, key: [u8; KEY]) {
+        let mut mixed = [0u8; KEY];
+        for ((mixed, old), new) in mixed
+            .as_chunks_mut::<4>()
+            .0
+            .iter_mut()
+            .zip(self.key)
+            .zip(key.as_chunks::<4>().0)
+        {
+            *mixed = (old ^ u32::from_le_bytes(*new)).to_le_bytes();
+        }
+        self.rekey(mixed);
+        erase(&mut mixed);
+        self.since_reseed = 0;
+    }
+
+    /// Returns the number of bytes generated since the last reseed.
+    #[must_use]
+    pub fn since_reseed(&self) -> usize {
+        self.since_reseed
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut done = 0;
+        while done < dest.len() {
+            if self.position == BUFFER {
+                self.refill();
+            }
+            let count = (dest.len() - done).min(BUFFER - self.position);
+            let source = &mut self.buffer[self.position..self.position + count];
+            dest[done..done + count].copy_from_slice(source);
+            erase(source);
+            self.position += count;
+            done += count;
+        }
+        self.since_reseed += dest.len();
+    }
+
+    fn rekey(&mut self, key: [u8; KEY]) {
+        for (word, bytes) in self.key.iter_mut().zip(key.as_chunks::<4>().0) {
+            *word = u32::from_le_bytes(*bytes);
+        }
+        self.refill();
+    }
+
+    /// Generates fresh keystream, taking its first bytes as the next key and keeping the rest as output.
+    fn refill(&mut self) {
+        let mut stream = [0u8; BLOCKS * BLOCK];
+        for (counter, out) in (0u32..).zip(stream.as_chunks_mut::<BLOCK>().0) {
+            block(&self.key, counter, [0; 3], out);
+        }
+        for (word, bytes) in self.key.iter_mut().zip(stream.as_chunks::<4>().0) {
+            *word = u32::from_le_bytes(*bytes);
+        }
+        self.buffer.copy_from_slice(&stream[KEY..]);
+        erase(&mut stream);
+        self.position = 0;
+    }
+}
+impl Iterator for ChaCha20Rng {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = [0; size_of::<u64>()];
+        self.fill_bytes(&mut bytes);
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+impl Drop for ChaCha20Rng {
+    fn drop(&mut self) {
+        erase(&mut self.key);
+        erase(&mut self.buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::block;
+
+    /// The block function test vector of RFC 8439, section 2.3.2.
+    #[test]
+    fn known_answer() {
+        // The bytes 0 to 31, as little-endian words.
+        let key = [
+            0x0302_0100,
+            0x0706_0504,
+            0x0b0a_0908,
+            0x0f0e_0d0c,
+            0x1312_1110,
+            0x1716_1514,
+            0x1b1a_1918,
+            0x1f1e_1d1c,
+        ];
+        let mut out = [0; 64];
+        block(&key, 1, [0x0900_0000, 0x4a00_0000, 0], &mut out);
+        assert_eq!(
+            out[..16],
+            [
+                0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+                0x71, 0xc4,
+            ]
+        );
+    }
+}
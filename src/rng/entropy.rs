@@ -0,0 +1,248 @@
+use core::hint;
+
+use crate::{Arch, ArchImpl, fdt::DeviceTree};
+
+use super::sha256::{self, Sha256};
+
+/// Bits of credited entropy the pool needs before the kernel generator is seeded from it.
+/// The pool is conditioned with SHA-256, so it cannot hold more than this.
+pub const READY_BITS: usize = 256;
+/// Jitter samples that must pass the health tests for a bit of entropy to be credited.
+const SAMPLES_PER_BIT: usize = 64;
+/// Identical samples in a row that fail the repetition count test (SP 800-90B 4.4.1),
+/// assuming at least one bit of min-entropy per sample and a false alarm rate of 2^-20.
+const REPETITION_CUTOFF: usize = 21;
+/// Samples in each window of the adaptive proportion test (SP 800-90B 4.4.2).
+const PROPORTION_WINDOW: usize = 512;
+/// Repeats of a window's first sample within the window that fail the adaptive proportion test,
+/// for the same entropy assumption and false alarm rate.
+const PROPORTION_CUTOFF: usize = 410;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+/// Where entropy fed to the pool comes from.
+pub enum EntropySource {
+    /// Boot-time noise such as timestamps and addresses, never credited.
+    Boot,
+    /// The hardware entropy source of the CPU, such as the RISC-V Zkr `seed` CSR.
+    Hardware,
+    /// Jitter measured on the cycle counter.
+    TimerJitter,
+    /// A seed handed over by the bootloader.
+    Bootloader,
+    /// The `rng-seed` property of the device tree's `/chosen` node.
+    DeviceTree,
+}
+impl EntropySource {
+    pub const COUNT: usize = 5;
+    pub const ALL: [EntropySource; Self::COUNT] = [
+        EntropySource::Boot,
+        EntropySource::Hardware,
+        EntropySource::TimerJitter,
+        EntropySource::Bootloader,
+        EntropySource::DeviceTree,
+    ];
+}
+
+#[derive(Debug)]
+/// Hashes everything fed to it with SHA-256 and keeps an estimate of the entropy credited by each source.
+pub struct EntropyPool {
+    hash: Sha256,
+    credits: [usize; EntropySource::COUNT],
+}
+impl EntropyPool {
+    #[must_use]
+    pub const fn new() -> Self {
+        EntropyPool {
+            hash: Sha256::new(),
+            credits: [0; _],
+        }
+    }
+
+    /// Mixes `bytes` from `source` in, crediting at most `bits` of entropy, and never more bits than were fed.
+    pub fn feed(&mut self, source: EntropySource, bytes: &[u8], bits: usize) {
+        // Framing each input keeps different sequences of inputs from hashing the same.
+        self.hash.update(&[source as u8]);
+        self.hash.update(&bytes.len().to_le_bytes());
+        self.hash.update(bytes);
+        self.credits[source as usize] += bits.min(bytes.len() * 8);
+    }
+
+    /// Returns the bits of entropy credited to `source`.
+    #[must_use]
+    pub fn credited(&self, source: EntropySource) -> usize {
+        self.credits[source as usize]
+    }
+
+    /// Returns the bits of entropy credited to all sources together.
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.credits.iter().sum()
+    }
+
+    /// Returns true once enough entropy has been credited to key a cryptographic generator.
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.total() >= READY_BITS
+    }
+
+    /// Extracts a 256-bit key from the pool, then restarts the pool from a different digest of its state,
+    /// so the key cannot be recovered from the pool and the same key is never extracted twice.
+    pub fn extract_key(&mut self) -> [u8; 32] {
+        let mut state = self.hash.clone().finalize();
+        let key = sha256::digest(&[b"key", &state]);
+        self.hash = Sha256::new();
+        self.hash.update(b"pool");
+        self.hash.update(&state);
+        super::chacha::erase(&mut state);
+        key
+    }
+
+    pub fn log_credits(&self) {
+        for source in EntropySource::ALL {
+            log::info!("Entropy{source:?}: {} bits", self.credited(source));
+        }
+    }
+}
+impl Default for EntropyPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+/// The continuous health tests of SP 800-90B run on raw noise samples,
+/// which catch a noise source that got stuck or lost most of its entropy.
+struct HealthTests {
+    previous: Option<usize>,
+    repetitions: usize,
+    reference: usize,
+    matches: usize,
+    windowed: usize,
+    failed: bool,
+}
+impl HealthTests {
+    const fn new() -> Self {
+        HealthTests {
+            previous: None,
+            repetitions: 0,
+            reference: 0,
+            matches: 0,
+            windowed: 0,
+            failed: false,
+        }
+    }
+
+    /// Runs both tests on `sample`, returning false once either has failed.
+    fn check(&mut self, sample: usize) -> bool {
+        if self.previous == Some(sample) {
+            self.repetitions += 1;
+        } else {
+            self.repetitions = 1;
+        }
+        self.previous = Some(sample);
+        if self.windowed == 0 {
+            self.reference = sample;
+            self.matches = 0;
+        }
+        if sample == self.reference {
+            self.matches += 1;
+        }
+        self.windowed = (self.windowed + 1) % PROPORTION_WINDOW;
+        self.failed |= self.repetitions >= REPETITION_CUTOFF || self.matches >= PROPORTION_CUTOFF;
+        !self.failed
+    }
+}
+
+/// Samples the cycle counter `samples` times around a little memory traffic.
+/// Returns the timings condensed into a key and the bits of entropy credited to them:
+/// a bit for every [`SAMPLES_PER_BIT`] timings, or nothing once the timings fail a health test.
+#[must_use]
+pub fn timer_jitter(samples: usize) -> ([u8; 32], usize) {
+    let mut timings = Sha256::new();
+    let mut health = HealthTests::new();
+    let mut scratch = [0usize; 64];
+    let mut previous = Arch::cycle_counter();
+    for i in 0..samples {
+        // Walking a buffer makes the timing depend on caches and pipelines.
+        for (j, slot) in scratch.iter_mut().enumerate() {
+            *slot = hint::black_box(slot.wrapping_mul(31).wrapping_add(i ^ j));
+        }
+        let now = Arch::cycle_counter();
+        let delta = now.wrapping_sub(previous);
+        timings.update(&delta.to_ne_bytes());
+        health.check(delta);
+        previous = now;
+    }
+    if health.failed {
+        log::warn!("Timer jitter failed its health tests, crediting none of it");
+        return (timings.finalize(), 0);
+    }
+    (timings.finalize(), samples / SAMPLES_PER_BIT)
+}
+
+/// Returns the `rng-seed` property of the device tree's `/chosen` node, if any.
+#[must_use]
+pub fn device_tree_seed<'a>(tree: &DeviceTree<'a>) -> Option<&'a [u8]> {
+    tree.find("/chosen")?
+        .property("rng-seed")
+        .filter(|seed| !seed.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EntropyPool, EntropySource, HealthTests, PROPORTION_CUTOFF, PROPORTION_WINDOW,
+        REPETITION_CUTOFF,
+    };
+
+    #[test]
+    fn health_tests_pass_varied_samples() {
+        let mut health = HealthTests::new();
+        assert!((0..4 * PROPORTION_WINDOW).all(|i| health.check(i % 7)));
+    }
+
+    #[test]
+    fn repetition_count_catches_a_stuck_source() {
+        let mut health = HealthTests::new();
+        assert!((1..REPETITION_CUTOFF).all(|_| health.check(3)));
+        assert!(!health.check(3));
+        assert!(!health.check(4), "a failure is not forgotten");
+    }
+
+    #[test]
+    fn adaptive_proportion_catches_a_biased_source() {
+        let mut health = HealthTests::new();
+        // Runs too short for the repetition count test, but nine in ten samples repeat the window's first.
+        let passed = (0..PROPORTION_WINDOW)
+            .take_while(|&i| health.check(if i % 10 == 9 { i } else { 0 }))
+            .count();
+        assert_eq!(
+            passed,
+            PROPORTION_CUTOFF / 9 * 10 + PROPORTION_CUTOFF % 9 - 1
+        );
+    }
+
+    #[test]
+    fn pool_credits_and_extracts() {
+        let mut pool = EntropyPool::new();
+        pool.feed(EntropySource::Boot, &[1; 64], 0);
+        pool.feed(EntropySource::Hardware, &[2; 16], 1000);
+        assert_eq!(pool.credited(EntropySource::Hardware), 128);
+        assert!(!pool.is_ready());
+        pool.feed(EntropySource::Bootloader, &[3; 16], 128);
+        assert!(pool.is_ready());
+        let first = pool.extract_key();
+        assert_ne!(first, pool.extract_key());
+    }
+
+    #[test]
+    fn inputs_are_framed() {
+        let mut split = EntropyPool::new();
+        split.feed(EntropySource::Boot, b"ab", 0);
+        split.feed(EntropySource::Boot, b"c", 0);
+        let mut joined = EntropyPool::new();
+        joined.feed(EntropySource::Boot, b"abc", 0);
+        assert_ne!(split.extract_key(), joined.extract_key());
+    }
+}
@@ -0,0 +1,234 @@
+use super::chacha::erase;
+
+const BLOCK: usize = 64;
+/// Bytes in a digest.
+pub const DIGEST: usize = 32;
+
+const INITIAL: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+const ROUNDS: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+#[derive(Debug, Clone)]
+/// An incremental SHA-256 (FIPS 180-4) hash, wiped when dropped.
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; BLOCK],
+    /// Bytes hashed so far, including those still in `buffer`.
+    len: usize,
+}
+impl Sha256 {
+    #[must_use]
+    pub const fn new() -> Self {
+        Sha256 {
+            state: INITIAL,
+            buffer: [0; _],
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        let mut filled = self.len % BLOCK;
+        self.len = self.len.wrapping_add(bytes.len());
+        while !bytes.is_empty() {
+            let count = bytes.len().min(BLOCK - filled);
+            self.buffer[filled..filled + count].copy_from_slice(&bytes[..count]);
+            bytes = &bytes[count..];
+            filled += count;
+            if filled == BLOCK {
+                compress(&mut self.state, &self.buffer);
+                filled = 0;
+            }
+        }
+    }
+
+    /// Pads the message and returns its digest.
+    #[must_use]
+    pub fn finalize(mut self) -> [u8; DIGEST] {
+        let bits = (self.len as u64).wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.len % BLOCK != BLOCK - size_of::<u64>() {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0; DIGEST];
+        for (bytes, word) in digest.as_chunks_mut::<4>().0.iter_mut().zip(self.state) {
+            *bytes = word.to_be_bytes();
+        }
+        digest
+    }
+}
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        erase(&mut self.state);
+        erase(&mut self.buffer);
+    }
+}
+
+/// Returns the SHA-256 digest of the concatenation of `parts`.
+#[must_use]
+pub fn digest(parts: &[&[u8]]) -> [u8; DIGEST] {
+    let mut hash = Sha256::new();
+    for part in parts {
+        hash.update(part);
+    }
+    hash.finalize()
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; BLOCK]) {
+    let mut schedule = [0u32; 64];
+    for (word, bytes) in schedule.iter_mut().zip(block.as_chunks::<4>().0) {
+        *word = u32::from_be_bytes(*bytes);
+    }
+    for i in 16..64 {
+        let s0 = schedule[i - 15].rotate_right(7)
+            ^ schedule[i - 15].rotate_right(18)
+            ^ (schedule[i - 15] >> 3);
+        let s1 = schedule[i - 2].rotate_right(17)
+            ^ schedule[i - 2].rotate_right(19)
+            ^ (schedule[i - 2] >> 10);
+        schedule[i] = schedule[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(schedule[i - 7])
+            .wrapping_add(s1);
+    }
+    // The working variables a to h, shifted along by one each round.
+    let mut work = *state;
+    for (round, word) in ROUNDS.iter().zip(schedule) {
+        let (a, e) = (work[0], work[4]);
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & work[5]) ^ (!e & work[6]);
+        let t1 = work[7]
+            .wrapping_add(s1)
+            .wrapping_add(choice)
+            .wrapping_add(*round)
+            .wrapping_add(word);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & work[1]) ^ (a & work[2]) ^ (work[1] & work[2]);
+        work.rotate_right(1);
+        work[4] = work[4].wrapping_add(t1);
+        work[0] = t1.wrapping_add(s0.wrapping_add(majority));
+    }
+    for (word, value) in state.iter_mut().zip(work) {
+        *word = word.wrapping_add(value);
+    }
+    erase(&mut work);
+    erase(&mut schedule);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sha256, digest};
+
+    fn hex(digest: [u8; 32]) -> alloc::string::String {
+        digest
+            .iter()
+            .map(|byte| alloc::format!("{byte:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(
+            hex(digest(&[])),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(digest(&[b"abc"])),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(digest(&[
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ])),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn incremental_updates_match() {
+        let message = [0x5a; 200];
+        let mut hash = Sha256::new();
+        for chunk in message.chunks(7) {
+            hash.update(chunk);
+        }
+        assert_eq!(hash.finalize(), digest(&[&message]));
+    }
+}
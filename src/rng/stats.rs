@@ -0,0 +1,209 @@
+use core::{error::Error, fmt::Display};
+
+/// Words per statistics block; larger blocks would overflow the integer statistics.
+pub const BLOCK_WORDS: usize = 1 << 20;
+/// Squared bound, in hundredths, on how many standard deviations a statistic may stray.
+/// Five standard deviations fail a healthy generator about once in two million tests.
+const Z_SQUARED_CENTI: u128 = 2500;
+/// Degrees of freedom of the byte chi-square test.
+const BYTE_FREEDOM: i128 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A failed generator check.
+pub enum StatFailure {
+    /// Ones and zeros are not balanced.
+    Monobit,
+    /// Runs of equal bits are too long or too short.
+    Runs,
+    /// Byte values are not uniformly distributed.
+    ChiSquare,
+}
+impl Display for StatFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StatFailure::Monobit => write!(f, "Monobit test failed"),
+            StatFailure::Runs => write!(f, "Runs test failed"),
+            StatFailure::ChiSquare => write!(f, "Byte chi-square test failed"),
+        }
+    }
+}
+impl Error for StatFailure {}
+
+#[derive(Debug, Clone)]
+/// Counts gathered over a block of generator output, bits taken least significant first.
+pub struct StatCounts {
+    words: usize,
+    ones: u64,
+    /// Changes between adjacent bits.
+    flips: u64,
+    last: Option<bool>,
+    bytes: [u64; 256],
+}
+impl StatCounts {
+    #[must_use]
+    pub const fn new() -> Self {
+        StatCounts {
+            words: 0,
+            ones: 0,
+            flips: 0,
+            last: None,
+            bytes: [0; _],
+        }
+    }
+
+    /// Adds a word to the block.
+    /// # Panics
+    /// Panics if the block already holds [`BLOCK_WORDS`] words.
+    pub fn add(&mut self, word: u64) {
+        assert!(self.words < BLOCK_WORDS, "Statistics block is full");
+        self.words += 1;
+        self.ones += u64::from(word.count_ones());
+        self.flips += u64::from(((word ^ (word >> 1)) & (u64::MAX >> 1)).count_ones());
+        if let Some(last) = self.last {
+            self.flips += u64::from(last != (word & 1 != 0));
+        }
+        self.last = Some(word >> 63 != 0);
+        for byte in word.to_le_bytes() {
+            self.bytes[usize::from(byte)] += 1;
+        }
+    }
+
+    /// Checks the proportion of ones against a fair coin.
+    #[must_use]
+    pub fn monobit(&self) -> bool {
+        let bits = self.bits();
+        let excess = (2 * i128::from(self.ones) - bits).unsigned_abs();
+        excess * excess * 100 <= Z_SQUARED_CENTI * bits.unsigned_abs()
+    }
+
+    /// Checks the number of runs of equal bits with the Wald-Wolfowitz test.
+    #[must_use]
+    pub fn runs(&self) -> bool {
+        let bits = self.bits();
+        let ones = i128::from(self.ones);
+        let pairs = 2 * ones * (bits - ones);
+        if pairs <= bits {
+            return false;
+        }
+        // Scaled by `bits`, the observed runs minus their expectation `pairs / bits + 1`.
+        let deviation = (bits * (i128::from(self.flips) + 1) - pairs - bits).unsigned_abs();
+        // The variance, scaled by `bits² (bits - 1)`.
+        let variance = (pairs * (pairs - bits)).unsigned_abs();
+        // A generator far enough off to overflow fails anyway.
+        deviation
+            .checked_mul(deviation)
+            .and_then(|square| square.checked_mul((bits - 1).unsigned_abs() * 100))
+            .is_some_and(|scaled| scaled <= Z_SQUARED_CENTI * variance)
+    }
+
+    /// Checks the distribution of byte values, approximating chi-square by a normal distribution.
+    #[must_use]
+    pub fn chi_square(&self) -> bool {
+        let count = self.bits() / 8;
+        let squares: i128 = self
+            .bytes
+            .iter()
+            .map(|&observed| i128::from(observed) * i128::from(observed))
+            .sum();
+        // The statistic minus its mean, both scaled by `count`.
+        let deviation = (256 * squares - count * count - BYTE_FREEDOM * count).unsigned_abs();
+        deviation * deviation * 100
+            <= Z_SQUARED_CENTI * (2 * BYTE_FREEDOM * count * count).unsigned_abs()
+    }
+
+    /// Runs every test on the block.
+    /// # Errors
+    /// Returns the first test that failed.
+    pub fn check(&self) -> Result<(), StatFailure> {
+        if !self.monobit() {
+            return Err(StatFailure::Monobit);
+        }
+        if !self.runs() {
+            return Err(StatFailure::Runs);
+        }
+        if !self.chi_square() {
+            return Err(StatFailure::ChiSquare);
+        }
+        Ok(())
+    }
+
+    fn bits(&self) -> i128 {
+        i128::try_from(self.words * u64::BITS as usize).unwrap_or(i128::MAX)
+    }
+}
+impl Default for StatCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the statistical tests on `blocks` blocks of [`BLOCK_WORDS`] words from `words`.
+/// # Errors
+/// Returns the first test that failed.
+/// A block cut short by `words` running dry is tested as it is, and fails if empty.
+pub fn check(mut words: impl Iterator<Item = u64>, blocks: usize) -> Result<(), StatFailure> {
+    for _ in 0..blocks {
+        let mut counts = StatCounts::new();
+        for word in words.by_ref().take(BLOCK_WORDS) {
+            counts.add(word);
+        }
+        counts.check()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatCounts, StatFailure, check};
+    use crate::rng::{Rng, chacha::ChaCha20Rng};
+
+    /// Words per test block, enough for every test to tell good output from bad.
+    const WORDS: usize = 1 << 12;
+
+    fn counts(words: impl Iterator<Item = u64>) -> StatCounts {
+        let mut counts = StatCounts::new();
+        for word in words.take(WORDS) {
+            counts.add(word);
+        }
+        counts
+    }
+
+    #[test]
+    fn generators_pass() {
+        assert_eq!(counts(Rng::from_seed(b"stats")).check(), Ok(()));
+        assert_eq!(counts(ChaCha20Rng::new([0x5a; 32])).check(), Ok(()));
+    }
+
+    #[test]
+    fn zeros_fail_every_test() {
+        let zeros = counts(core::iter::repeat(0));
+        assert!(!zeros.monobit());
+        assert!(!zeros.runs());
+        assert!(!zeros.chi_square());
+        assert_eq!(zeros.check(), Err(StatFailure::Monobit));
+    }
+
+    #[test]
+    fn alternating_bits_fail_the_runs_test() {
+        let alternating = counts(core::iter::repeat(0x5555_5555_5555_5555));
+        assert!(alternating.monobit());
+        assert!(!alternating.runs());
+        assert_eq!(alternating.check(), Err(StatFailure::Runs));
+    }
+
+    #[test]
+    fn a_constant_word_fails_the_byte_test() {
+        // 32 ones and 32 flips, counting the one into the next copy, as a fair coin would give.
+        let constant = counts(core::iter::repeat(0xa648_a7dd_0683_9eb9));
+        assert!(constant.monobit());
+        assert!(constant.runs());
+        assert!(!constant.chi_square());
+        assert_eq!(constant.check(), Err(StatFailure::ChiSquare));
+    }
+
+    #[test]
+    fn short_streams_fail() {
+        assert!(check(core::iter::empty(), 1).is_err());
+        assert_eq!(check(core::iter::empty(), 0), Ok(()));
+    }
+}
@@ -0,0 +1,483 @@
+use alloc::boxed::Box;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    marker::PhantomData,
+    ops::Deref,
+    ptr::NonNull,
+};
+
+use crate::{
+    Page, PhyPageNumber,
+    object::{CapabilityNode, Endpoint, Thread},
+    page::{
+        PhysicalPageAllocator, PhysicalPageDirectMap,
+        descriptor::PageKind,
+        stats::{MEMORY_STATS, MemoryCounter},
+    },
+    sync::SpinLock,
+};
+
+/// The most pages a single slab may span.
+pub const MAX_SLAB_PAGES: usize = 16;
+/// The fewest objects a slab should hold, unless that would exceed [`MAX_SLAB_PAGES`].
+const MIN_OBJECTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Counters of a single slab cache.
+pub struct SlabStats {
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_total: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+#[derive(Debug)]
+/// Header kept at the start of every slab.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+#[derive(Debug)]
+/// Link kept in every free object.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+#[derive(Debug)]
+struct SlabState {
+    /// Slabs with at least one free object.
+    partial: Option<NonNull<Slab>>,
+    /// Completely free slabs in `partial`, at most one is kept.
+    empty: usize,
+    stats: SlabStats,
+}
+unsafe impl Send for SlabState {}
+
+#[derive(Debug)]
+/// A cache of equally sized objects, carved out of slabs of whole pages.
+/// Use it through [`Allocator`], e.g. `Box::new_in(value, &cache)`.
+pub struct SlabCache<M: PhysicalPageDirectMap, A: PhysicalPageAllocator> {
+    name: &'static str,
+    map: M,
+    allocator: A,
+    align: usize,
+    stride: usize,
+    offset: usize,
+    pages: usize,
+    capacity: usize,
+    state: SpinLock<SlabState>,
+}
+impl<M: PhysicalPageDirectMap, A: PhysicalPageAllocator> SlabCache<M, A> {
+    /// Creates an empty cache of objects fitting `layout`.
+    /// # Panics
+    /// Panics if a single object does not fit in a slab.
+    pub fn new(name: &'static str, layout: Layout, map: M, allocator: A) -> Self {
+        let align = layout.align().max(align_of::<FreeObject>());
+        assert!(
+            align <= Page::SIZE,
+            "Slab objects cannot be aligned beyond a page"
+        );
+        let stride = layout
+            .size()
+            .max(size_of::<FreeObject>())
+            .next_multiple_of(align);
+        let offset = size_of::<Slab>().next_multiple_of(align);
+        let capacity = |pages: usize| (pages * Page::SIZE).saturating_sub(offset) / stride;
+        let pages = (0..=MAX_SLAB_PAGES.ilog2())
+            .map(|order| 1 << order)
+            .find(|&pages| capacity(pages) >= MIN_OBJECTS)
+            .unwrap_or(MAX_SLAB_PAGES);
+        assert!(
+            capacity(pages) > 0,
+            "Slab objects of {} bytes do not fit in a slab",
+            layout.size()
+        );
+        SlabCache {
+            name,
+            map,
+            allocator,
+            align,
+            stride,
+            offset,
+            pages,
+            capacity: capacity(pages),
+            state: SpinLock::new(SlabState {
+                partial: None,
+                empty: 0,
+                stats: SlabStats::default(),
+            }),
+        }
+    }
+
+    /// Creates an empty cache of `T` objects.
+    pub fn of<T>(name: &'static str, map: M, allocator: A) -> Self {
+        Self::new(name, Layout::new::<T>(), map, allocator)
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the size of every object, including padding.
+    #[must_use]
+    pub fn object_size(&self) -> usize {
+        self.stride
+    }
+
+    #[must_use]
+    pub fn pages_per_slab(&self) -> usize {
+        self.pages
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.state.lock().stats
+    }
+
+    /// Logs the counters in the style of `/proc/slabinfo`.
+    pub fn log_stats(&self) {
+        let stats = self.stats();
+        log::info!(
+            "Slab {}: {} of {} objects of {} bytes in use, {} slabs of {} pages",
+            self.name,
+            stats.objects_in_use,
+            stats.objects_total,
+            self.stride,
+            stats.slabs,
+            self.pages
+        );
+    }
+
+    /// Returns every completely free slab to the physical allocator.
+    pub fn reclaim(&self) {
+        let mut state = self.state.lock();
+        let mut slab = state.partial;
+        while let Some(current) = slab {
+            slab = unsafe { (*current.as_ptr()).next };
+            if unsafe { (*current.as_ptr()).in_use } == 0 {
+                self.release(&mut state, current);
+            }
+        }
+    }
+
+    /// Takes a fresh slab from the physical allocator and adds it to the partial list.
+    fn grow(&self, state: &mut SlabState) -> Result<(), AllocError> {
+        let ppn = self
+            .allocator
+            .allocate_aligned(self.pages, self.pages)
+            .map_err(|_| AllocError)?;
+        self.allocator.retype(ppn, self.pages, PageKind::Kernel);
+        let base = self.map.phy_to_virt(ppn).cast::<u8>();
+        let mut free = None;
+        for index in (0..self.capacity).rev() {
+            let object = unsafe { base.add(self.offset + index * self.stride) }.cast();
+            unsafe { object.write(FreeObject { next: free }) };
+            free = Some(object);
+        }
+        let slab = base.cast::<Slab>();
+        unsafe {
+            slab.write(Slab {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+            });
+        }
+        push(state, slab);
+        state.empty += 1;
+        state.stats.slabs += 1;
+        state.stats.objects_total += self.capacity;
+        MEMORY_STATS.add(MemoryCounter::Slab, self.pages);
+        Ok(())
+    }
+
+    /// Unlinks a completely free slab and returns its pages.
+    fn release(&self, state: &mut SlabState, slab: NonNull<Slab>) {
+        unlink(state, slab);
+        state.empty -= 1;
+        state.stats.slabs -= 1;
+        state.stats.objects_total -= self.capacity;
+        MEMORY_STATS.sub(MemoryCounter::Slab, self.pages);
+        let ppn = self.slab_of(slab.cast());
+        unsafe { self.allocator.deallocate_contiguous(ppn, self.pages) };
+    }
+
+    /// Returns the first page of the slab holding `ptr`.
+    fn slab_of(&self, ptr: NonNull<u8>) -> PhyPageNumber {
+        let ppn = self
+            .map
+            .virt_to_phy(ptr.as_ptr())
+            .expect("Slab object outside the direct map");
+        PhyPageNumber::from(usize::from(ppn) & !(self.pages - 1))
+    }
+}
+unsafe impl<M: PhysicalPageDirectMap, A: PhysicalPageAllocator> Allocator for SlabCache<M, A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.stride || layout.align() > self.align {
+            return Err(AllocError);
+        }
+        let mut state = self.state.lock();
+        if state.partial.is_none() && self.grow(&mut state).is_err() {
+            state.stats.failures += 1;
+            return Err(AllocError);
+        }
+        let slab = state
+            .partial
+            .expect("partial slab list is empty after growing");
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let object = slab_ref
+            .free
+            .expect("slab on the partial list has no free object");
+        slab_ref.free = unsafe { object.as_ref() }.next;
+        if slab_ref.in_use == 0 {
+            state.empty -= 1;
+        }
+        slab_ref.in_use += 1;
+        if slab_ref.free.is_none() {
+            unlink(&mut state, slab);
+        }
+        state.stats.allocations += 1;
+        state.stats.objects_in_use += 1;
+        Ok(NonNull::slice_from_raw_parts(object.cast(), self.stride))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        let slab = self.map.phy_to_virt(self.slab_of(ptr)).cast::<Slab>();
+        let mut state = self.state.lock();
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let object = ptr.cast::<FreeObject>();
+        unsafe {
+            object.write(FreeObject {
+                next: slab_ref.free,
+            });
+        }
+        if slab_ref.free.is_none() {
+            push(&mut state, slab);
+        }
+        slab_ref.free = Some(object);
+        slab_ref.in_use -= 1;
+        state.stats.frees += 1;
+        state.stats.objects_in_use -= 1;
+        if slab_ref.in_use == 0 {
+            state.empty += 1;
+            if state.empty > 1 {
+                self.release(&mut state, slab);
+            }
+        }
+    }
+}
+impl<M: PhysicalPageDirectMap, A: PhysicalPageAllocator> Drop for SlabCache<M, A> {
+    fn drop(&mut self) {
+        self.reclaim();
+        assert_eq!(
+            self.state.lock().stats.objects_in_use,
+            0,
+            "Slab cache {} dropped with live objects",
+            self.name
+        );
+    }
+}
+
+#[derive(Debug)]
+/// A [`SlabCache`] of `T` objects only.
+pub struct ObjectCache<T, M: PhysicalPageDirectMap, A: PhysicalPageAllocator> {
+    cache: SlabCache<M, A>,
+    _object: PhantomData<fn() -> T>,
+}
+impl<T, M: PhysicalPageDirectMap, A: PhysicalPageAllocator> ObjectCache<T, M, A> {
+    pub fn new(name: &'static str, map: M, allocator: A) -> Self {
+        ObjectCache {
+            cache: SlabCache::of::<T>(name, map, allocator),
+            _object: PhantomData,
+        }
+    }
+
+    /// Moves `value` into a fresh object of the cache.
+    /// # Errors
+    /// Returns an error if no slab can be allocated.
+    pub fn alloc(&self, value: T) -> Result<Box<T, &SlabCache<M, A>>, AllocError> {
+        Box::try_new_in(value, &self.cache)
+    }
+}
+impl<T, M: PhysicalPageDirectMap, A: PhysicalPageAllocator> Deref for ObjectCache<T, M, A> {
+    type Target = SlabCache<M, A>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cache
+    }
+}
+
+#[derive(Debug)]
+/// The caches of the kernel objects every process needs.
+pub struct KernelCaches<M: PhysicalPageDirectMap, A: PhysicalPageAllocator> {
+    pub threads: ObjectCache<Thread, M, A>,
+    pub endpoints: ObjectCache<Endpoint, M, A>,
+    pub capability_nodes: ObjectCache<CapabilityNode, M, A>,
+}
+impl<M, A> KernelCaches<M, A>
+where
+    M: PhysicalPageDirectMap + Clone,
+    A: PhysicalPageAllocator + Clone,
+{
+    pub fn new(map: M, allocator: A) -> Self {
+        KernelCaches {
+            threads: ObjectCache::new("thread", map.clone(), allocator.clone()),
+            endpoints: ObjectCache::new("endpoint", map.clone(), allocator.clone()),
+            capability_nodes: ObjectCache::new("capability_node", map, allocator),
+        }
+    }
+
+    pub fn log_stats(&self) {
+        self.threads.log_stats();
+        self.endpoints.log_stats();
+        self.capability_nodes.log_stats();
+    }
+}
+
+fn push(state: &mut SlabState, slab: NonNull<Slab>) {
+    unsafe {
+        (*slab.as_ptr()).prev = None;
+        (*slab.as_ptr()).next = state.partial;
+        if let Some(next) = state.partial {
+            (*next.as_ptr()).prev = Some(slab);
+        }
+    }
+    state.partial = Some(slab);
+}
+
+fn unlink(state: &mut SlabState, slab: NonNull<Slab>) {
+    let Slab { prev, next, .. } = unsafe { slab.read() };
+    match prev {
+        Some(prev) => unsafe { (*prev.as_ptr()).next = next },
+        None => state.partial = next,
+    }
+    if let Some(next) = next {
+        unsafe { (*next.as_ptr()).prev = prev };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+    use core::alloc::{Allocator, Layout};
+
+    use super::{KernelCaches, SlabCache, SlabStats};
+    use crate::{
+        object::Thread,
+        page::{PhysicalPageDirectMap, testing::HostMemory},
+    };
+
+    #[test]
+    fn boxes_live_in_slabs() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let cache = SlabCache::of::<[u64; 4]>("test", &memory, &buddy);
+        assert_eq!((cache.object_size(), cache.pages_per_slab()), (32, 1));
+        let boxed = Box::new_in([7; 4], &cache);
+        assert_eq!(*boxed, [7; 4]);
+        let page = memory.virt_to_phy(core::ptr::from_ref(&*boxed).cast());
+        assert!(page.is_some_and(|page| usize::from(page) < 32));
+        assert_eq!(buddy.free_count(), 31);
+        drop(boxed);
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.allocations, stats.frees, stats.objects_in_use),
+            (1, 1, 0)
+        );
+        // One empty slab is kept for the next allocation.
+        assert_eq!(stats.slabs, 1);
+        cache.reclaim();
+        assert_eq!(cache.stats().slabs, 0);
+        assert_eq!(buddy.free_count(), 32);
+    }
+
+    #[test]
+    fn counts_objects_per_cache() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let small = SlabCache::of::<u64>("small", &memory, &buddy);
+        let large = SlabCache::new("large", Layout::new::<[u8; 1000]>(), &memory, &buddy);
+        assert_eq!(large.pages_per_slab(), 2);
+        let objects: Vec<_> = (0..3).map(|i| Box::new_in(i, &small)).collect();
+        let blocks: Vec<_> = (0..5).map(|_| Box::new_in([0u8; 1000], &large)).collect();
+        let capacity = |cache: &SlabCache<_, _>| {
+            (cache.pages_per_slab() * crate::Page::SIZE - size_of::<super::Slab>())
+                / cache.object_size()
+        };
+        assert_eq!(
+            small.stats(),
+            SlabStats {
+                slabs: 1,
+                objects_in_use: 3,
+                objects_total: capacity(&small),
+                allocations: 3,
+                frees: 0,
+                failures: 0,
+            }
+        );
+        assert_eq!(large.stats().objects_in_use, 5);
+        assert_eq!(large.stats().objects_total, capacity(&large));
+        // Objects too large or too aligned for the cache are refused without counting a failure.
+        assert!(small.allocate(Layout::new::<[u64; 2]>()).is_err());
+        assert!(
+            small
+                .allocate(Layout::from_size_align(8, 64).unwrap())
+                .is_err()
+        );
+        assert_eq!(small.stats().failures, 0);
+        drop(objects);
+        drop(blocks);
+        assert_eq!(small.stats().objects_in_use, 0);
+        assert_eq!(large.stats().frees, 5);
+    }
+
+    #[test]
+    fn empty_slabs_go_back() {
+        let memory = HostMemory::new(0, 9);
+        let buddy = memory.buddy(8);
+        let cache = SlabCache::new("test", Layout::new::<[u8; 1024]>(), &memory, &buddy);
+        // Fewer than eight objects fit in two pages, so slabs take four, past the header.
+        assert_eq!(cache.pages_per_slab(), 4);
+        let objects: Vec<_> = (0..2 * 15)
+            .map(|_| Box::new_in([0u8; 1024], &cache))
+            .collect();
+        assert_eq!(cache.stats().slabs, 2);
+        assert_eq!(buddy.free_count(), 0);
+        // Both slabs are full and the physical allocator is empty.
+        assert!(cache.allocate(Layout::new::<[u8; 1024]>()).is_err());
+        assert_eq!(cache.stats().failures, 1);
+        drop(objects);
+        // The first slab to empty is kept, the second goes straight back.
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(buddy.free_count(), 4);
+        cache.reclaim();
+        assert_eq!(buddy.free_count(), 8);
+    }
+
+    #[test]
+    fn kernel_objects_have_their_own_caches() {
+        let memory = HostMemory::new(0, 33);
+        let buddy = memory.buddy(32);
+        let caches = KernelCaches::new(&memory, &buddy);
+        let thread = caches.threads.alloc(Thread::new(3)).unwrap();
+        let endpoint = caches.endpoints.alloc(Default::default()).unwrap();
+        let node = caches.capability_nodes.alloc(Default::default()).unwrap();
+        assert_eq!(thread.priority, 3);
+        assert!(endpoint.head.is_none());
+        assert!(node.slots.iter().all(|slot| *slot == Default::default()));
+        for (cache, name) in [
+            (&*caches.threads, "thread"),
+            (&*caches.endpoints, "endpoint"),
+            (&*caches.capability_nodes, "capability_node"),
+        ] {
+            assert_eq!(cache.name(), name);
+            assert_eq!(cache.stats().objects_in_use, 1);
+        }
+        caches.log_stats();
+    }
+}
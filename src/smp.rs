@@ -0,0 +1,183 @@
+use core::{
+    alloc::Layout,
+    hint,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    Arch, ArchImpl, BootParms, Page, PhyPageNumber, arch::page::PagingMode, sync::SpinLock, timer,
+};
+
+/// The most harts the kernel brings up; any further ones stay parked in the bootloader.
+pub const MAX_HARTS: usize = 64;
+/// Pages of stack each secondary hart runs on.
+const STACK_PAGES: usize = 16;
+/// Polls of a started hart before the boot hart stops waiting for it to come online.
+const ONLINE_POLLS: usize = 1 << 24;
+/// How long an idle hart sleeps between two runs of its upkeep work.
+const IDLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// What the kernel knows of a hart, indexed by its hart index.
+struct Hart {
+    /// The ID the bootloader and firmware know the hart by.
+    hardware_id: AtomicUsize,
+    online: AtomicBool,
+    /// End of the stack the hart moves to once started, 0 for the boot hart and harts not started.
+    stack_top: AtomicUsize,
+}
+impl Hart {
+    const fn new() -> Self {
+        Hart {
+            hardware_id: AtomicUsize::new(0),
+            online: AtomicBool::new(false),
+            stack_top: AtomicUsize::new(0),
+        }
+    }
+}
+
+static HARTS: [Hart; MAX_HARTS] = [const { Hart::new() }; _];
+/// Harts given an index so far, whose entries in [`HARTS`] are filled in.
+static HART_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The kernel page tree secondary harts install, set before the first of them starts.
+static KERNEL_MMU: SpinLock<Option<(PagingMode, PhyPageNumber)>> = SpinLock::new(None);
+
+/// Records the current hart as hart 0, online, and gives the secondary harts the bootloader offers the following indices.
+/// # Safety
+/// Must be called once by the boot hart, before anything asks for the hart index.
+pub unsafe fn init(parms: &impl BootParms) {
+    unsafe { Arch::set_hart_id(0) };
+    HARTS[0]
+        .hardware_id
+        .store(parms.boot_hart(), Ordering::Relaxed);
+    HARTS[0].online.store(true, Ordering::Relaxed);
+    let mut count = 1;
+    for hardware_id in parms.secondary_harts() {
+        if count == MAX_HARTS {
+            log::warn!("Only {MAX_HARTS} harts are supported, leaving the rest parked");
+            break;
+        }
+        HARTS[count]
+            .hardware_id
+            .store(hardware_id, Ordering::Relaxed);
+        count += 1;
+    }
+    HART_COUNT.store(count, Ordering::Release);
+}
+
+/// Starts the secondary harts, each on its own stack taken from the kernel heap,
+/// and waits in turn for each to install the kernel page tree at `root` and come online.
+/// # Panics
+/// Panics if the stack size is not a valid layout.
+pub fn start_secondary_harts<P: BootParms>(mode: PagingMode, root: PhyPageNumber) {
+    *KERNEL_MMU.lock() = Some((mode, root));
+    let stack_layout = Layout::from_size_align(STACK_PAGES * Page::SIZE, Page::SIZE).unwrap();
+    for (index, hart) in HARTS.iter().enumerate().take(hart_count()).skip(1) {
+        let hardware_id = hart.hardware_id.load(Ordering::Relaxed);
+        // Never freed: the hart runs on it for as long as the kernel does.
+        let stack = unsafe { alloc::alloc::alloc(stack_layout) };
+        if stack.is_null() {
+            log::warn!("No memory left for the stack of hart {index} ({hardware_id:#x})");
+            break;
+        }
+        hart.stack_top
+            .store(stack.addr() + stack_layout.size(), Ordering::Release);
+        if !P::start_hart(hardware_id) {
+            log::warn!("Hart {index} ({hardware_id:#x}) could not be started");
+            continue;
+        }
+        let online = (0..ONLINE_POLLS).any(|_| {
+            hint::spin_loop();
+            hart.online.load(Ordering::Acquire)
+        });
+        if !online {
+            log::warn!("Hart {index} ({hardware_id:#x}) did not come online");
+        }
+    }
+    log::info!("{} of {} harts online", online_count(), hart_count());
+}
+
+/// Entry point of a secondary hart started through [`BootParms::start_hart`], still on the bootloader's stack.
+/// # Safety
+/// Must be called once, by the hart started with `hardware_id`.
+/// # Panics
+/// Panics if the hart cannot switch to the kernel page tree.
+pub unsafe fn secondary_entry(hardware_id: usize) -> ! {
+    let Some(index) =
+        (1..hart_count()).find(|&id| HARTS[id].hardware_id.load(Ordering::Relaxed) == hardware_id)
+    else {
+        // Not a hart the kernel asked for, so it has no stack to move to.
+        loop {
+            Arch::wait_for_interrupt();
+        }
+    };
+    unsafe { Arch::set_hart_id(index) };
+    Arch::init_hart();
+    let (mode, root) = KERNEL_MMU
+        .lock()
+        .expect("secondary hart started before the kernel page tree was set");
+    assert!(
+        unsafe { Arch::set_mmu(0, mode, root) },
+        "hart {index} cannot use the kernel page tree"
+    );
+    Arch::flush_mmu(None, None);
+    // The bootloader's stack is only as large as it chose, and lies in memory it marks reclaimable:
+    // the kernel does not hand that memory out yet, but moving off it lets a later kernel reclaim it.
+    let stack_top = HARTS[index].stack_top.load(Ordering::Acquire);
+    unsafe { Arch::run_on_stack(stack_top, secondary_main, index) }
+}
+
+extern "C" fn secondary_main(index: usize) -> ! {
+    Arch::install_traps();
+    log::info!(
+        "Hart {index} ({:#x}) online",
+        HARTS[index].hardware_id.load(Ordering::Relaxed)
+    );
+    HARTS[index].online.store(true, Ordering::Release);
+    // There is no scheduler to join yet, so the hart idles until one hands it work.
+    idle(|| {})
+}
+
+/// Idles the current hart for good, running `work` each time it wakes up, and at least every [`IDLE_PERIOD`]
+/// where there is a timer.
+/// `work` is meant for upkeep that can wait until nothing else needs the hart, such as scrubbing free frames.
+pub fn idle(mut work: impl FnMut()) -> ! {
+    loop {
+        work();
+        if !timer::sleep(IDLE_PERIOD) {
+            timer::wait();
+        }
+    }
+}
+
+/// Returns the number of harts the kernel knows of, whether or not they came online.
+#[must_use]
+pub fn hart_count() -> usize {
+    HART_COUNT.load(Ordering::Acquire)
+}
+
+/// Returns the number of harts online.
+#[must_use]
+pub fn online_count() -> usize {
+    (0..hart_count()).filter(|&id| is_online(id)).count()
+}
+
+/// Returns true if hart `id` has come online.
+#[must_use]
+pub fn is_online(id: usize) -> bool {
+    HARTS
+        .get(id)
+        .is_some_and(|hart| hart.online.load(Ordering::Acquire))
+}
+
+/// Returns the hardware ID of hart `id`, or `None` if no hart has that index.
+#[must_use]
+pub fn hardware_id(id: usize) -> Option<usize> {
+    (id < hart_count()).then(|| HARTS[id].hardware_id.load(Ordering::Relaxed))
+}
+
+/// Returns the index of the current hart.
+#[must_use]
+pub fn current() -> usize {
+    Arch::hart_id()
+}
@@ -0,0 +1,146 @@
+use core::{error::Error, fmt::Display};
+
+use crate::{
+    arch::trap::{SyscallRegisters, Trap, TrapFrame},
+    heap::GLOBAL_HEAP,
+    page::stats::{MEMORY_STATS, MemoryCounter},
+    rng,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+#[non_exhaustive]
+/// System call numbers.
+pub enum Syscall {
+    /// Returns a cryptographically secure random word.
+    GetRandom = 0x1,
+    /// Returns a memory counter in pages, selected by the first argument as a [`MemoryCounter`].
+    /// Only available in debug builds.
+    DebugMemoryStat = 0x8000_0000,
+    /// Logs every live kernel heap allocation with its call stack, returning how many there are.
+    /// Only available in debug builds.
+    DebugHeapDump = 0x8000_0001,
+}
+impl TryFrom<usize> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(Syscall::GetRandom),
+            0x8000_0000 => Ok(Syscall::DebugMemoryStat),
+            0x8000_0001 => Ok(Syscall::DebugHeapDump),
+            _ => Err(SyscallError::NoSuchSyscall),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(usize)]
+/// System call errors, numbered as user space sees them; 0 stands for success.
+pub enum SyscallError {
+    NoSuchSyscall = 1,
+    InvalidArgument = 2,
+    /// The service behind the system call is not available yet.
+    NotReady = 3,
+}
+impl Display for SyscallError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SyscallError::NoSuchSyscall => write!(f, "No such system call"),
+            SyscallError::InvalidArgument => write!(f, "Invalid system call argument"),
+            SyscallError::NotReady => write!(f, "System call service is not ready"),
+        }
+    }
+}
+impl Error for SyscallError {}
+
+/// Handles a system call trap from user mode: runs the call the registers of `frame` ask for,
+/// then hands the result back in them.
+pub fn handle_trap(_trap: &Trap, frame: &mut TrapFrame) {
+    let (number, args) = frame.syscall();
+    frame.set_syscall_result(dispatch(number, args));
+}
+
+/// Runs the system call `number` with its raw arguments.
+/// # Errors
+/// Returns an error if the system call does not exist or an argument is invalid.
+pub fn dispatch(number: usize, args: [usize; 6]) -> Result<usize, SyscallError> {
+    match Syscall::try_from(number)? {
+        Syscall::GetRandom => {
+            let mut bytes = [0; size_of::<usize>()];
+            rng::fill_bytes(&mut bytes)
+                .map(|()| usize::from_ne_bytes(bytes))
+                .map_err(|_| SyscallError::NotReady)
+        }
+        Syscall::DebugMemoryStat if cfg!(debug_assertions) => MemoryCounter::try_from(args[0])
+            .map(|counter| MEMORY_STATS.get(counter))
+            .map_err(|_| SyscallError::InvalidArgument),
+        Syscall::DebugHeapDump if cfg!(debug_assertions) => {
+            GLOBAL_HEAP.dump();
+            Ok(GLOBAL_HEAP.live())
+        }
+        Syscall::DebugMemoryStat | Syscall::DebugHeapDump => Err(SyscallError::NoSuchSyscall),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Syscall, SyscallError, dispatch};
+    use crate::rng::{self, entropy::EntropySource};
+    #[cfg(debug_assertions)]
+    use crate::{
+        heap::GLOBAL_HEAP,
+        page::stats::{MEMORY_STATS, MemoryCounter},
+    };
+
+    #[test]
+    fn get_random() {
+        rng::add_entropy(EntropySource::Bootloader, &[0x5a; 64], 512);
+        rng::reseed().unwrap();
+        let first = dispatch(Syscall::GetRandom as usize, [0; 6]).unwrap();
+        let second = dispatch(Syscall::GetRandom as usize, [0; 6]).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn debug_memory_stat() {
+        let number = Syscall::DebugMemoryStat as usize;
+        MEMORY_STATS.add(MemoryCounter::Slab, 3);
+        let slab = dispatch(number, [MemoryCounter::Slab as usize, 0, 0, 0, 0, 0]).unwrap();
+        assert!(slab >= 3);
+        assert_eq!(
+            dispatch(number, [MemoryCounter::COUNT, 0, 0, 0, 0, 0]),
+            Err(SyscallError::InvalidArgument)
+        );
+        MEMORY_STATS.sub(MemoryCounter::Slab, 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn debug_heap_dump() {
+        // The host allocator serves the tests, so the kernel heap tracks nothing.
+        assert_eq!(
+            dispatch(Syscall::DebugHeapDump as usize, [0; 6]),
+            Ok(GLOBAL_HEAP.live())
+        );
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn debug_calls_are_missing_in_release() {
+        for number in [Syscall::DebugMemoryStat, Syscall::DebugHeapDump] {
+            assert_eq!(
+                dispatch(number as usize, [0; 6]),
+                Err(SyscallError::NoSuchSyscall)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_numbers() {
+        for number in [0, 2, 0x8000_0002, usize::MAX] {
+            assert_eq!(dispatch(number, [0; 6]), Err(SyscallError::NoSuchSyscall));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use core::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    Arch, ArchImpl,
+    arch::trap::{self, Trap, TrapFrame, TrapKind},
+    hardening,
+    smp::{self, MAX_HARTS},
+};
+
+/// Runs in the timer interrupt of the hart whose deadline passed, with that deadline.
+/// The timer is disarmed by then, so the callback may arm it again.
+pub type TimerCallback = fn(u64);
+
+/// The deadline each hart's timer is armed for, [`u64::MAX`] while disarmed.
+static DEADLINES: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(u64::MAX) }; _];
+/// The registered callback, mangled as trap handlers are; 0 means none.
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Handles timer interrupts from now on.
+/// Must run after [`hardening::init`], as the handler is mangled with it.
+pub fn init() {
+    trap::set_handler(TrapKind::TimerInterrupt, handle_trap);
+}
+
+/// Registers `callback` for every deadline that passes, returning the callback it replaces.
+pub fn set_callback(callback: TimerCallback) -> Option<TimerCallback> {
+    let previous = CALLBACK.swap(hardening::mangle(callback as usize), Ordering::AcqRel);
+    (previous != 0).then(|| demangle_callback(previous))
+}
+
+/// Removes the callback, returning it.
+pub fn clear_callback() -> Option<TimerCallback> {
+    let previous = CALLBACK.swap(0, Ordering::AcqRel);
+    (previous != 0).then(|| demangle_callback(previous))
+}
+
+/// Returns the current time in timer ticks.
+#[must_use]
+pub fn now() -> u64 {
+    Arch::timer_ticks()
+}
+
+/// Returns `duration` in timer ticks, or `None` if the timer frequency is unknown.
+#[must_use]
+pub fn ticks(duration: Duration) -> Option<u64> {
+    ticks_at(duration, Arch::timer_frequency())
+}
+
+/// Arms the current hart's timer to interrupt once [`now`] reaches `deadline`, replacing any earlier deadline.
+/// Returns false if there is no timer.
+#[must_use]
+pub fn arm(deadline: u64) -> bool {
+    let slot = &DEADLINES[smp::current()];
+    // Recorded first, as a deadline already passed interrupts as soon as it is set.
+    slot.store(deadline, Ordering::Release);
+    let armed = Arch::set_deadline(Some(deadline));
+    if !armed {
+        slot.store(u64::MAX, Ordering::Release);
+    }
+    armed
+}
+
+/// Disarms the current hart's timer.
+pub fn disarm() {
+    DEADLINES[smp::current()].store(u64::MAX, Ordering::Release);
+    let _ = Arch::set_deadline(None);
+}
+
+/// Idles the current hart for at least `duration`, replacing any deadline armed on it.
+/// Returns false at once if there is no timer to wake the hart.
+#[must_use]
+pub fn sleep(duration: Duration) -> bool {
+    let Some(deadline) = ticks(duration).map(|ticks| now().saturating_add(ticks)) else {
+        return false;
+    };
+    if !arm(deadline) {
+        return false;
+    }
+    while now() < deadline {
+        wait();
+    }
+    disarm();
+    true
+}
+
+/// Waits for an interrupt with interrupts disabled, then takes it.
+/// Interrupts stay off otherwise, so handlers never find the hart in the middle of holding a lock.
+pub fn wait() {
+    Arch::wait_for_interrupt();
+    let enabled = Arch::set_interrupts(true);
+    let _ = Arch::set_interrupts(enabled);
+}
+
+fn handle_trap(_trap: &Trap, _frame: &mut TrapFrame) {
+    let deadline = DEADLINES[smp::current()].swap(u64::MAX, Ordering::AcqRel);
+    // Disarmed before the callback runs, so a deadline the callback arms stays armed.
+    let _ = Arch::set_deadline(None);
+    let callback = CALLBACK.load(Ordering::Acquire);
+    // An interrupt with no deadline recorded raced a disarm and is dropped.
+    if deadline != u64::MAX && callback != 0 {
+        demangle_callback(callback)(deadline);
+    }
+}
+
+fn ticks_at(duration: Duration, frequency: u64) -> Option<u64> {
+    (frequency != 0).then(|| {
+        let ticks = duration.as_nanos().saturating_mul(u128::from(frequency)) / 1_000_000_000;
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    })
+}
+
+fn demangle_callback(mangled: usize) -> TimerCallback {
+    let addr = hardening::demangle(mangled);
+    // Only callbacks stored by `set_callback` are ever mangled into the slot.
+    unsafe { core::mem::transmute::<usize, TimerCallback>(addr) }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use super::{DEADLINES, clear_callback, handle_trap, set_callback, ticks_at};
+    use crate::{arch::trap::Trap, smp};
+
+    #[test]
+    fn converts_durations() {
+        assert_eq!(ticks_at(Duration::from_millis(3), 10_000_000), Some(30_000));
+        assert_eq!(ticks_at(Duration::from_nanos(1), 1_000), Some(0));
+        assert_eq!(ticks_at(Duration::MAX, u64::MAX), Some(u64::MAX));
+        assert_eq!(ticks_at(Duration::from_secs(1), 0), None);
+    }
+
+    #[test]
+    fn interrupts_run_the_callback_once() {
+        static FIRED: AtomicU64 = AtomicU64::new(0);
+        fn record(deadline: u64) {
+            FIRED.store(deadline, Ordering::Relaxed);
+        }
+        set_callback(record);
+        DEADLINES[smp::current()].store(42, Ordering::Relaxed);
+        handle_trap(&Trap::TimerInterrupt, &mut Default::default());
+        assert_eq!(FIRED.swap(0, Ordering::Relaxed), 42);
+        // The deadline is gone, so a second interrupt is spurious.
+        handle_trap(&Trap::TimerInterrupt, &mut Default::default());
+        assert_eq!(FIRED.load(Ordering::Relaxed), 0);
+        assert_eq!(DEADLINES[smp::current()].load(Ordering::Relaxed), u64::MAX);
+        clear_callback();
+    }
+}